@@ -0,0 +1,88 @@
+//! Inserts a value after every instruction of a module, through [`OrderedArenaLike`].
+//!
+//! Runs over the wasm files given as arguments, or over generated modules of growing size:
+//! `cargo bench --bench insert -- a.wasm b.wasm`.
+use std::time::Instant;
+
+use portal_ir::{
+    compat::{
+        waffle::{base::MFCache, link::empty_module},
+        ModLikeIter, OrderedArenaLike,
+    },
+    utils::waffle::parse,
+};
+use waffle::{FuncDecl, FunctionBody, Module, Operator, SignatureData, Type, ValueDef};
+
+fn nop() -> ValueDef {
+    ValueDef::Operator(Operator::Nop, Default::default(), Default::default())
+}
+
+/// `funcs` functions, each adding one to its parameter `len` times, as parsed back from wasm.
+fn generated(funcs: usize, len: usize) -> Module<'static> {
+    let mut m = empty_module();
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    for i in 0..funcs {
+        let mut b = FunctionBody::new(&m, s);
+        let l = b.entry;
+        let mut x = b.blocks[l].params[0].1;
+        let t = b.single_type_list(Type::I32);
+        let none = b.arg_pool.from_iter([].into_iter());
+        let one = b.add_value(ValueDef::Operator(Operator::I32Const { value: 1 }, none, t));
+        b.append_to_block(l, one);
+        for _ in 0..len {
+            let args = b.arg_pool.from_iter([x, one].into_iter());
+            x = b.add_value(ValueDef::Operator(Operator::I32Add, args, t));
+            b.append_to_block(l, x);
+        }
+        b.set_terminator(l, waffle::Terminator::Return { values: vec![x] });
+        m.funcs.push(FuncDecl::Body(s, format!("f{i}"), b));
+    }
+    parse(&m.to_wasm_bytes().unwrap()).unwrap()
+}
+
+fn bench(name: &str, m: Module<'static>) {
+    let mut m = MFCache::from_inner(m);
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let count = |m: &MFCache<Module<'static>>| -> usize {
+        m.keys()
+            .into_iter()
+            .map(|k| m[k].func().unwrap().blocks[k.block].insts.len())
+            .sum()
+    };
+    let n = count(m);
+    let start = Instant::now();
+    for k in m.keys() {
+        let insts = m[k].func().unwrap().blocks[k.block].insts.clone();
+        for (i, v) in insts.into_iter().enumerate() {
+            if i % 2 == 0 {
+                m[k].push_after(nop(), v);
+            } else {
+                m[k].push_just_before(nop(), v);
+            }
+        }
+    }
+    m.flush();
+    let elapsed = start.elapsed();
+    assert_eq!(count(m), 2 * n);
+    println!("{name}: insert {n:>8} values among {n:>8}: {elapsed:?}");
+}
+
+fn main() {
+    let paths: Vec<_> = std::env::args()
+        .skip(1)
+        .filter(|a| a.ends_with(".wasm"))
+        .collect();
+    if !paths.is_empty() {
+        for p in paths {
+            bench(&p, parse(&std::fs::read(&p).unwrap()).unwrap());
+        }
+        return;
+    }
+    bench("mod1.wasm", parse(include_bytes!("../src/tests/mod1.wasm")).unwrap());
+    for (funcs, len) in [(100, 1_000), (1_000, 1_000), (10_000, 100)] {
+        bench(&format!("{funcs} x {len}"), generated(funcs, len));
+    }
+}
@@ -1 +1,182 @@
+use either::Either::Right;
+use waffle::{
+    ExportKind, Func, FuncDecl, FunctionBody, ImportKind, Module, Terminator, Value, ValueDef,
+};
 
+use crate::{
+    compat::{
+        call::Call,
+        waffle::{
+            base::{BlockRef, FuncAndBlock, GetModule, Importd, MFCache},
+            link::Linker,
+        },
+        ArenaLike,
+    },
+    error::Error,
+    utils::waffle::{append_call, pick_outputs},
+};
+
+pub fn rename_import(
+    m: &mut Module,
+    module: &str,
+    name: &str,
+    to_module: &str,
+    to_name: &str,
+) -> bool {
+    let mut found = false;
+    for i in m.imports.iter_mut() {
+        if i.module == module && i.name == name {
+            i.module = to_module.to_owned();
+            i.name = to_name.to_owned();
+            found = true;
+        }
+    }
+    found
+}
+pub fn rename_export(m: &mut Module, name: &str, to: &str) -> bool {
+    let mut found = false;
+    for e in m.exports.iter_mut() {
+        if e.name == name {
+            e.name = to.to_owned();
+            found = true;
+        }
+    }
+    found
+}
+pub fn imported_func(m: &Module, module: &str, name: &str) -> Option<Func> {
+    m.imports.iter().find_map(|i| match i.kind {
+        ImportKind::Func(f) if i.module == module && i.name == name => Some(f),
+        _ => None,
+    })
+}
+pub fn exported_func(m: &Module, name: &str) -> Option<Func> {
+    m.exports.iter().find_map(|e| match e.kind {
+        ExportKind::Func(f) if e.name == name => Some(f),
+        _ => None,
+    })
+}
+fn params(f: &FunctionBody) -> Vec<Value> {
+    f.blocks[f.entry].params.iter().map(|a| a.1).collect()
+}
+
+/// Replaces the imported function `module.name` by a body built with `body`, which receives the
+/// entry block and its parameters and returns the values to return.
+///
+/// The function keeps its index, so its callers are unaffected; as imports must precede bodies,
+/// run [`reorder_imports`] before emitting the module.
+pub fn shim_import<M: GetModule, E: From<Error<FuncAndBlock>>>(
+    m: &mut MFCache<M>,
+    module: &str,
+    name: &str,
+    body: impl FnOnce(&mut BlockRef<MFCache<M>>, Vec<Value>) -> Result<Vec<Value>, E>,
+) -> Result<Option<Func>, E> {
+    let Some(f) = imported_func(m.module(), module, name) else {
+        return Ok(None);
+    };
+    let sig = m.module().funcs[f].sig();
+    let b = FunctionBody::new(m.module(), sig);
+    let entry = b.entry;
+    let p = params(&b);
+    m.module_mut().funcs[f] = FuncDecl::Body(sig, name.to_owned(), b);
+    m.module_mut()
+        .imports
+        .retain(|i| !(i.module == module && i.name == name));
+    let k = &mut m[FuncAndBlock {
+        func: f,
+        block: entry,
+    }];
+    let rets = body(k, p)?;
+    k.try_func_mut()?
+        .set_terminator(entry, Terminator::Return { values: rets });
+    Ok(Some(f))
+}
+/// Shims `module.name` with a call to another import, through [`Call::call`].
+pub fn forward_import<M: GetModule, E: From<Error<FuncAndBlock>>>(
+    m: &mut MFCache<M>,
+    module: &str,
+    name: &str,
+    to: Importd,
+) -> Result<Option<Func>, E> {
+    shim_import(m, module, name, |k, p| {
+        let v = <ValueDef as Call<_, _, Importd, E>>::call(k, Right(to), p)?;
+        let v = k.push(v);
+        let b = k.k.block;
+        let f = k.try_func_mut()?;
+        let tys = f.values[v].tys(&f.type_pool).to_vec();
+        Ok(pick_outputs(f, b, v, &tys))
+    })
+}
+/// Turns the imported function `module.name` into one forwarding to `to`.
+///
+/// Returns `None` if there is no such import or if the signatures differ.
+pub fn resolve_import(m: &mut Module, module: &str, name: &str, to: Func) -> Option<Func> {
+    let f = imported_func(m, module, name)?;
+    let sig = m.funcs[f].sig();
+    if m.signatures[sig] != m.signatures[m.funcs[to].sig()] {
+        return None;
+    }
+    let mut b = FunctionBody::new(m, sig);
+    let entry = b.entry;
+    let p = params(&b);
+    let rets = append_call(m, &mut b, entry, to, p);
+    b.set_terminator(entry, Terminator::Return { values: rets });
+    m.funcs[f] = FuncDecl::Body(sig, name.to_owned(), b);
+    m.imports
+        .retain(|i| !(i.module == module && i.name == name));
+    Some(f)
+}
+/// Points the export `name` at a new function running `pre` on the arguments and `post` on the
+/// results of the original one.
+///
+/// `pre` must map the export's parameters to themselves and `post` its results to themselves;
+/// a hook that does not fails with [`Error::BadSignature`]. Returns `None` if there is no such
+/// export.
+pub fn wrap_export(
+    m: &mut Module,
+    name: &str,
+    pre: Option<Func>,
+    post: Option<Func>,
+) -> Result<Option<Func>, Error<Func>> {
+    let Some(f) = exported_func(m, name) else {
+        return Ok(None);
+    };
+    let sig = m.funcs[f].sig();
+    let s = &m.signatures[sig];
+    for (h, tys) in [(pre, &s.params), (post, &s.returns)] {
+        let Some(h) = h else {
+            continue;
+        };
+        let t = &m.signatures[m.funcs[h].sig()];
+        if &t.params != tys || &t.returns != tys {
+            return Err(Error::BadSignature(h));
+        }
+    }
+    let mut b = FunctionBody::new(m, sig);
+    let entry = b.entry;
+    let mut v = params(&b);
+    for h in [pre, Some(f), post].into_iter().flatten() {
+        v = append_call(m, &mut b, entry, h, v);
+    }
+    b.set_terminator(entry, Terminator::Return { values: v });
+    let w = m.funcs.push(FuncDecl::Body(sig, format!("{name}$wrap"), b));
+    for e in m.exports.iter_mut() {
+        if e.name == name {
+            e.kind = ExportKind::Func(w);
+        }
+    }
+    Ok(Some(w))
+}
+
+/// Merges `b` into `a` with a [`Linker`]. `a_name` and `b_name` are the module names each one imports the other as.
+pub fn merge(
+    a: Module<'static>,
+    a_name: &str,
+    b: Module<'static>,
+    b_name: &str,
+) -> anyhow::Result<Module<'static>> {
+    Linker::new().module(a_name, a).module(b_name, b).link()
+}
+/// Renumbers entities so imports precede definitions, as required for emission.
+pub fn reorder_imports(m: Module<'static>) -> anyhow::Result<Module<'static>> {
+    Linker::new().module("", m).link()
+}
@@ -0,0 +1,8 @@
+pub mod defuse;
+pub mod dom;
+pub mod liveness;
+pub mod cfg;
+pub mod dataflow;
+pub mod memory;
+pub mod range;
+pub mod bounds;
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use crate::compat::{
+    rewrite::Op,
+    stmt::{Statement, Stmt},
+    tree::UnTreeTerminator,
+    ArenaLikeIter, FunId, FunLike, ModLikeIter, Term, Val, ValID,
+};
+
+use super::{
+    dataflow::{solve, Analysis},
+    memory::{Access, MemoryOp},
+    range::{RangeOp, Ranged, Ranges},
+};
+
+/// A load or store, with its address split into a base and a constant offset.
+pub struct Checked<M: ModLikeIter, K> {
+    pub fun: FunId<M>,
+    pub value: ValID<M>,
+    pub access: Access<K>,
+    pub base: ValID<M>,
+    /// The access's own offset, plus any constant added to `base` to compute the address.
+    pub offset: u64,
+    /// Whether the access is known to stay within the memory's initial size.
+    pub in_bounds: bool,
+}
+
+/// One explicit check standing for several accesses off the same base: `base + end` must not
+/// exceed the size of `memory`. Emitting it before `before` is up to the backend.
+pub struct BoundsCheck<M: ModLikeIter, K> {
+    pub fun: FunId<M>,
+    /// The first of `accesses`, which the check goes right before.
+    pub before: ValID<M>,
+    pub memory: K,
+    pub base: ValID<M>,
+    pub end: u64,
+    pub accesses: Vec<ValID<M>>,
+}
+
+/// A plan of bounds checks for backends which make memory accesses checked explicitly, such as
+/// source emitters. Nothing is inserted into the module; emitters place each [`BoundsCheck`]
+/// themselves.
+///
+/// Accesses whose address is bounded by [`Ranges`] within the memory's initial size need no
+/// check, memories only growing. The others are grouped by memory and base within each
+/// function, so that one check, planned at the first of them, covers the furthest. A group
+/// ends at anything which writes, as the check would otherwise trap before the write instead
+/// of after it.
+pub struct BoundsChecks<M: ModLikeIter, K> {
+    pub accesses: Vec<Checked<M, K>>,
+    pub checks: Vec<BoundsCheck<M, K>>,
+}
+impl<M: ModLikeIter, K: Ord + Clone> BoundsChecks<M, K>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Ord + Clone,
+    Val<M>: Statement<M>,
+    Op<M>: RangeOp + MemoryOp<Memory = K>,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>>,
+{
+    /// Plans the checks of `m`, given the initial size in bytes of every memory.
+    pub fn new<Err>(m: &M, size: impl Fn(&K) -> Option<u64>) -> Result<Self, Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        let mut r = Ranges::default();
+        let s = solve(m, &mut r)?;
+        let mut accesses = vec![];
+        let mut checks: Vec<BoundsCheck<M, K>> = vec![];
+        // Nothing is known in unreached blocks.
+        let unreached = Ranged::<M>::new();
+        for f in m.keys() {
+            let fun = &m.code()[f.clone()];
+            let mut fact = s.entry[&f].clone();
+            let mut open: BTreeMap<(K, ValID<M>), usize> = BTreeMap::new();
+            for v in fun.all().keys() {
+                let st = fun.all()[v.clone()].into_statement(fun);
+                if let Stmt::Basic(o, args) = &st {
+                    let known = fact.as_ref().unwrap_or(&unreached);
+                    if let (Some(a), Some(addr)) = (o.access(), args.first()) {
+                        let (base, offset) = Self::split(fun, known, addr.clone(), a.offset);
+                        let end = offset.saturating_add(a.size);
+                        let in_bounds = match (known.get(&base), size(&a.memory)) {
+                            (Some(b), Some(n)) => b.hi.checked_add(end).is_some_and(|e| e <= n),
+                            _ => false,
+                        };
+                        if !in_bounds {
+                            let key = (a.memory.clone(), base.clone());
+                            match open.get(&key) {
+                                Some(i) => {
+                                    let c = &mut checks[*i];
+                                    c.end = c.end.max(end);
+                                    c.accesses.push(v.clone());
+                                }
+                                None => {
+                                    open.insert(key, checks.len());
+                                    checks.push(BoundsCheck {
+                                        fun: f.clone(),
+                                        before: v.clone(),
+                                        memory: a.memory.clone(),
+                                        base: base.clone(),
+                                        end,
+                                        accesses: vec![v.clone()],
+                                    });
+                                }
+                            }
+                        }
+                        accesses.push(Checked {
+                            fun: f.clone(),
+                            value: v.clone(),
+                            access: a,
+                            base,
+                            offset,
+                            in_bounds,
+                        });
+                    }
+                    if o.writes() {
+                        open.clear();
+                    }
+                }
+                r.transfer(m, &f, &v, &st, &mut fact);
+            }
+        }
+        Ok(BoundsChecks { accesses, checks })
+    }
+    /// Folds additions of constants into the address `a`, as long as they cannot wrap.
+    fn split(fun: &M::Fun, fact: &Ranged<M>, mut a: ValID<M>, mut offset: u64) -> (ValID<M>, u64) {
+        while let Stmt::Basic(o, args) = fun.all()[a.clone()].into_statement(fun) {
+            let [x, y] = &args[..] else {
+                break;
+            };
+            if !o.is_add() {
+                break;
+            }
+            let (rx, ry) = (fact.get(x), fact.get(y));
+            let (base, c, rb) = match (rx.and_then(|r| r.value()), ry.and_then(|r| r.value())) {
+                (_, Some(c)) => (x, c, rx),
+                (Some(c), _) => (y, c, ry),
+                _ => break,
+            };
+            let Some(rb) = rb else {
+                break;
+            };
+            if rb.hi.checked_add(c).is_none_or(|e| e > rb.max) {
+                break;
+            }
+            offset = offset.saturating_add(c);
+            a = base.clone();
+        }
+        (a, offset)
+    }
+    /// Accesses needing no check.
+    pub fn proven(&self) -> impl Iterator<Item = &Checked<M, K>> {
+        self.accesses.iter().filter(|a| a.in_bounds)
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::compat::{tree::UnTreeTerminator, FunId, FunLike, ModLikeIter, Term};
+
+/// Successors and predecessors of every function, from [`UnTreeTerminator::get_tree`].
+pub struct Cfg<M: ModLikeIter> {
+    pub succs: BTreeMap<FunId<M>, Vec<FunId<M>>>,
+    pub preds: BTreeMap<FunId<M>, Vec<FunId<M>>>,
+}
+impl<M: ModLikeIter> Cfg<M>
+where
+    FunId<M>: Ord + Clone,
+{
+    pub fn new<Err>(m: &M) -> Result<Self, Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        let mut succs = BTreeMap::new();
+        let mut preds: BTreeMap<FunId<M>, Vec<FunId<M>>> = BTreeMap::new();
+        for f in m.keys() {
+            preds.entry(f.clone()).or_default();
+            let fun = &m.code()[f.clone()];
+            let s: Vec<_> = match fun.terminator().get_tree(fun)? {
+                None => vec![],
+                Some(t) => t.entries().into_iter().map(|e| e.fun).collect(),
+            };
+            for t in s.iter() {
+                preds.entry(t.clone()).or_default().push(f.clone());
+            }
+            succs.insert(f, s);
+        }
+        Ok(Cfg { succs, preds })
+    }
+    pub fn succs(&self, f: &FunId<M>) -> &[FunId<M>] {
+        self.succs.get(f).map(|a| &a[..]).unwrap_or_default()
+    }
+    pub fn preds(&self, f: &FunId<M>) -> &[FunId<M>] {
+        self.preds.get(f).map(|a| &a[..]).unwrap_or_default()
+    }
+    /// Functions reachable from `root`, in reverse postorder.
+    pub fn reverse_postorder(&self, root: &FunId<M>) -> Vec<FunId<M>> {
+        let mut seen = BTreeSet::new();
+        let mut post = vec![];
+        // Each frame is a function and the index of its next successor to visit.
+        let mut stack = vec![(root.clone(), 0)];
+        seen.insert(root.clone());
+        while let Some((f, i)) = stack.pop() {
+            match self.succs(&f).get(i).cloned() {
+                Some(s) => {
+                    stack.push((f, i + 1));
+                    if seen.insert(s.clone()) {
+                        stack.push((s, 0));
+                    }
+                }
+                None => post.push(f),
+            }
+        }
+        post.reverse();
+        post
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::compat::{
+    stmt::{Statement, Stmt},
+    tree::{Entry, Tree, UnTreeTerminator},
+    ArenaLikeIter, FunId, FunLike, ModLikeIter, Term, Val, ValID,
+};
+
+/// A join-semilattice of dataflow facts.
+pub trait Lattice: Clone {
+    fn bottom() -> Self;
+    /// Joins `other` into `self`, returning whether `self` changed.
+    fn join(&mut self, other: &Self) -> bool;
+}
+impl<T: Ord + Clone> Lattice for BTreeSet<T> {
+    fn bottom() -> Self {
+        BTreeSet::new()
+    }
+
+    fn join(&mut self, other: &Self) -> bool {
+        let n = self.len();
+        self.extend(other.iter().cloned());
+        self.len() != n
+    }
+}
+impl<K: Ord + Clone, V: Lattice> Lattice for BTreeMap<K, V> {
+    fn bottom() -> Self {
+        BTreeMap::new()
+    }
+
+    fn join(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (k, v) in other {
+            match self.get_mut(k) {
+                Some(w) => changed |= w.join(v),
+                None => {
+                    self.insert(k.clone(), v.clone());
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// `None` stands for unreached, below every fact.
+impl<T: Lattice> Lattice for Option<T> {
+    fn bottom() -> Self {
+        None
+    }
+
+    fn join(&mut self, other: &Self) -> bool {
+        match (self.as_mut(), other) {
+            (_, None) => false,
+            (Some(s), Some(o)) => s.join(o),
+            (None, Some(o)) => {
+                *self = Some(o.clone());
+                true
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// A monotone dataflow problem over the block graph given by [`UnTreeTerminator::get_tree`].
+pub trait Analysis<M: ModLikeIter>
+where
+    Val<M>: Statement<M>,
+{
+    type Fact: Lattice;
+    const DIRECTION: Direction;
+    /// Fact flowing into functions without predecessors (forward) or successors (backward).
+    fn boundary(&mut self, m: &M, f: &FunId<M>) -> Self::Fact {
+        let _ = (m, f);
+        Self::Fact::bottom()
+    }
+    /// Applies the statement `s`, defining `v`, to `fact`.
+    fn transfer(
+        &mut self,
+        m: &M,
+        f: &FunId<M>,
+        v: &ValID<M>,
+        s: &Stmt<Val<M>, M>,
+        fact: &mut Self::Fact,
+    );
+    /// Applies the terminator of `f`; forward analyses see it last, backward ones first.
+    fn terminator(&mut self, m: &M, f: &FunId<M>, t: &Tree<M, M::Fun>, fact: &mut Self::Fact) {
+        let _ = (m, f, t, fact);
+    }
+    /// Applies the edge from `from` along `e`. Backward analyses receive the fact of `e.fun`.
+    fn edge(&mut self, m: &M, from: &FunId<M>, e: &Entry<M>, fact: &mut Self::Fact) {
+        let _ = (m, from, e, fact);
+    }
+}
+
+/// Facts at the start and end of every function, in program order regardless of direction.
+pub struct Solution<M: ModLikeIter, F> {
+    pub entry: BTreeMap<FunId<M>, F>,
+    pub exit: BTreeMap<FunId<M>, F>,
+}
+
+/// Solves `a` over `m` to a fixpoint with a worklist.
+pub fn solve<M: ModLikeIter, A: Analysis<M>, Err>(
+    m: &M,
+    a: &mut A,
+) -> Result<Solution<M, A::Fact>, Err>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Clone,
+    Val<M>: Statement<M>,
+    Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>>,
+{
+    let keys = m.keys();
+    let mut trees = BTreeMap::new();
+    let mut preds: BTreeMap<FunId<M>, Vec<FunId<M>>> = BTreeMap::new();
+    for f in keys.iter() {
+        let fun = &m.code()[f.clone()];
+        let t = fun.terminator().get_tree(fun)?;
+        for e in t.iter().flat_map(|t| t.targets()) {
+            preds.entry(e.fun.clone()).or_default().push(f.clone());
+        }
+        trees.insert(f.clone(), t);
+    }
+    // `input` holds the fact before a function is applied, in the direction of the analysis.
+    let mut input: BTreeMap<FunId<M>, A::Fact> = BTreeMap::new();
+    let mut output: BTreeMap<FunId<M>, A::Fact> = BTreeMap::new();
+    for f in keys.iter() {
+        let boundary = match A::DIRECTION {
+            Direction::Forward => preds.get(f).is_none_or(|p| p.is_empty()),
+            Direction::Backward => trees[f].as_ref().is_none_or(|t| t.targets().is_empty()),
+        };
+        let i = if boundary {
+            a.boundary(m, f)
+        } else {
+            A::Fact::bottom()
+        };
+        input.insert(f.clone(), i);
+    }
+    let mut work: VecDeque<FunId<M>> = match A::DIRECTION {
+        Direction::Forward => keys.iter().cloned().collect(),
+        Direction::Backward => keys.iter().rev().cloned().collect(),
+    };
+    let mut queued: BTreeSet<FunId<M>> = keys.iter().cloned().collect();
+    while let Some(f) = work.pop_front() {
+        queued.remove(&f);
+        let fun = &m.code()[f.clone()];
+        let mut fact = input[&f].clone();
+        let mut vals = fun.all().keys();
+        if A::DIRECTION == Direction::Backward {
+            vals.reverse();
+            if let Some(t) = &trees[&f] {
+                a.terminator(m, &f, t, &mut fact);
+            }
+        }
+        for v in vals {
+            let s = fun.all()[v.clone()].into_statement(fun);
+            a.transfer(m, &f, &v, &s, &mut fact);
+        }
+        if A::DIRECTION == Direction::Forward {
+            if let Some(t) = &trees[&f] {
+                a.terminator(m, &f, t, &mut fact);
+            }
+        }
+        output.insert(f.clone(), fact.clone());
+        match A::DIRECTION {
+            Direction::Forward => {
+                for e in trees[&f].iter().flat_map(|t| t.targets()) {
+                    let mut g = fact.clone();
+                    a.edge(m, &f, e, &mut g);
+                    let Some(i) = input.get_mut(&e.fun) else {
+                        continue;
+                    };
+                    if i.join(&g) && queued.insert(e.fun.clone()) {
+                        work.push_back(e.fun.clone());
+                    }
+                }
+            }
+            Direction::Backward => {
+                for p in preds.get(&f).into_iter().flatten() {
+                    let Some(Some(t)) = trees.get(p) else {
+                        continue;
+                    };
+                    let mut changed = false;
+                    for e in t.targets().into_iter().filter(|e| e.fun == f) {
+                        let mut g = fact.clone();
+                        a.edge(m, p, e, &mut g);
+                        changed |= input.get_mut(p).unwrap().join(&g);
+                    }
+                    if changed && queued.insert(p.clone()) {
+                        work.push_back(p.clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok(match A::DIRECTION {
+        Direction::Forward => Solution {
+            entry: input,
+            exit: output,
+        },
+        Direction::Backward => Solution {
+            entry: output,
+            exit: input,
+        },
+    })
+}
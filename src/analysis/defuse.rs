@@ -0,0 +1,196 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::compat::{
+    stmt::{Statement, Stmt},
+    tree::{Tree, UnTreeTerminator},
+    ArenaLikeIter, FunId, FunLike, ModLikeIter, Term, Val, ValID,
+};
+
+/// A single use of a value within a function.
+pub enum Use<M: ModLikeIter> {
+    /// Operand of another value, through `Stmt::Basic` or `Stmt::Pick`.
+    Value(ValID<M>),
+    /// Selector of the terminator's `Tree::Switch`.
+    Switch,
+    /// Argument `idx` passed along to `target` by the terminator, branching or tail calling.
+    Arg { target: FunId<M>, idx: usize },
+    /// Return value `idx` of the terminator's `Tree::Return`.
+    Return { idx: usize },
+}
+impl<M: ModLikeIter> Clone for Use<M>
+where
+    ValID<M>: Clone,
+    FunId<M>: Clone,
+{
+    fn clone(&self) -> Self {
+        match self {
+            Use::Value(v) => Use::Value(v.clone()),
+            Use::Switch => Use::Switch,
+            Use::Arg { target, idx } => Use::Arg {
+                target: target.clone(),
+                idx: *idx,
+            },
+            Use::Return { idx } => Use::Return { idx: *idx },
+        }
+    }
+}
+
+/// A used value, paired with how it is used.
+pub type Operand<M> = (ValID<M>, Use<M>);
+
+/// Values used by the statement `v` of `f`.
+pub fn stmt_operands<M: ModLikeIter>(f: &M::Fun, v: &Val<M>) -> Vec<ValID<M>>
+where
+    Val<M>: Statement<M>,
+{
+    match v.into_statement(f) {
+        Stmt::Basic(_, args) => args,
+        Stmt::Pick(v, _) => vec![v],
+        Stmt::Param(_) => vec![],
+    }
+}
+
+/// Values used by the terminator of `f`, along with how they are used.
+pub fn term_operands<M: ModLikeIter, Err>(f: &M::Fun) -> Result<Vec<Operand<M>>, Err>
+where
+    Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    FunId<M>: Clone,
+    ValID<M>: Clone,
+{
+    let mut all = vec![];
+    let entries = match f.terminator().get_tree(f)? {
+        None => vec![],
+        Some(Tree::TailCall(e)) => vec![e],
+        Some(Tree::Return(vs)) => {
+            all.extend(
+                vs.into_iter()
+                    .enumerate()
+                    .map(|(idx, v)| (v, Use::Return { idx })),
+            );
+            vec![]
+        }
+        Some(t) => {
+            if let Tree::Switch(v, _, _) = &t {
+                all.push((v.clone(), Use::Switch));
+            }
+            t.entries()
+        }
+    };
+    for e in entries {
+        for (idx, a) in e.args.into_iter().enumerate() {
+            all.push((
+                a,
+                Use::Arg {
+                    target: e.fun.clone(),
+                    idx,
+                },
+            ));
+        }
+    }
+    Ok(all)
+}
+
+type FunUses<M> = BTreeMap<ValID<M>, Vec<Use<M>>>;
+
+/// Def-use index over every function of a module.
+///
+/// [`DefUse::refresh`] recomputes the functions whose [`FunLike::version`] changed, and any new
+/// ones. Edits the backend does not count, such as through its own handles, are reported with
+/// [`DefUse::invalidate`]; [`DefUse::pushed`] records a single push without recomputing.
+pub struct DefUse<M: ModLikeIter> {
+    uses: BTreeMap<FunId<M>, FunUses<M>>,
+    dirty: BTreeSet<FunId<M>>,
+    versions: BTreeMap<FunId<M>, u64>,
+}
+impl<M: ModLikeIter> DefUse<M>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Ord + Clone,
+    Val<M>: Statement<M>,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>>,
+{
+    pub fn new<Err>(m: &M) -> Result<Self, Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        let mut d = DefUse {
+            uses: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            versions: BTreeMap::new(),
+        };
+        d.refresh(m)?;
+        Ok(d)
+    }
+    /// Uses of `v` within `f`.
+    pub fn uses(&self, f: &FunId<M>, v: &ValID<M>) -> &[Use<M>] {
+        self.uses
+            .get(f)
+            .and_then(|u| u.get(v))
+            .map(|a| &a[..])
+            .unwrap_or(&[])
+    }
+    /// Uses of `v` in every function, for backends whose values are shared between functions.
+    pub fn all_uses<'a>(
+        &'a self,
+        v: &'a ValID<M>,
+    ) -> impl Iterator<Item = (&'a FunId<M>, &'a Use<M>)> + 'a {
+        self.uses
+            .iter()
+            .flat_map(move |(f, u)| u.get(v).into_iter().flatten().map(move |u| (f, u)))
+    }
+    pub fn is_dead(&self, f: &FunId<M>, v: &ValID<M>) -> bool {
+        self.uses(f, v).is_empty()
+    }
+    /// Records the operands of `v`, freshly pushed into `f` as its only change.
+    pub fn pushed(&mut self, m: &M, f: FunId<M>, v: ValID<M>) {
+        if self.dirty.contains(&f) {
+            return;
+        }
+        let fun = &m.code()[f.clone()];
+        let now = fun.version();
+        match self.versions.get(&f) {
+            Some(&seen) if seen == now || seen + 1 == now => {}
+            _ => {
+                self.dirty.insert(f);
+                return;
+            }
+        }
+        self.versions.insert(f.clone(), now);
+        let u = self.uses.entry(f).or_default();
+        for a in stmt_operands::<M>(fun, &fun.all()[v.clone()]) {
+            u.entry(a).or_default().push(Use::Value(v.clone()));
+        }
+    }
+    /// Marks `f` as changed in a way its [`FunLike::version`] does not count.
+    pub fn invalidate(&mut self, f: FunId<M>) {
+        self.dirty.insert(f);
+    }
+    /// Recomputes every function changed since it was last computed, or marked by
+    /// [`DefUse::invalidate`].
+    pub fn refresh<Err>(&mut self, m: &M) -> Result<(), Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        let mut stale = std::mem::take(&mut self.dirty);
+        for f in m.keys() {
+            if self.versions.get(&f) != Some(&m.code()[f.clone()].version()) {
+                stale.insert(f);
+            }
+        }
+        for f in stale {
+            let fun = &m.code()[f.clone()];
+            self.versions.insert(f.clone(), fun.version());
+            let mut u: FunUses<M> = BTreeMap::new();
+            for v in fun.all().keys() {
+                for a in stmt_operands::<M>(fun, &fun.all()[v.clone()]) {
+                    u.entry(a).or_default().push(Use::Value(v.clone()));
+                }
+            }
+            for (a, k) in term_operands(fun)? {
+                u.entry(a).or_default().push(k);
+            }
+            self.uses.insert(f, u);
+        }
+        Ok(())
+    }
+}
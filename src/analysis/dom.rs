@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use crate::compat::{FunId, ModLikeIter};
+
+use super::cfg::Cfg;
+
+/// Dominator tree of the functions reachable from `root`, after Cooper, Harvey and Kennedy.
+pub struct Dominators<M: ModLikeIter> {
+    pub root: FunId<M>,
+    idom: BTreeMap<FunId<M>, FunId<M>>,
+}
+impl<M: ModLikeIter> Dominators<M>
+where
+    FunId<M>: Ord + Clone,
+{
+    pub fn new(cfg: &Cfg<M>, root: FunId<M>) -> Self {
+        let rpo = cfg.reverse_postorder(&root);
+        let order: BTreeMap<_, _> = rpo
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, f)| (f, i))
+            .collect();
+        let mut idom = BTreeMap::new();
+        idom.insert(root.clone(), root.clone());
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for f in rpo.iter().skip(1) {
+                let mut new: Option<FunId<M>> = None;
+                for p in cfg.preds(f).iter().filter(|p| idom.contains_key(*p)) {
+                    new = Some(match new {
+                        None => p.clone(),
+                        Some(n) => Self::intersect(&idom, &order, p.clone(), n),
+                    });
+                }
+                let Some(new) = new else {
+                    continue;
+                };
+                if idom.get(f) != Some(&new) {
+                    idom.insert(f.clone(), new);
+                    changed = true;
+                }
+            }
+        }
+        Dominators { root, idom }
+    }
+    fn intersect(
+        idom: &BTreeMap<FunId<M>, FunId<M>>,
+        order: &BTreeMap<FunId<M>, usize>,
+        mut a: FunId<M>,
+        mut b: FunId<M>,
+    ) -> FunId<M> {
+        while a != b {
+            while order[&a] > order[&b] {
+                a = idom[&a].clone();
+            }
+            while order[&b] > order[&a] {
+                b = idom[&b].clone();
+            }
+        }
+        a
+    }
+    /// Immediate dominator of `f`; `None` for the root and unreachable functions.
+    pub fn idom(&self, f: &FunId<M>) -> Option<&FunId<M>> {
+        self.idom.get(f).filter(|d| **d != *f)
+    }
+    pub fn is_reachable(&self, f: &FunId<M>) -> bool {
+        self.idom.contains_key(f)
+    }
+    /// Whether every path from the root to `b` goes through `a`.
+    pub fn dominates(&self, a: &FunId<M>, b: &FunId<M>) -> bool {
+        if !self.is_reachable(b) {
+            return false;
+        }
+        let mut b = b;
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom(b) {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::compat::{
+    stmt::Statement, tree::UnTreeTerminator, ArenaLikeIter, FunId, FunLike, ModLikeIter, Term, Val,
+    ValID,
+};
+
+use super::defuse::{stmt_operands, term_operands};
+
+/// Backward liveness over the block graph given by [`UnTreeTerminator::get_tree`].
+///
+/// Values may be used outside of the function defining them (as waffle blocks do), so a value is
+/// live into every function between its definition and its uses. Parameters are defined by the
+/// function receiving them.
+///
+/// Liveness is solved over the whole module at once; [`Liveness::refresh`] solves it again
+/// once any function's [`FunLike::version`] changed.
+pub struct Liveness<M: ModLikeIter> {
+    pub live_in: BTreeMap<FunId<M>, BTreeSet<ValID<M>>>,
+    pub live_out: BTreeMap<FunId<M>, BTreeSet<ValID<M>>>,
+    pressure: BTreeMap<FunId<M>, usize>,
+    versions: BTreeMap<FunId<M>, u64>,
+}
+impl<M: ModLikeIter> Liveness<M>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Ord + Clone,
+    Val<M>: Statement<M>,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>>,
+{
+    pub fn new<Err>(m: &M) -> Result<Self, Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        let keys = m.keys();
+        let mut succs: BTreeMap<FunId<M>, Vec<FunId<M>>> = BTreeMap::new();
+        let mut gen: BTreeMap<FunId<M>, BTreeSet<ValID<M>>> = BTreeMap::new();
+        let mut kill: BTreeMap<FunId<M>, BTreeSet<ValID<M>>> = BTreeMap::new();
+        for f in keys.iter() {
+            let fun = &m.code()[f.clone()];
+            let defs: BTreeSet<_> = fun.all().keys().into_iter().collect();
+            let mut uses = BTreeSet::new();
+            for v in fun.all().keys() {
+                uses.extend(stmt_operands::<M>(fun, &fun.all()[v]));
+            }
+            uses.extend(term_operands::<M, Err>(fun)?.into_iter().map(|a| a.0));
+            let s = match fun.terminator().get_tree(fun)? {
+                None => vec![],
+                Some(t) => t.entries().into_iter().map(|e| e.fun).collect(),
+            };
+            succs.insert(f.clone(), s);
+            gen.insert(f.clone(), uses.difference(&defs).cloned().collect());
+            kill.insert(f.clone(), defs);
+        }
+        let mut live_in: BTreeMap<FunId<M>, BTreeSet<ValID<M>>> = gen.clone();
+        let mut live_out: BTreeMap<FunId<M>, BTreeSet<ValID<M>>> = BTreeMap::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for f in keys.iter().rev() {
+                let mut out = BTreeSet::new();
+                for s in &succs[f] {
+                    out.extend(live_in.get(s).into_iter().flatten().cloned());
+                }
+                let mut i = gen[f].clone();
+                i.extend(out.difference(&kill[f]).cloned());
+                if i != live_in[f] {
+                    live_in.insert(f.clone(), i);
+                    changed = true;
+                }
+                live_out.insert(f.clone(), out);
+            }
+        }
+        let mut l = Liveness {
+            live_in,
+            live_out,
+            pressure: BTreeMap::new(),
+            versions: Self::versions(m),
+        };
+        for f in keys {
+            let p = l.walk(m, &f)?;
+            l.pressure.insert(f, p);
+        }
+        Ok(l)
+    }
+    fn versions(m: &M) -> BTreeMap<FunId<M>, u64> {
+        let v = |f: FunId<M>| (f.clone(), m.code()[f].version());
+        m.keys().into_iter().map(v).collect()
+    }
+    /// Whether any function was added, or changed as far as its [`FunLike::version`] tells.
+    pub fn is_stale(&self, m: &M) -> bool {
+        Self::versions(m) != self.versions
+    }
+    /// Solves liveness again if it [`Liveness::is_stale`], returning whether it was.
+    pub fn refresh<Err>(&mut self, m: &M) -> Result<bool, Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        if !self.is_stale(m) {
+            return Ok(false);
+        }
+        *self = Self::new(m)?;
+        Ok(true)
+    }
+    /// Highest number of simultaneously live values at any point of `f`.
+    fn walk<Err>(&self, m: &M, f: &FunId<M>) -> Result<usize, Err>
+    where
+        Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    {
+        let fun = &m.code()[f.clone()];
+        let mut live = self.live_out[f].clone();
+        live.extend(term_operands::<M, Err>(fun)?.into_iter().map(|a| a.0));
+        let mut max = live.len();
+        for v in fun.all().keys().into_iter().rev() {
+            let ops = stmt_operands::<M>(fun, &fun.all()[v.clone()]);
+            live.remove(&v);
+            live.extend(ops);
+            max = max.max(live.len());
+        }
+        Ok(max)
+    }
+    pub fn is_live_in(&self, f: &FunId<M>, v: &ValID<M>) -> bool {
+        self.live_in.get(f).is_some_and(|a| a.contains(v))
+    }
+    pub fn is_live_out(&self, f: &FunId<M>, v: &ValID<M>) -> bool {
+        self.live_out.get(f).is_some_and(|a| a.contains(v))
+    }
+    /// Functions `v` is live across, in or out.
+    pub fn range(&self, v: &ValID<M>) -> BTreeSet<FunId<M>> {
+        self.live_in
+            .iter()
+            .chain(self.live_out.iter())
+            .filter(|a| a.1.contains(v))
+            .map(|a| a.0.clone())
+            .collect()
+    }
+    /// Maximum register pressure within `f`.
+    pub fn pressure(&self, f: &FunId<M>) -> usize {
+        self.pressure.get(f).cloned().unwrap_or_default()
+    }
+    /// Maximum register pressure across every function of the module.
+    pub fn max_pressure(&self) -> usize {
+        self.pressure.values().cloned().max().unwrap_or_default()
+    }
+}
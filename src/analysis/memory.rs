@@ -0,0 +1,28 @@
+/// A load or store, addressed by its first operand; stores take the stored value second.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Access<K> {
+    pub memory: K,
+    pub store: bool,
+    /// Constant offset added to the address.
+    pub offset: u64,
+    /// Bytes accessed.
+    pub size: u64,
+}
+
+/// Operators of a backend with linear memories.
+pub trait MemoryOp {
+    type Memory: Ord + Clone;
+    /// The access this makes, if it loads or stores.
+    fn access(&self) -> Option<Access<Self::Memory>>;
+    /// Whether this may change memory in ways [`MemoryOp::access`] does not describe, such as
+    /// calls and growing a memory.
+    fn clobbers(&self) -> bool;
+    /// Whether this may trap other than by going out of bounds in [`MemoryOp::access`].
+    fn traps(&self) -> bool;
+    /// Whether, as a load, this reads back exactly the value `store` stored to the same place.
+    fn reloads(&self, store: &Self) -> bool;
+    /// Whether this changes any state, so that a trap moved before it would be observable.
+    fn writes(&self) -> bool {
+        self.clobbers() || self.access().is_some_and(|a| a.store)
+    }
+}
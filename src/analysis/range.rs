@@ -0,0 +1,303 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+};
+
+use crate::compat::{
+    rewrite::Op,
+    stmt::{Statement, Stmt},
+    tree::{Entry, Tree},
+    ArenaLikeIter, FunId, FunLike, ModLikeIter, Val, ValID,
+};
+
+use super::dataflow::{Analysis, Direction, Lattice};
+
+/// How many times the bounds of a block parameter may grow before they are widened.
+const WIDEN_AFTER: u8 = 2;
+
+/// Unsigned bounds `lo..=hi` on an integer no greater than `max`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Range {
+    pub lo: u64,
+    pub hi: u64,
+    pub max: u64,
+}
+impl Range {
+    pub fn new(lo: u64, hi: u64, max: u64) -> Self {
+        Range { lo, hi, max }
+    }
+    pub fn exact(v: u64, max: u64) -> Self {
+        Range::new(v, v, max)
+    }
+    pub fn full(max: u64) -> Self {
+        Range::new(0, max, max)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lo > self.hi
+    }
+    /// The only value in the range, if there is one.
+    pub fn value(&self) -> Option<u64> {
+        (self.lo == self.hi).then_some(self.lo)
+    }
+    /// The smallest range holding both.
+    pub fn hull(&self, other: &Range) -> Range {
+        let mut r = *self;
+        r.join(other);
+        r
+    }
+}
+impl Lattice for Range {
+    fn bottom() -> Self {
+        Range::new(1, 0, 0)
+    }
+
+    fn join(&mut self, other: &Self) -> bool {
+        if other.is_empty() {
+            return false;
+        }
+        let old = *self;
+        *self = match self.is_empty() {
+            true => *other,
+            false => Range::new(
+                self.lo.min(other.lo),
+                self.hi.max(other.hi),
+                self.max.max(other.max),
+            ),
+        };
+        *self != old
+    }
+}
+
+/// `args[lhs] < args[rhs]` if `strict`, `args[lhs] <= args[rhs]` otherwise, unsigned.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Compare {
+    pub lhs: usize,
+    pub rhs: usize,
+    pub strict: bool,
+}
+
+/// Operators on integers whose results can be bounded.
+pub trait RangeOp {
+    /// Bounds of the result, from those of the operands where they are known.
+    fn range(&self, args: &[Option<Range>]) -> Option<Range>;
+    /// The test this makes, if it is `1` when one operand is below another and `0` otherwise.
+    fn compare(&self, args: &[Option<Range>]) -> Option<Compare>;
+    /// Whether this adds its two operands, without wrapping if their bounds say it cannot.
+    fn is_add(&self) -> bool;
+}
+
+/// Bounds of the values known at a point.
+///
+/// Unlike maps in general, a join keeps only the values bounded on both sides: one left out is
+/// unbounded on its side.
+pub struct Ranged<M: ModLikeIter>(pub BTreeMap<ValID<M>, Range>);
+impl<M: ModLikeIter> Ranged<M> {
+    pub fn new() -> Self {
+        Ranged(BTreeMap::new())
+    }
+}
+impl<M: ModLikeIter> Default for Ranged<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<M: ModLikeIter> Clone for Ranged<M>
+where
+    ValID<M>: Clone,
+{
+    fn clone(&self) -> Self {
+        Ranged(self.0.clone())
+    }
+}
+impl<M: ModLikeIter> Deref for Ranged<M> {
+    type Target = BTreeMap<ValID<M>, Range>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl<M: ModLikeIter> DerefMut for Ranged<M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl<M: ModLikeIter> Lattice for Ranged<M>
+where
+    ValID<M>: Ord + Clone,
+{
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn join(&mut self, other: &Self) -> bool {
+        let n = self.len();
+        self.retain(|k, _| other.contains_key(k));
+        let mut changed = self.len() != n;
+        for (k, r) in self.iter_mut() {
+            changed |= r.join(&other[k]);
+        }
+        changed
+    }
+}
+
+struct Branch<M: ModLikeIter> {
+    cond: ValID<M>,
+    /// Where the branch goes when `cond` is zero, then where it goes otherwise.
+    to: [(FunId<M>, Vec<ValID<M>>); 2],
+}
+
+/// Forward interval analysis of integer values, refined along branches on comparisons.
+///
+/// Block parameters take the hull of their arguments, widened once they keep growing, so a
+/// loop counter is only bounded past the loop's test.
+pub struct Ranges<M: ModLikeIter> {
+    branches: BTreeMap<FunId<M>, Branch<M>>,
+    /// Hull of the arguments of each block parameter so far, and how often it grew, by the
+    /// function it belongs to, as backends may number values per function.
+    params: BTreeMap<(FunId<M>, ValID<M>), (Range, u8)>,
+}
+impl<M: ModLikeIter> Default for Ranges<M> {
+    fn default() -> Self {
+        Ranges {
+            branches: BTreeMap::new(),
+            params: BTreeMap::new(),
+        }
+    }
+}
+impl<M: ModLikeIter> Ranges<M>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Ord + Clone,
+    Val<M>: Statement<M>,
+    Op<M>: RangeOp,
+{
+    /// Joins `r` into the bounds of the parameter `v` of `f`, widening them to `0..=max` once
+    /// they keep growing, so that loops reach a fixpoint.
+    fn widen(&mut self, f: FunId<M>, v: ValID<M>, r: Range) -> Range {
+        let (w, grown) = self.params.entry((f, v)).or_insert((r, 0));
+        let old = *w;
+        if w.join(&r) {
+            *grown = grown.saturating_add(1);
+            if *grown > WIDEN_AFTER {
+                if w.lo < old.lo {
+                    w.lo = 0;
+                }
+                if w.hi > old.hi {
+                    w.hi = w.max;
+                }
+            }
+        }
+        *w
+    }
+    /// Narrows `fact` as if `c` evaluated to `taken`.
+    fn refine(c: Compare, args: &[ValID<M>], taken: bool, fact: &mut Ranged<M>) {
+        let (Some(l), Some(r)) = (args.get(c.lhs), args.get(c.rhs)) else {
+            return;
+        };
+        // Failing `l < r` means `r <= l`, and failing `l <= r` means `r < l`.
+        let (l, r, strict) = match taken {
+            true => (l, r, c.strict),
+            false => (r, l, !c.strict),
+        };
+        let Some(mut b) = fact.get(r).copied() else {
+            return;
+        };
+        let mut a = fact.get(l).copied().unwrap_or(Range::full(b.max));
+        let d = strict as u64;
+        if b.hi < d || a.lo.checked_add(d).is_none() {
+            return;
+        }
+        a.hi = a.hi.min(b.hi - d);
+        b.lo = b.lo.max(a.lo + d);
+        if a.is_empty() || b.is_empty() {
+            return;
+        }
+        fact.insert(l.clone(), a);
+        fact.insert(r.clone(), b);
+    }
+}
+impl<M: ModLikeIter> Analysis<M> for Ranges<M>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Ord + Clone,
+    Val<M>: Statement<M>,
+    Op<M>: RangeOp,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>>,
+{
+    /// `None` where the block is not known to be reached yet.
+    type Fact = Option<Ranged<M>>;
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&mut self, _: &M, _: &FunId<M>) -> Self::Fact {
+        Some(Ranged::<M>::new())
+    }
+
+    fn transfer(
+        &mut self,
+        _: &M,
+        _: &FunId<M>,
+        v: &ValID<M>,
+        s: &Stmt<Val<M>, M>,
+        fact: &mut Self::Fact,
+    ) {
+        let Some(fact) = fact else {
+            return;
+        };
+        let r = match s {
+            // Set along the edges into the function.
+            Stmt::Param(_) => return,
+            Stmt::Pick(_, _) => None,
+            Stmt::Basic(o, args) => {
+                let a: Vec<_> = args.iter().map(|a| fact.get(a).copied()).collect();
+                o.range(&a)
+            }
+        };
+        match r {
+            Some(r) => fact.insert(v.clone(), r),
+            None => fact.remove(v),
+        };
+    }
+
+    fn terminator(&mut self, _: &M, f: &FunId<M>, t: &Tree<M, M::Fun>, _: &mut Self::Fact) {
+        let Tree::Switch(cond, go, default) = t else {
+            return;
+        };
+        let [zero] = &go[..] else {
+            return;
+        };
+        let to = |e: &Entry<M>| (e.fun.clone(), e.args.clone());
+        let b = Branch {
+            cond: cond.clone(),
+            to: [to(zero), to(default)],
+        };
+        self.branches.insert(f.clone(), b);
+    }
+
+    fn edge(&mut self, m: &M, from: &FunId<M>, e: &Entry<M>, fact: &mut Self::Fact) {
+        let Some(fact) = fact else {
+            return;
+        };
+        if let Some(b) = self.branches.get(from) {
+            let hit = |i: usize| b.to[i].0 == e.fun && b.to[i].1 == e.args;
+            let fun = &m.code()[from.clone()];
+            if let (Stmt::Basic(o, args), true) = (
+                fun.all()[b.cond.clone()].into_statement(fun),
+                hit(0) != hit(1),
+            ) {
+                let a: Vec<_> = args.iter().map(|a| fact.get(a).copied()).collect();
+                if let Some(c) = o.compare(&a) {
+                    Self::refine(c, &args, hit(1), fact);
+                }
+            }
+        }
+        let to = &m.code()[e.fun.clone()];
+        for v in to.all().keys() {
+            if let Stmt::Param(i) = to.all()[v.clone()].into_statement(to) {
+                // Left out, the parameter would only take the bounds of the other edges.
+                let r = e.args.get(i).and_then(|a| fact.get(a)).copied();
+                let r = r.unwrap_or(Range::full(u64::MAX));
+                let r = self.widen(e.fun.clone(), v.clone(), r);
+                fact.insert(v, r);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::ops::{Index, IndexMut};
 
+use either::Either;
 use id_arena::{Arena, Id};
 
 // use crate::{Fun, Module, ValueDef};
@@ -24,10 +25,21 @@ pub trait ArenaLike<T>: Index<Self::Id, Output = T> + IndexMut<Self::Id, Output
     type Id;
     fn push(&mut self, a: T) -> Self::Id;
 }
+pub trait ArenaLikeIter<T>: ArenaLike<T> {
+    fn keys(&self) -> Vec<Self::Id>;
+}
 pub trait OrderedArenaLike<T>: ArenaLike<T>{
     fn push_after(&mut self, a: T, after: Self::Id) -> Self::Id;
     fn push_just_before(&mut self, a: T, before: Self::Id) -> Self::Id;
 }
+pub trait MutableArenaLike<T>: ArenaLike<T> {
+    /// Removes `id`, returning its definition. Remaining uses of `id` are left dangling.
+    fn remove(&mut self, id: Self::Id) -> T;
+    /// Rewrites every value and terminator operand referring to `old` to refer to `new`.
+    fn replace_all_uses_with(&mut self, old: Self::Id, new: Self::Id);
+    /// Values whose operands include `id`. Terminator uses are not reported.
+    fn uses(&self, id: Self::Id) -> Vec<Self::Id>;
+}
 impl<T> ArenaLike<T> for Arena<T> {
     type Id = Id<T>;
 
@@ -35,6 +47,11 @@ impl<T> ArenaLike<T> for Arena<T> {
         return self.alloc(a);
     }
 }
+impl<T> ArenaLikeIter<T> for Arena<T> {
+    fn keys(&self) -> Vec<Self::Id> {
+        self.iter().map(|a| a.0).collect()
+    }
+}
 pub trait FunLike {
     type Value;
     type Arena: ArenaLike<Self::Value>;
@@ -43,6 +60,11 @@ pub trait FunLike {
     type Terminator;
     fn terminator(&self) -> &Self::Terminator;
     fn terminator_mut(&mut self) -> &mut Self::Terminator;
+    /// Counts changes made through [`FunLike::all_mut`] and [`FunLike::terminator_mut`], so
+    /// that analyses can tell when they are stale. Always zero for backends not counting them.
+    fn version(&self) -> u64 {
+        0
+    }
 }
 // impl<T, Y, R, D> FunLike for Fun<T, Y, R, D> {
 //     type Value = ValueDef<T, Y, R, D>;
@@ -79,6 +101,26 @@ pub trait ModLike {
 }
 pub trait ModLikeIter: ModLike{
     fn keys(&self) -> Vec<FunId<Self>>;
+    /// Every datum of the module.
+    fn data_keys(&self) -> Vec<DatId<Self>> {
+        vec![]
+    }
+}
+/// Modules reachable from outside, through named exports or an entry point.
+pub trait ModLikeExports: ModLike {
+    /// Exports by name, and the entry point under `None`.
+    fn exports(&self) -> Vec<(Option<String>, Exported<Self>)>;
+    fn set_export(&mut self, name: Option<&str>, x: Exported<Self>);
+}
+/// Functions with explicit parameters, which are values of their arena.
+pub trait ParamFunLike: FunLike {
+    type Param;
+    fn params(&self) -> Vec<(Self::Param, ValIDFun<Self>)>;
+    fn add_param(&mut self, p: Self::Param) -> ValIDFun<Self>;
+    /// Types of the results.
+    fn returns(&self) -> Vec<Self::Param>;
+    /// Gives the function its parameters so far and `r` as its signature.
+    fn set_returns(&mut self, r: Vec<Self::Param>);
 }
 // impl<T, Y, R, D> ModLike for Module<T, Y, R, D> {
 //     type Fun = Fun<T, Y, R, D>;
@@ -110,4 +152,5 @@ pub type ValID<A: ModLike> = ValIDFun<A::Fun>;
 pub type FunId<A: ModLike> = <A::Code as ArenaLike<A::Fun>>::Id;
 pub type DatId<A: ModLike> = <A::Data as ArenaLike<A::Datum>>::Id;
 pub type Val<A: ModLike> = <A::Fun as FunLike>::Value;
-pub type Term<A: ModLike> = <A::Fun as FunLike>::Terminator;
\ No newline at end of file
+pub type Term<A: ModLike> = <A::Fun as FunLike>::Terminator;
+pub type Exported<A> = Either<FunId<A>, DatId<A>>;
\ No newline at end of file
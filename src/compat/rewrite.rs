@@ -1 +1,278 @@
-use super::{FunLike, ModLike, ValIDFun};
+use std::collections::BTreeMap;
+
+use super::{
+    stmt::{Statement, Stmt},
+    typed::{TypedFunLike, TypedValue},
+    ArenaLikeIter, FunLike, ModLike, ModLikeIter, MutableArenaLike, OrderedArenaLike, Val, ValID,
+};
+
+pub type Op<In> = <Val<In> as Statement<In>>::Stmt;
+type OpPred<In> = Box<dyn Fn(&Op<In>) -> bool>;
+type Guard<In> = Box<dyn Fn(&<In as ModLike>::Fun, &ValID<In>) -> bool>;
+type OpBuild<In> = Box<dyn Fn(&Captures<In>) -> Option<Op<In>>>;
+
+/// Values and operators bound while matching a [`Pattern`].
+pub struct Captures<In: ModLike>
+where
+    Val<In>: Statement<In>,
+{
+    pub vals: BTreeMap<usize, ValID<In>>,
+    pub ops: BTreeMap<usize, Op<In>>,
+}
+impl<In: ModLike> Default for Captures<In>
+where
+    Val<In>: Statement<In>,
+{
+    fn default() -> Self {
+        Captures {
+            vals: BTreeMap::new(),
+            ops: BTreeMap::new(),
+        }
+    }
+}
+
+/// A tree pattern over `Stmt::Basic` values.
+pub enum Pattern<In: ModLike>
+where
+    Val<In>: Statement<In>,
+{
+    /// Matches any value, binding it to a slot. A slot bound twice must match the same value.
+    Any(usize),
+    /// Matches `Stmt::Basic` whose operator satisfies the predicate and whose operands match in
+    /// order, optionally binding the operator to a slot.
+    Op(Option<usize>, OpPred<In>, Vec<Pattern<In>>),
+    /// Matches when both the inner pattern and the guard accept the value.
+    Guard(Box<Pattern<In>>, Guard<In>),
+}
+impl<In: ModLike> Pattern<In>
+where
+    Val<In>: Statement<In>,
+    ValID<In>: Clone + PartialEq,
+{
+    pub fn any(slot: usize) -> Self {
+        Pattern::Any(slot)
+    }
+    pub fn op(pred: impl Fn(&Op<In>) -> bool + 'static, args: Vec<Pattern<In>>) -> Self {
+        Pattern::Op(None, Box::new(pred), args)
+    }
+    /// Like [`Pattern::op`], binding the operator to `slot`.
+    pub fn op_as(
+        slot: usize,
+        pred: impl Fn(&Op<In>) -> bool + 'static,
+        args: Vec<Pattern<In>>,
+    ) -> Self {
+        Pattern::Op(Some(slot), Box::new(pred), args)
+    }
+    pub fn guard(self, g: impl Fn(&In::Fun, &ValID<In>) -> bool + 'static) -> Self {
+        Pattern::Guard(Box::new(self), Box::new(g))
+    }
+    /// Restricts the pattern to values whose type satisfies `pred`.
+    pub fn typed(self, pred: impl Fn(&<In::Fun as TypedFunLike>::Type) -> bool + 'static) -> Self
+    where
+        In::Fun: TypedFunLike,
+        Val<In>: TypedValue<In::Fun, Type = <In::Fun as TypedFunLike>::Type>,
+    {
+        self.guard(move |f, v| pred(&f.all()[v.clone()].type_of(f)))
+    }
+    pub fn matches(&self, f: &In::Fun, v: &ValID<In>, caps: &mut Captures<In>) -> bool {
+        match self {
+            Pattern::Any(slot) => match caps.vals.get(slot) {
+                Some(w) => w == v,
+                None => {
+                    caps.vals.insert(*slot, v.clone());
+                    true
+                }
+            },
+            Pattern::Op(slot, pred, args) => {
+                let Stmt::Basic(op, a) = f.all()[v.clone()].into_statement(f) else {
+                    return false;
+                };
+                if !pred(&op) || a.len() != args.len() {
+                    return false;
+                }
+                if !a.iter().zip(args).all(|(a, p)| p.matches(f, a, caps)) {
+                    return false;
+                }
+                if let Some(slot) = slot {
+                    caps.ops.insert(*slot, op);
+                }
+                true
+            }
+            Pattern::Guard(p, g) => p.matches(f, v, caps) && g(f, v),
+        }
+    }
+}
+
+/// Replacement for a matched value.
+pub enum Template<In: ModLike>
+where
+    Val<In>: Statement<In>,
+{
+    /// A value bound by the pattern.
+    Capture(usize),
+    /// A new `Stmt::Basic`; the operator is built from the captures, and a `None` aborts the
+    /// rewrite.
+    Op(OpBuild<In>, Vec<Template<In>>),
+}
+impl<In: ModLike> Template<In>
+where
+    Val<In>: Statement<In>,
+{
+    pub fn capture(slot: usize) -> Self {
+        Template::Capture(slot)
+    }
+    pub fn op(
+        build: impl Fn(&Captures<In>) -> Option<Op<In>> + 'static,
+        args: Vec<Template<In>>,
+    ) -> Self {
+        Template::Op(Box::new(build), args)
+    }
+}
+
+pub struct Rule<In: ModLike>
+where
+    Val<In>: Statement<In>,
+{
+    pub name: String,
+    pub pattern: Pattern<In>,
+    pub replacement: Template<In>,
+}
+
+/// How often each rule fired, and how many sweeps the fixpoint took.
+#[derive(Default, Clone, Debug)]
+pub struct Stats {
+    pub applied: BTreeMap<String, usize>,
+    pub sweeps: usize,
+}
+impl Stats {
+    pub fn total(&self) -> usize {
+        self.applied.values().sum()
+    }
+}
+
+/// Applies a set of [`Rule`]s until none of them matches anymore.
+pub struct Rewriter<In: ModLike>
+where
+    Val<In>: Statement<In>,
+{
+    pub rules: Vec<Rule<In>>,
+    pub stats: Stats,
+    /// Upper bound on sweeps over a function, guarding against rules that undo each other.
+    pub max_sweeps: usize,
+}
+impl<In: ModLike> Rewriter<In>
+where
+    Val<In>: Statement<In>,
+    ValID<In>: Clone + PartialEq,
+    <In::Fun as FunLike>::Arena:
+        ArenaLikeIter<Val<In>> + OrderedArenaLike<Val<In>> + MutableArenaLike<Val<In>>,
+{
+    pub fn new(rules: Vec<Rule<In>>) -> Self {
+        Rewriter {
+            rules,
+            stats: Stats::default(),
+            max_sweeps: 64,
+        }
+    }
+    pub fn rule(mut self, name: &str, pattern: Pattern<In>, replacement: Template<In>) -> Self {
+        self.rules.push(Rule {
+            name: name.to_owned(),
+            pattern,
+            replacement,
+        });
+        self
+    }
+    /// Rewrites `f` to a fixpoint, returning the number of rewrites.
+    pub fn run_fun(&mut self, f: &mut In::Fun) -> usize {
+        let mut n = 0;
+        for _ in 0..self.max_sweeps {
+            self.stats.sweeps += 1;
+            let mut changed = false;
+            let mut removed = vec![];
+            for v in f.all().keys() {
+                if removed.contains(&v) {
+                    continue;
+                }
+                for r in self.rules.iter() {
+                    let mut caps = Captures::default();
+                    if !r.pattern.matches(f, &v, &mut caps) {
+                        continue;
+                    }
+                    match Self::apply(f, &v, &r.replacement, &caps) {
+                        None => continue,
+                        Some(true) => removed.push(v.clone()),
+                        Some(false) => {}
+                    }
+                    *self.stats.applied.entry(r.name.clone()).or_default() += 1;
+                    n += 1;
+                    changed = true;
+                    break;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        n
+    }
+    /// Rewrites every function of `m`, returning the number of rewrites.
+    pub fn run(&mut self, m: &mut In) -> usize
+    where
+        In: ModLikeIter,
+    {
+        let mut n = 0;
+        for f in m.keys() {
+            n += self.run_fun(&mut m.code_mut()[f]);
+        }
+        n
+    }
+    /// Replaces `root` by `t`, returning whether `root` was removed.
+    fn apply(
+        f: &mut In::Fun,
+        root: &ValID<In>,
+        t: &Template<In>,
+        caps: &Captures<In>,
+    ) -> Option<bool> {
+        match t {
+            Template::Capture(slot) => {
+                let new = caps.vals.get(slot)?.clone();
+                if new == *root {
+                    return None;
+                }
+                f.all_mut().replace_all_uses_with(root.clone(), new);
+                f.all_mut().remove(root.clone());
+                Some(true)
+            }
+            Template::Op(build, args) => {
+                let op = build(caps)?;
+                let mut a = vec![];
+                for t in args {
+                    a.push(Self::build(f, root, t, caps)?);
+                }
+                let v = Val::<In>::from_statement(&Stmt::Basic(op, a), f).ok()?;
+                f.all_mut()[root.clone()] = v;
+                Some(false)
+            }
+        }
+    }
+    /// Materializes `t` just before `root`.
+    fn build(
+        f: &mut In::Fun,
+        root: &ValID<In>,
+        t: &Template<In>,
+        caps: &Captures<In>,
+    ) -> Option<ValID<In>> {
+        match t {
+            Template::Capture(slot) => caps.vals.get(slot).cloned(),
+            Template::Op(build, args) => {
+                let op = build(caps)?;
+                let mut a = vec![];
+                for t in args {
+                    a.push(Self::build(f, root, t, caps)?);
+                }
+                let v = Val::<In>::from_statement(&Stmt::Basic(op, a), f).ok()?;
+                Some(f.all_mut().push_just_before(v, root.clone()))
+            }
+        }
+    }
+}
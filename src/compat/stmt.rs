@@ -6,6 +6,7 @@ use id_arena::Id;
 use relooper::{BranchMode, RelooperLabel, ShapedBlock};
 
 // use crate::ValueDef;
+use crate::error::Error;
 use crate::utils::{my_hash, param_hash, var_hash};
 
 use super::ValIDFun;
@@ -22,5 +23,7 @@ pub enum Stmt<S: Statement<In>, In: ModLike> {
 pub trait Statement<In: ModLike>: Sized {
     type Stmt: Clone;
     fn into_statement(&self, f: &In::Fun) -> Stmt<Self, In>;
-    fn from_statement(s: &Stmt<Self, In>, f: &mut In::Fun) -> Self;
+    /// Builds the value `s` in `f`, failing if `s` does not fit it, such as an operator whose
+    /// operands have the wrong types.
+    fn from_statement(s: &Stmt<Self, In>, f: &mut In::Fun) -> Result<Self, Error<FunId<In>>>;
 }
@@ -9,17 +9,71 @@ pub struct Entry<M: ModLike> {
 }
 pub trait TreeTerminator<M: ModLike<Fun = F>, F: FunLike<Terminator = Self>, Err>: Sized {
     fn just(n: &mut F, x: Entry<M>) -> Result<Self, Err>;
+    /// Branches to `go[v]`, or to `default` if `v` is out of bounds; see [`Tree::Switch`].
     fn switch(n: &mut F, v: ValIDFun<F>, go: Vec<Entry<M>>, default: Entry<M>)
         -> Result<Self, Err>;
+    /// Returns `values` from the function.
+    fn r#return(n: &mut F, values: Vec<ValIDFun<F>>) -> Result<Self, Err>;
+    /// Traps, leaving the function without returning.
+    fn trap(n: &mut F) -> Result<Self, Err>;
+    /// Leaves the function for `x`, returning whatever it returns.
+    ///
+    /// Branches to `x` by default, for backends which emulate entering other functions.
+    /// Backends without tail calls may instead call `x` and return its results, keeping this
+    /// function's frame, so unbounded tail recursion can run out of stack; their
+    /// [`UnTreeTerminator::get_tree`] should read the calls they emitted this way back as
+    /// [`Tree::TailCall`], and leave other calls followed by a return as calls.
+    fn tail_call(n: &mut F, x: Entry<M>) -> Result<Self, Err> {
+        Self::just(n, x)
+    }
 }
 pub trait UnTreeTerminator<M: ModLike<Fun = F>, F: FunLike<Terminator = Self>, Err>:
     TreeTerminator<M, F, Err>
 {
     fn get_tree(&self, n: &F) -> Result<Option<Tree<M, F>>, Err>;
 }
+/// Terminators whose value operands can be rewritten, including those without a [`Tree`].
+pub trait MapTerminator<F: FunLike>: Sized {
+    fn map_values<Err>(
+        self,
+        f: &mut dyn FnMut(ValIDFun<F>) -> Result<ValIDFun<F>, Err>,
+    ) -> Result<Self, Err>;
+}
 pub enum Tree<M: ModLike<Fun = F>, F: FunLike> {
     Just(Entry<M>),
+    /// Branches on an index, taken as unsigned: the `i`th entry if it is `i`, the default if
+    /// it is out of bounds.
+    ///
+    /// With a single entry, this is a boolean test branching there on zero and to the default
+    /// otherwise. Without any, it branches to the default.
     Switch(ValIDFun<F>, Vec<Entry<M>>, Entry<M>),
+    /// Leaves the function for an entry of another one, returning whatever it returns.
+    ///
+    /// See [`TreeTerminator::tail_call`] for backends that only emulate this with a call.
+    TailCall(Entry<M>),
+    /// Returns the values from the function.
+    Return(Vec<ValIDFun<F>>),
+    /// Traps.
+    Trap,
+}
+impl<M: ModLike<Fun = F>, F: FunLike> Tree<M, F> {
+    /// Every entry the tree may branch to, default last. Tail calls, returns and traps leave
+    /// the function, so they branch nowhere.
+    pub fn entries(self) -> Vec<Entry<M>> {
+        match self {
+            Tree::Just(j) => vec![j],
+            Tree::Switch(_, go, default) => go.into_iter().chain(Some(default)).collect(),
+            Tree::TailCall(_) | Tree::Return(_) | Tree::Trap => vec![],
+        }
+    }
+    /// Like [`Tree::entries`], by reference.
+    pub fn targets(&self) -> Vec<&Entry<M>> {
+        match self {
+            Tree::Just(j) => vec![j],
+            Tree::Switch(_, go, default) => go.iter().chain(Some(default)).collect(),
+            Tree::TailCall(_) | Tree::Return(_) | Tree::Trap => vec![],
+        }
+    }
 }
 pub trait Reloop<M: ModLike<Fun = Self>, Err>: FunLike + Sized
 where
@@ -59,6 +113,9 @@ where
                         Self::collect(m, &j.fun, go)?;
                     }
                 }
+                Tree::TailCall(_) | Tree::Return(_) | Tree::Trap => {
+                    go.insert(*i, vec![]);
+                }
             }
         }else{
             go.insert(*i, vec![]);
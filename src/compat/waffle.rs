@@ -1,23 +1,45 @@
+use std::collections::BTreeMap;
+
 use either::Either::{Left, Right};
-use waffle::{BlockTarget, Import, Operator, Terminator, Type, ValueDef};
+use waffle::{
+    Block, BlockTarget, Export, ExportKind, Func, FunctionBody, MemoryArg, Operator, Terminator,
+    Type, Value, ValueDef,
+};
 
-use crate::utils::{waffle::vendor::op_outputs, R};
+use crate::{
+    error::Error,
+    utils::{
+        waffle::{pick_outputs, vendor::op_outputs},
+        R,
+    },
+};
 
-use self::base::{BlockRef, ExportData, FuncAndBlock, GetModule, Importd, MFCache};
+use self::{
+    base::{BlockRef, ExportData, ExportKey, FuncAndBlock, GetModule, Importd, MFCache},
+    indirect::Callee,
+};
 
 use super::{
-    call::Call,
+    call::{BindFun, Call, CallIndirect},
     stmt::{Statement, Stmt},
-    tree::{Entry, TreeTerminator, UnTreeTerminator},
+    tree::{Entry, MapTerminator, TreeTerminator, UnTreeTerminator},
     typed::{TypedFunLike, TypedValue},
-    FunLike, ModLike, ModLikeIter,
+    Exported, FunLike, ModLike, ModLikeExports, ModLikeIter, ParamFunLike,
 };
 
 pub mod base;
+pub mod indirect;
+pub mod link;
+pub mod memory;
+pub mod rules;
+pub mod switch;
 impl<M: GetModule> TypedValue<BlockRef<M>> for waffle::ValueDef {
     type Type = Vec<Type>;
 
     fn type_of(&self, f: &BlockRef<M>) -> Self::Type {
+        if let ValueDef::Alias(v) = self {
+            return f.func().unwrap().values[*v].type_of(f);
+        }
         return self.tys(&f.func().unwrap().type_pool).to_owned();
     }
 }
@@ -45,8 +67,14 @@ impl<M: GetModule> FunLike for BlockRef<M> {
 
     fn terminator_mut(&mut self) -> &mut Self::Terminator {
         let k = self.k.block;
+        self.changed();
         return &mut self.func_mut().unwrap().blocks[k].terminator;
     }
+
+    /// Shared by every block of the function, as values are.
+    fn version(&self) -> u64 {
+        self.cur().map_or(0, |m| m.version(self.k.func))
+    }
 }
 impl<M: GetModule> ModLike for MFCache<M> {
     type Fun = BlockRef<MFCache<M>>;
@@ -73,35 +101,25 @@ impl<M: GetModule> ModLike for MFCache<M> {
         self
     }
 }
-impl<M: GetModule, E: Default> Call<MFCache<M>, BlockRef<MFCache<M>>, Importd, E> for ValueDef {
+impl<M: GetModule, E: From<Error<FuncAndBlock>>> Call<MFCache<M>, BlockRef<MFCache<M>>, Importd, E> for ValueDef {
     fn call(
         n: &mut BlockRef<MFCache<M>>,
         f: either::Either<super::FunId<MFCache<M>>, Importd>,
         args: Vec<super::ValIDFun<BlockRef<MFCache<M>>>>,
     ) -> Result<Self, E> {
-        let v = n.func_mut().r()?.arg_pool.from_iter(args.into_iter());
+        let v = n.try_func_mut()?.arg_pool.from_iter(args.into_iter());
         let (new, sig) = match f {
             either::Either::Left(f) => {
-                let f = n.cur_mut().r()?[f].to_func().r()?;
-                (f, n.cur().r()?.module().funcs[f].sig())
+                let f = n.try_cur_mut()?[f].to_func().r_or(Error::MissingBody(f))?;
+                (f, n.try_cur()?.module().funcs[f].sig())
             }
             either::Either::Right(i) => {
-                let fun = n
-                    .cur_mut()
-                    .r()?
-                    .module_mut()
-                    .funcs
-                    .push(waffle::FuncDecl::Import(i.sig, "$".to_owned()));
-                n.cur_mut().r()?.module_mut().imports.push(Import {
-                    module: i.module,
-                    name: i.func,
-                    kind: waffle::ImportKind::Func(fun),
-                });
-                (fun, i.sig)
+                let sig = i.sig;
+                (i.declare(n.try_cur_mut()?.module_mut()), sig)
             }
         };
-        let sig = n.cur().r()?.module().signatures[sig].returns.clone();
-        let t = n.func_mut().r()?.type_pool.from_iter(sig.into_iter());
+        let sig = n.try_cur()?.module().signatures[sig].returns.clone();
+        let t = n.try_func_mut()?.type_pool.from_iter(sig.into_iter());
         return Ok(ValueDef::Operator(
             waffle::Operator::Call {
                 function_index: new,
@@ -111,13 +129,115 @@ impl<M: GetModule, E: Default> Call<MFCache<M>, BlockRef<MFCache<M>>, Importd, E
         ));
     }
 }
-impl<M: GetModule, E: Default> TreeTerminator<MFCache<M>, BlockRef<MFCache<M>>, E> for Terminator {
+/// An alias of the slot of `f`, added first in the block of `n` as a function value.
+fn fun_value<M: GetModule, E: From<Error<FuncAndBlock>>>(
+    n: &mut BlockRef<MFCache<M>>,
+    f: Func,
+) -> Result<ValueDef, E> {
+    let k = n.k;
+    let slot = n.try_cur_mut()?.table_slot(f);
+    let sig = n.try_cur()?.module().funcs[f].sig();
+    let t = n.try_func_mut()?.single_type_list(Type::I32);
+    let c = ValueDef::Operator(Operator::I32Const { value: slot }, Default::default(), t);
+    // Constant, so it may go before wherever the alias goes.
+    let v = match n.try_func()?.blocks[k.block].insts.first() {
+        Some(i) => n.add_just_before(c, *i),
+        None => n.add(c),
+    };
+    let v = v.r_or(Error::MissingBody(k))?;
+    n.try_cur_mut()?.set_callee(k, v, Callee { sig, env: false });
+    Ok(ValueDef::Alias(v))
+}
+/// Function values are table slots; see [`indirect`].
+impl<M: GetModule, E: From<Error<FuncAndBlock>>>
+    CallIndirect<MFCache<M>, BlockRef<MFCache<M>>, Importd, E> for ValueDef
+{
+    fn fun(n: &mut BlockRef<MFCache<M>>, f: FuncAndBlock) -> Result<Self, E> {
+        let f = n.try_cur_mut()?.entry_func(f).r_or(Error::MissingBody(f))?;
+        fun_value(n, f)
+    }
+
+    fn r#extern(n: &mut BlockRef<MFCache<M>>, x: Importd) -> Result<Self, E> {
+        let f = x.declare(n.try_cur_mut()?.module_mut());
+        fun_value(n, f)
+    }
+
+    fn call_indirect(
+        n: &mut BlockRef<MFCache<M>>,
+        v: Value,
+        r: Vec<Value>,
+    ) -> Result<Self, E> {
+        let k = n.k;
+        let c = n.try_cur()?.callee(k, v).r_or(Error::NotAFunction(k))?;
+        let table_index = n.try_cur_mut()?.funcref_table();
+        let mut args = vec![];
+        let slot = match c.env {
+            false => v,
+            true => {
+                args.push(v);
+                let memory = n.try_cur()?.closure_memory().r_or(Error::NoClosureMemory(k))?;
+                let a = n.try_func_mut()?.arg_pool.single(v);
+                let t = n.try_func_mut()?.single_type_list(Type::I32);
+                let memory = MemoryArg {
+                    align: 2,
+                    offset: 0,
+                    memory,
+                };
+                n.add(ValueDef::Operator(Operator::I32Load { memory }, a, t))
+                    .r_or(Error::MissingBody(k))?
+            }
+        };
+        args.extend(r);
+        args.push(slot);
+        let rets = n.try_cur()?.module().signatures[c.sig].returns.clone();
+        let f = n.try_func_mut()?;
+        let a = f.arg_pool.from_iter(args.into_iter());
+        let t = f.type_pool.from_iter(rets.into_iter());
+        Ok(ValueDef::Operator(
+            Operator::CallIndirect {
+                sig_index: c.sig,
+                table_index,
+            },
+            a,
+            t,
+        ))
+    }
+}
+/// Closures are environments called through trampolines; see [`indirect`].
+impl<M: GetModule, E: From<Error<FuncAndBlock>>>
+    BindFun<MFCache<M>, BlockRef<MFCache<M>>, Importd, E> for ValueDef
+{
+    fn bind(n: &mut BlockRef<MFCache<M>>, v: Value, all: Vec<Value>) -> Result<Self, E> {
+        let k = n.k;
+        let c = n.try_cur()?.callee(k, v).r_or(Error::NotAFunction(k))?;
+        let ctor = n
+            .try_cur_mut()?
+            .closure_ctor(c, all.len())
+            .r_or_else(|| Error::BadSignature(k).at(k, "binding"))?;
+        let f = n.try_func_mut()?;
+        let a = f.arg_pool.from_iter(Some(v).into_iter().chain(all));
+        let t = f.single_type_list(Type::I32);
+        Ok(ValueDef::Operator(
+            Operator::Call {
+                function_index: ctor,
+            },
+            a,
+            t,
+        ))
+    }
+
+    fn call_one(n: &mut BlockRef<MFCache<M>>, v: Value) -> Result<Self, E> {
+        CallIndirect::<MFCache<M>, _, Importd, E>::call_indirect(n, v, vec![])
+    }
+}
+impl<M: GetModule, E: From<Error<FuncAndBlock>>> TreeTerminator<MFCache<M>, BlockRef<MFCache<M>>, E> for Terminator {
     fn just(n: &mut BlockRef<MFCache<M>>, x: super::tree::Entry<MFCache<M>>) -> Result<Self, E> {
-        let f = n.k.func;
+        let k = n.k;
+        let missing = Error::MissingBlock(x.fun).at(k, "branching");
         return Ok(Terminator::Br {
             target: BlockTarget {
                 args: x.args,
-                block: n.cur_mut().r()?[x.fun].block_in_func(f).r()?,
+                block: n.try_cur_mut()?[x.fun].block_in_func(k.func).r_or(missing)?,
             },
         });
     }
@@ -128,18 +248,27 @@ impl<M: GetModule, E: Default> TreeTerminator<MFCache<M>, BlockRef<MFCache<M>>,
         mut go: Vec<super::tree::Entry<MFCache<M>>>,
         default: super::tree::Entry<MFCache<M>>,
     ) -> Result<Self, E> {
-        let f = n.k.func;
+        let k = n.k;
+        let missing = |t| Error::MissingBlock(t).at(k, "switching");
         let default = BlockTarget {
             args: default.args,
-            block: n.cur_mut().r()?[default.fun].block_in_func(f).r()?,
+            block: n.try_cur_mut()?[default.fun]
+                .block_in_func(k.func)
+                .r_or(missing(default.fun))?,
         };
         let mut params = vec![];
         for g in go.drain(..) {
             params.push(BlockTarget {
                 args: g.args,
-                block: n.cur_mut().r()?[g.fun].block_in_func(f).r()?,
+                block: n.try_cur_mut()?[g.fun]
+                    .block_in_func(k.func)
+                    .r_or(missing(g.fun))?,
             })
         }
+        if params.is_empty() {
+            return Ok(Terminator::Br { target: default });
+        }
+        // A zero index picks the only target, so it is taken when the condition is false.
         if params.len() == 1 {
             return Ok(Terminator::CondBr {
                 cond: v,
@@ -153,8 +282,105 @@ impl<M: GetModule, E: Default> TreeTerminator<MFCache<M>, BlockRef<MFCache<M>>,
             default: default,
         });
     }
+
+    fn r#return(_: &mut BlockRef<MFCache<M>>, values: Vec<Value>) -> Result<Self, E> {
+        Ok(Terminator::Return { values })
+    }
+
+    fn trap(_: &mut BlockRef<MFCache<M>>) -> Result<Self, E> {
+        Ok(Terminator::Unreachable)
+    }
+
+    /// waffle has no `return_call`, so this calls the function `x` enters and returns its
+    /// results, using a frame a real tail call would not. A call to it the block already ends
+    /// with, such as one [`UnTreeTerminator::get_tree`] took for this tail call, is reused.
+    /// Only calls emitted here read back as [`Tree::TailCall`](super::tree::Tree::TailCall);
+    /// other calls followed by a return stay calls.
+    fn tail_call(n: &mut BlockRef<MFCache<M>>, x: Entry<MFCache<M>>) -> Result<Self, E> {
+        let k = n.k;
+        let f = n
+            .try_cur_mut()?
+            .entry_func(x.fun)
+            .r_or_else(|| Error::MissingBody(x.fun).at(k, "tail calling"))?;
+        let m = n.try_cur()?.module();
+        let rets = m.signatures[m.funcs[f].sig()].returns.clone();
+        if rets != n.try_func()?.rets {
+            return Err(Error::BadSignature(k).at(k, "tail calling").into());
+        }
+        n.flush_insts().r_or(Error::MissingBody(k))?;
+        let b = n.try_func()?;
+        if let Some((c, values)) = trailing_call(b, k.block) {
+            if let ValueDef::Operator(Operator::Call { function_index }, a, _) = b.values[c] {
+                if function_index == f && b.arg_pool[a] == x.args[..] {
+                    n.try_cur_mut()?.tail_calls.insert((k.func, c));
+                    return Ok(Terminator::Return { values });
+                }
+            }
+        }
+        let b = n.try_func_mut()?;
+        let a = b.arg_pool.from_iter(x.args.into_iter());
+        let t = b.type_pool.from_iter(rets.iter().cloned());
+        let o = Operator::Call { function_index: f };
+        let v = n
+            .add(ValueDef::Operator(o, a, t))
+            .r_or(Error::MissingBody(k))?;
+        n.try_cur_mut()?.tail_calls.insert((k.func, v));
+        let values = pick_outputs(n.try_func_mut()?, k.block, v, &rets);
+        Ok(Terminator::Return { values })
+    }
+}
+/// The call `b` ends with, if only outputs of it follow, and all of those outputs.
+fn trailing_call(f: &FunctionBody, b: Block) -> Option<(Value, Vec<Value>)> {
+    let mut picks = BTreeMap::new();
+    for v in f.blocks[b].insts.iter().rev() {
+        match f.values[*v] {
+            ValueDef::PickOutput(c, i, _) => {
+                picks.insert(i, (c, *v));
+            }
+            ValueDef::Operator(Operator::Call { .. }, _, tys) => {
+                if picks.values().any(|&(c, _)| c != *v) {
+                    return None;
+                }
+                let outs = match f.type_pool[tys].len() {
+                    1 => vec![*v],
+                    n => (0..n as u32)
+                        .map(|i| picks.get(&i).map(|p| p.1))
+                        .collect::<Option<_>>()?,
+                };
+                return Some((*v, outs));
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+/// The function entered by the call `n` ends with, if [`TreeTerminator::tail_call`] emitted it
+/// and the block returns exactly its results `values`.
+fn tail_call_entry<M: GetModule>(
+    n: &BlockRef<MFCache<M>>,
+    values: &[Value],
+) -> Option<Entry<MFCache<M>>> {
+    let f = n.func()?;
+    let (c, outs) = trailing_call(f, n.k.block)?;
+    if !n.cur()?.tail_calls.contains(&(n.k.func, c)) {
+        return None;
+    }
+    let ValueDef::Operator(Operator::Call { function_index }, a, _) = f.values[c] else {
+        return None;
+    };
+    if outs != values {
+        return None;
+    }
+    let g = n.cur()?.module().funcs[function_index].body()?;
+    Some(Entry {
+        fun: FuncAndBlock {
+            func: function_index,
+            block: g.entry,
+        },
+        args: f.arg_pool[a].to_vec(),
+    })
 }
-impl<M: GetModule, E: Default> UnTreeTerminator<MFCache<M>, BlockRef<MFCache<M>>, E>
+impl<M: GetModule, E: From<Error<FuncAndBlock>>> UnTreeTerminator<MFCache<M>, BlockRef<MFCache<M>>, E>
     for Terminator
 {
     fn get_tree(
@@ -217,12 +443,59 @@ impl<M: GetModule, E: Default> UnTreeTerminator<MFCache<M>, BlockRef<MFCache<M>>
                     },
                 )));
             }
-            Terminator::Return { values } => Ok(None),
-            Terminator::Unreachable => Ok(None),
+            Terminator::Return { values } => {
+                let f = n.try_func()?;
+                let values: Vec<_> = values.iter().map(|v| f.resolve_alias(*v)).collect();
+                if let Some(e) = tail_call_entry(n, &values) {
+                    return Ok(Some(super::tree::Tree::TailCall(e)));
+                }
+                Ok(Some(super::tree::Tree::Return(values)))
+            }
+            Terminator::Unreachable => Ok(Some(super::tree::Tree::Trap)),
             Terminator::None => Ok(None),
         }
     }
 }
+impl<M: GetModule> MapTerminator<BlockRef<M>> for Terminator {
+    fn map_values<Err>(
+        mut self,
+        f: &mut dyn FnMut(Value) -> Result<Value, Err>,
+    ) -> Result<Self, Err> {
+        let targets: Vec<&mut BlockTarget> = match &mut self {
+            Terminator::Br { target } => vec![target],
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } => {
+                *cond = f(*cond)?;
+                vec![if_true, if_false]
+            }
+            Terminator::Select {
+                value,
+                targets,
+                default,
+            } => {
+                *value = f(*value)?;
+                targets.iter_mut().chain(Some(default)).collect()
+            }
+            Terminator::Return { values } => {
+                for v in values.iter_mut() {
+                    *v = f(*v)?;
+                }
+                vec![]
+            }
+            Terminator::Unreachable | Terminator::None => vec![],
+        };
+        for t in targets {
+            for v in t.args.iter_mut() {
+                *v = f(*v)?;
+            }
+        }
+        Ok(self)
+    }
+}
+/// waffle has no exception handling, so there are no `try` or `throw` constructs to carry.
 impl<M: GetModule> Statement<MFCache<M>> for ValueDef {
     type Stmt = Operator;
 
@@ -246,32 +519,37 @@ impl<M: GetModule> Statement<MFCache<M>> for ValueDef {
     fn from_statement(
         s: &super::stmt::Stmt<Self, MFCache<M>>,
         f: &mut <MFCache<M> as ModLike>::Fun,
-    ) -> Self {
+    ) -> Result<Self, Error<FuncAndBlock>> {
+        let k = f.k;
         match s {
             Stmt::Basic(s, v) => {
+                // waffle operands are single values; results of calls are picked first.
                 let mut sk = vec![];
+                let b = f.try_func()?;
                 for w in v {
-                    sk.push((f.all()[*w].ty(&f.func().unwrap().type_pool).unwrap(), *w));
+                    match b.values[b.resolve_alias(*w)].tys(&b.type_pool) {
+                        [t] => sk.push((*t, *w)),
+                        _ => return Err(Error::Missing(k, format!("a single result of {w}"))),
+                    }
                 }
-                let t = op_outputs(f.cur().unwrap().module(), &sk, s).unwrap();
-                let t = f
-                    .func_mut()
-                    .unwrap()
-                    .type_pool
-                    .from_iter(t.iter().map(|a| *a));
-                ValueDef::Operator(
-                    s.clone(),
-                    f.func_mut()
-                        .unwrap()
-                        .arg_pool
-                        .from_iter(v.iter().map(|a| *a)),
-                    t,
-                )
+                let m = f.try_cur()?.module();
+                let t = op_outputs(m, &sk, s)
+                    .map_err(|_| Error::Missing(k, format!("valid operands for {s}")))?;
+                let b = f.try_func_mut()?;
+                let t = b.type_pool.from_iter(t.iter().copied());
+                let a = b.arg_pool.from_iter(v.iter().copied());
+                Ok(ValueDef::Operator(s.clone(), a, t))
+            }
+            Stmt::Param(p) => {
+                let ps = f.params().r_or(Error::MissingBody(k))?;
+                let q = ps.get(*p).r_or_else(|| Error::Missing(k, format!("parameter {p}")))?;
+                Ok(ValueDef::Alias(q.1))
             }
-            Stmt::Param(p) => ValueDef::Alias(f.params().unwrap()[*p].1),
             Stmt::Pick(i, u) => {
-                let t = f.all()[*i].tys(&f.func().unwrap().type_pool);
-                return ValueDef::PickOutput(*i, *u as u32, t[*u]);
+                let b = f.try_func()?;
+                let t = b.values[b.resolve_alias(*i)].tys(&b.type_pool);
+                let t = t.get(*u).r_or_else(|| Error::Missing(k, format!("output {u} of {i}")))?;
+                Ok(ValueDef::PickOutput(*i, *u as u32, *t))
             }
         }
     }
@@ -292,4 +570,87 @@ impl<M: GetModule> ModLikeIter for MFCache<M> {
             })
             .collect();
     }
+    fn data_keys(&self) -> Vec<super::DatId<Self>> {
+        let m = self.module();
+        let mut k: Vec<_> = m.tables.iter().map(ExportKey::Table).collect();
+        k.extend(m.globals.iter().map(ExportKey::Global));
+        k.extend(m.memories.iter().map(ExportKey::Memory));
+        k
+    }
+}
+impl<M: GetModule> ModLikeExports for MFCache<M> {
+    fn exports(&self) -> Vec<(Option<String>, Exported<Self>)> {
+        let m = self.module();
+        let entry = |f: Func| {
+            m.funcs[f].body().map(|b| FuncAndBlock {
+                func: f,
+                block: b.entry,
+            })
+        };
+        let mut e: Vec<_> = m
+            .exports
+            .iter()
+            .filter_map(|e| {
+                let x = match e.kind {
+                    ExportKind::Func(f) => Left(entry(f)?),
+                    ExportKind::Table(t) => Right(ExportKey::Table(t)),
+                    ExportKind::Global(g) => Right(ExportKey::Global(g)),
+                    ExportKind::Memory(a) => Right(ExportKey::Memory(a)),
+                };
+                Some((Some(e.name.clone()), x))
+            })
+            .collect();
+        e.extend(m.start_func.and_then(entry).map(|k| (None, Left(k))));
+        e
+    }
+
+    fn set_export(&mut self, name: Option<&str>, x: Exported<Self>) {
+        let kind = match x {
+            Left(k) => {
+                let entry = self.module().funcs[k.func].body().map(|b| b.entry);
+                let f = if entry == Some(k.block) {
+                    k.func
+                } else {
+                    self[k].to_func().unwrap()
+                };
+                ExportKind::Func(f)
+            }
+            Right(ExportKey::Table(t)) => ExportKind::Table(t),
+            Right(ExportKey::Global(g)) => ExportKind::Global(g),
+            Right(ExportKey::Memory(a)) => ExportKind::Memory(a),
+        };
+        let Some(name) = name else {
+            if let ExportKind::Func(f) = kind {
+                self.module_mut().start_func = Some(f);
+            }
+            return;
+        };
+        let m = self.module_mut();
+        match m.exports.iter_mut().find(|e| e.name == name) {
+            Some(e) => e.kind = kind,
+            None => m.exports.push(Export {
+                name: name.to_owned(),
+                kind,
+            }),
+        }
+    }
+}
+impl<M: GetModule> ParamFunLike for BlockRef<M> {
+    type Param = Type;
+
+    fn params(&self) -> Vec<(Type, Value)> {
+        BlockRef::params(self).unwrap_or_default()
+    }
+
+    fn add_param(&mut self, p: Type) -> Value {
+        BlockRef::add_param(self, p).unwrap()
+    }
+
+    fn returns(&self) -> Vec<Type> {
+        self.func().map(|f| f.rets.clone()).unwrap_or_default()
+    }
+
+    fn set_returns(&mut self, r: Vec<Type>) {
+        BlockRef::set_returns(self, r).unwrap();
+    }
 }
@@ -1,15 +1,21 @@
 use std::{
     cell::UnsafeCell,
-    collections::BTreeMap,
-    ops::{Deref, DerefMut, Index, IndexMut}, marker::PhantomPinned, pin::Pin,
+    collections::{BTreeMap, BTreeSet},
+    ops::{Index, IndexMut}, marker::PhantomPinned, pin::Pin,
 };
 
 use waffle::{
     Block, BlockTarget, Export, ExportKind, Func, FuncDecl, FunctionBody, Global, GlobalData,
-    Memory, MemoryData, Signature, SignatureData, Table, TableData, Type, Value, ValueDef,
+    Memory, MemoryData, Operator, Signature, SignatureData, Table, TableData, Type, Value,
+    ValueDef,
 };
 
-use crate::{compat::{ArenaLike, OrderedArenaLike}, utils::waffle::clone_fn};
+use super::indirect::Closures;
+use crate::{
+    compat::{ArenaLike, ArenaLikeIter, MutableArenaLike, OrderedArenaLike},
+    error::Error,
+    utils::waffle::{clone_fn, tweak_terminator, tweak_value},
+};
 #[derive(Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Default, Debug, Hash)]
 pub struct FuncAndBlock {
     pub func: waffle::Func,
@@ -20,7 +26,7 @@ pub struct FuncRef<M> {
     cur: *mut M,
     pub func: Func,
 }
-impl<M> FuncRef<M> {
+impl<M: GetModule> FuncRef<M> {
     pub fn r#in(self, b: Block) -> BlockRef<M> {
         return BlockRef {
             cur: self.cur,
@@ -28,22 +34,76 @@ impl<M> FuncRef<M> {
                 func: self.func,
                 block: b,
             },
+            pending: Default::default(),
         };
     }
 }
-pub struct BlockRef<M> {
+/// Instruction insertions recorded against a block but not yet spliced into its `insts`.
+///
+/// Anchored insertions are applied in a single pass by [`BlockRef::flush_insts`], so inserting
+/// many values costs O(1) each plus one O(n) merge, instead of rebuilding `insts` every time.
+#[derive(Default, Clone)]
+pub struct PendingInsts {
+    after: BTreeMap<Value, Vec<Value>>,
+    before: BTreeMap<Value, Vec<Value>>,
+}
+impl PendingInsts {
+    pub fn is_empty(&self) -> bool {
+        self.after.is_empty() && self.before.is_empty()
+    }
+    fn emit(&mut self, v: Value, out: &mut Vec<Value>) {
+        // Later insertions before an anchor sit closer to it; later insertions after an anchor
+        // also sit closer to it, hence the reversal.
+        for b in self.before.remove(&v).unwrap_or_default() {
+            self.emit(b, out);
+        }
+        out.push(v);
+        for a in self.after.remove(&v).unwrap_or_default().into_iter().rev() {
+            self.emit(a, out);
+        }
+    }
+    /// Splices the insertions into `old`.
+    fn apply(mut self, old: Vec<Value>) -> Vec<Value> {
+        let mut new = Vec::with_capacity(old.len());
+        for j in old {
+            self.emit(j, &mut new);
+        }
+        // Values anchored on something outside of the block are kept at its end.
+        while let Some((_, vs)) = self.before.pop_first() {
+            for v in vs {
+                self.emit(v, &mut new);
+            }
+        }
+        while let Some((_, vs)) = self.after.pop_first() {
+            for v in vs.into_iter().rev() {
+                self.emit(v, &mut new);
+            }
+        }
+        new
+    }
+}
+pub struct BlockRef<M: GetModule> {
     cur: *mut M,
     pub k: FuncAndBlock,
+    pending: PendingInsts,
 }
-impl<M> Default for BlockRef<M> {
+impl<M: GetModule> Default for BlockRef<M> {
     fn default() -> Self {
         Self {
             cur: std::ptr::null_mut(),
             k: Default::default(),
+            pending: Default::default(),
         }
     }
 }
-impl<M> BlockRef<M> {
+/// Handles outside of an [`MFCache`], such as those from [`FuncRef::in`] or
+/// [`BlockRef::in_func`], are never flushed by it, so they flush their own insertions.
+impl<M: GetModule> Drop for BlockRef<M> {
+    fn drop(&mut self) {
+        self.flush_insts();
+    }
+}
+impl<M: GetModule> BlockRef<M> {
     pub fn into_func_ref(self) -> (FuncRef<M>, Block) {
         return (
             FuncRef {
@@ -57,6 +117,12 @@ impl<M> BlockRef<M> {
 pub trait GetModule {
     fn module(&self) -> &waffle::Module<'static>;
     fn module_mut(&mut self) -> &mut waffle::Module<'static>;
+    /// Changes counted for `f`, if this counts them; see [`MFCache`].
+    fn version(&self, _: Func) -> u64 {
+        0
+    }
+    /// Counts a change to `f` made through a [`BlockRef`].
+    fn changed(&mut self, _: Func) {}
 }
 impl GetModule for waffle::Module<'static>{
     fn module(&self) -> &waffle::Module<'static> {
@@ -76,6 +142,7 @@ impl<M: GetModule> Index<waffle::Value> for BlockRef<M> {
 }
 impl<M: GetModule> IndexMut<waffle::Value> for BlockRef<M> {
     fn index_mut(&mut self, index: waffle::Value) -> &mut Self::Output {
+        self.changed();
         return &mut self.func_mut().unwrap().values[index];
     }
 }
@@ -95,6 +162,80 @@ impl<M: GetModule> OrderedArenaLike<waffle::ValueDef> for BlockRef<M>{
         return self.add_just_before(a, before).unwrap();
     }
 }
+impl<M: GetModule> ArenaLikeIter<waffle::ValueDef> for BlockRef<M> {
+    /// The block's parameters followed by its instructions, including deferred insertions.
+    fn keys(&self) -> Vec<Self::Id> {
+        let Some(f) = self.func() else {
+            return vec![];
+        };
+        let b = &f.blocks[self.k.block];
+        let insts = match self.pending.is_empty() {
+            true => b.insts.clone(),
+            false => self.pending.clone().apply(b.insts.clone()),
+        };
+        b.params.iter().map(|a| a.1).chain(insts).collect()
+    }
+}
+impl<M: GetModule> MutableArenaLike<waffle::ValueDef> for BlockRef<M> {
+    /// Leaves an `unreachable` without results in place of `id`, so that a dangling use still
+    /// reads as a statement, and fails [`Statement::from_statement`] as an operand.
+    ///
+    /// # Panics
+    ///
+    /// If `id` is not an instruction of this block, such as a block parameter.
+    ///
+    /// [`Statement::from_statement`]: crate::compat::stmt::Statement::from_statement
+    fn remove(&mut self, id: Self::Id) -> waffle::ValueDef {
+        let l = self.k.block;
+        self.flush_insts().unwrap();
+        let f = self.func_mut().unwrap();
+        let insts = &mut f.blocks[l].insts;
+        let i = insts.iter().position(|v| *v == id);
+        let i = i.unwrap_or_else(|| panic!("{id} is not an instruction of {l}"));
+        insts.remove(i);
+        self.changed();
+        let f = self.func_mut().unwrap();
+        let (args, tys) = (Default::default(), Default::default());
+        let gone = ValueDef::Operator(Operator::Unreachable, args, tys);
+        std::mem::replace(&mut f.values[id], gone)
+    }
+
+    fn replace_all_uses_with(&mut self, old: Self::Id, new: Self::Id) {
+        let users = self.uses(old);
+        self.changed();
+        let f = self.func_mut().unwrap();
+        let swap = |v: &mut Value| {
+            if *v == old {
+                *v = new;
+            }
+        };
+        for u in users {
+            let mut d = f.values[u].clone();
+            let b = f.value_blocks[u];
+            tweak_value(f, &mut d, swap, b);
+            f.values[u] = d;
+        }
+        let blocks: Vec<Block> = f.blocks.entries().map(|a| a.0).collect();
+        for b in blocks {
+            let mut t = f.blocks[b].terminator.clone();
+            tweak_terminator(f, &mut t, swap, |_| {});
+            f.blocks[b].terminator = t;
+        }
+    }
+
+    fn uses(&self, id: Self::Id) -> Vec<Self::Id> {
+        let f = self.func().unwrap();
+        f.values
+            .entries()
+            .filter(|(_, d)| match d {
+                ValueDef::Operator(_, l, _) | ValueDef::Trace(_, l) => f.arg_pool[*l].contains(&id),
+                ValueDef::PickOutput(v, _, _) | ValueDef::Alias(v) => *v == id,
+                _ => false,
+            })
+            .map(|a| a.0)
+            .collect()
+    }
+}
 impl<M: GetModule> BlockRef<M> {
     pub fn cur(&self) -> Option<&M> {
         if self.cur.is_null() {
@@ -115,37 +256,80 @@ impl<M: GetModule> BlockRef<M> {
         let f = self.k.func;
         return self.cur_mut()?.module_mut().funcs[f].body_mut();
     }
+    /// Counts a change to the function, for [`FunLike::version`].
+    ///
+    /// [`FunLike::version`]: crate::compat::FunLike::version
+    pub(super) fn changed(&mut self) {
+        let f = self.k.func;
+        if let Some(m) = self.cur_mut() {
+            m.changed(f);
+        }
+    }
+    /// Like [`BlockRef::cur`], saying why it failed.
+    pub fn try_cur(&self) -> Result<&M, Error<FuncAndBlock>> {
+        let k = self.k;
+        self.cur().ok_or(Error::NullHandle(k))
+    }
+    pub fn try_cur_mut(&mut self) -> Result<&mut M, Error<FuncAndBlock>> {
+        let k = self.k;
+        self.cur_mut().ok_or(Error::NullHandle(k))
+    }
+    /// Like [`BlockRef::func`], saying why it failed.
+    pub fn try_func(&self) -> Result<&FunctionBody, Error<FuncAndBlock>> {
+        let k = self.k;
+        self.try_cur()?.module().funcs[k.func]
+            .body()
+            .ok_or(Error::MissingBody(k))
+    }
+    pub fn try_func_mut(&mut self) -> Result<&mut FunctionBody, Error<FuncAndBlock>> {
+        let k = self.k;
+        self.try_cur_mut()?.module_mut().funcs[k.func]
+            .body_mut()
+            .ok_or(Error::MissingBody(k))
+    }
     pub fn add(&mut self, a: ValueDef) -> Option<Value>{
         return self.add_after(a, None);
     }
+    /// Adds `a` directly after `after`, or at the end of the block if `after` is `None`.
+    ///
+    /// Anchored insertions are deferred until [`BlockRef::flush_insts`].
     pub fn add_after(&mut self, a: ValueDef, after: Option<Value>) -> Option<Value>{
-        let v = self.func_mut()?.add_value(a);
         let l = self.k.block;
-        let mut i = std::mem::take(&mut self.func_mut()?.blocks[l].insts);
-        for j in i{
-            self.func_mut()?.blocks[l].insts.push(j);
-            if let Some(after) = after{
-            if j == after{
-                self.func_mut()?.append_to_block(l, v);
+        let v = self.func_mut()?.add_value(a);
+        self.changed();
+        match after {
+            None => self.func_mut()?.append_to_block(l, v),
+            Some(after) => {
+                self.func_mut()?.value_blocks[v] = l;
+                self.pending.after.entry(after).or_default().push(v);
             }
         }
-        }
-        if let None = after{
-            self.func_mut()?.append_to_block(l, v);
-        }
         return Some(v);
     }
+    /// Adds `a` directly before `before`.
+    ///
+    /// The insertion is deferred until [`BlockRef::flush_insts`].
     pub fn add_just_before(&mut self, a: ValueDef, before: Value) -> Option<Value>{
-        let v = self.func_mut()?.add_value(a);
         let l = self.k.block;
-        let mut i = std::mem::take(&mut self.func_mut()?.blocks[l].insts);
-        for j in i{
-            if j == before{
-                self.func_mut()?.append_to_block(l, v);
-            }
-            self.func_mut()?.blocks[l].insts.push(j);
+        let v = self.func_mut()?.add_value(a);
+        self.changed();
+        self.func_mut()?.value_blocks[v] = l;
+        self.pending.before.entry(before).or_default().push(v);
+        Some(v)
+    }
+    /// Splices all deferred insertions into the block's `insts`.
+    ///
+    /// Must be called (directly, through [`MFCache::flush`] or by dropping the handle) before
+    /// reading `insts` from the underlying [`FunctionBody`].
+    pub fn flush_insts(&mut self) -> Option<()> {
+        if self.pending.is_empty() {
+            return Some(());
         }
-        return Some(v);
+        let l = self.k.block;
+        let old = std::mem::take(&mut self.func_mut()?.blocks[l].insts);
+        let new = std::mem::take(&mut self.pending).apply(old);
+        self.func_mut()?.blocks[l].insts = new;
+        Some(())
     }
     pub fn params(&self) -> Option<Vec<(Type, Value)>> {
         let l = self.k.block;
@@ -155,7 +339,26 @@ impl<M: GetModule> BlockRef<M> {
         let l = self.k.block;
         return Some(self.func_mut()?.add_blockparam(l, t));
     }
-    pub fn in_func(self, target: Func) -> Option<BlockRef<M>> {
+    /// Gives the function a signature of its own, taking the parameters of its entry block and
+    /// returning `r`.
+    pub fn set_returns(&mut self, r: Vec<Type>) -> Option<()> {
+        let f = self.k.func;
+        let b = self.func()?;
+        let params = b.blocks[b.entry].params.iter().map(|p| p.0).collect();
+        let m = self.cur_mut()?.module_mut();
+        let s = m.signatures.push(SignatureData {
+            params,
+            returns: r.clone(),
+        });
+        let FuncDecl::Body(sig, _, body) = &mut m.funcs[f] else {
+            return None;
+        };
+        *sig = s;
+        body.rets = r;
+        Some(())
+    }
+    pub fn in_func(mut self, target: Func) -> Option<BlockRef<M>> {
+        self.flush_insts()?;
         if self.k.func == target {
             return Some(self);
         }
@@ -171,9 +374,11 @@ impl<M: GetModule> BlockRef<M> {
                 func: target,
                 block: *lr.all.get(&self.k.block)?,
             },
+            pending: Default::default(),
         });
     }
     pub fn block_in_func(&mut self, target: Func) -> Option<Block> {
+        self.flush_insts()?;
         if self.k.func == target {
             return Some(self.k.block);
         }
@@ -220,8 +425,16 @@ pub enum ExportKey {
 
 pub struct MFCache<M: GetModule> {
     ptr: Option<M>,
-    cache: UnsafeCell<BTreeMap<FuncAndBlock, BlockRef<MFCache<M>>>>,
+    /// Boxed, so handles stay put while others are added.
+    cache: UnsafeCell<BTreeMap<FuncAndBlock, Box<BlockRef<MFCache<M>>>>>,
     data_cache: UnsafeCell<BTreeMap<ExportKey, ExportData>>,
+    pub(super) closures: Closures,
+    /// Calls emitted for [`TreeTerminator::tail_call`], which read back as tail calls.
+    ///
+    /// [`TreeTerminator::tail_call`]: crate::compat::tree::TreeTerminator::tail_call
+    pub(super) tail_calls: BTreeSet<(Func, Value)>,
+    /// Changes made to each function through its handles.
+    versions: BTreeMap<Func, u64>,
     _pinned: PhantomPinned,
 }
 impl<M: GetModule> Drop for MFCache<M> {
@@ -249,10 +462,16 @@ impl<M: GetModule> MFCache<M> {
             ptr: Some(m),
             cache: UnsafeCell::new(BTreeMap::new()),
             data_cache: UnsafeCell::new(BTreeMap::new()),
+            closures: Closures::default(),
+            tail_calls: BTreeSet::new(),
+            versions: BTreeMap::new(),
             _pinned: PhantomPinned,
         });
     }
     pub fn flush(&mut self) {
+        for b in self.cache.get_mut().values_mut() {
+            b.flush_insts();
+        }
         for (k, v) in std::mem::take(self.data_cache.get_mut()) {
             match (k, v) {
                 (ExportKey::Table(t), ExportData::Table(d)) => self.module_mut().tables[t] = d,
@@ -262,6 +481,13 @@ impl<M: GetModule> MFCache<M> {
             }
         }
     }
+    /// The function `f` is the entry block of, or a new function starting at it.
+    pub fn entry_func(&mut self, f: FuncAndBlock) -> Option<Func> {
+        match self.module().funcs[f.func].body()?.entry == f.block {
+            true => Some(f.func),
+            false => self[f].to_func(),
+        }
+    }
     pub fn alloc_block(&mut self, sig: SignatureData) -> FuncAndBlock {
         let s = self.module_mut().signatures.push(sig);
         let f = FunctionBody::new(self.module(), s);
@@ -283,6 +509,14 @@ impl<M: GetModule> GetModule for MFCache<M> {
     fn module_mut(&mut self) -> &mut waffle::Module<'static> {
         return self.ptr.as_mut().unwrap().module_mut();
     }
+
+    fn version(&self, f: Func) -> u64 {
+        self.versions.get(&f).copied().unwrap_or_default()
+    }
+
+    fn changed(&mut self, f: Func) {
+        *self.versions.entry(f).or_default() += 1;
+    }
 }
 impl<M: GetModule> Index<FuncAndBlock> for MFCache<M> {
     type Output = BlockRef<MFCache<M>>;
@@ -290,9 +524,12 @@ impl<M: GetModule> Index<FuncAndBlock> for MFCache<M> {
     fn index(&self, index: FuncAndBlock) -> &Self::Output {
         return unsafe { &mut *self.cache.get() }
             .entry(index)
-            .or_insert_with(|| BlockRef {
-                cur: self as *const MFCache<M> as *mut MFCache<M>,
-                k: index,
+            .or_insert_with(|| {
+                Box::new(BlockRef {
+                    cur: self as *const MFCache<M> as *mut MFCache<M>,
+                    k: index,
+                    pending: Default::default(),
+                })
             });
     }
 }
@@ -300,9 +537,12 @@ impl<M: GetModule> IndexMut<FuncAndBlock> for MFCache<M> {
     fn index_mut(&mut self, index: FuncAndBlock) -> &mut Self::Output {
         return unsafe { &mut *self.cache.get() }
             .entry(index)
-            .or_insert_with(|| BlockRef {
-                cur: self as *const MFCache<M> as *mut MFCache<M>,
-                k: index,
+            .or_insert_with(|| {
+                Box::new(BlockRef {
+                    cur: self as *const MFCache<M> as *mut MFCache<M>,
+                    k: index,
+                    pending: Default::default(),
+                })
             });
     }
 }
@@ -351,3 +591,17 @@ pub struct Importd {
     pub func: String,
     pub sig: Signature,
 }
+impl Importd {
+    /// Adds the import to `m`, returning its function.
+    pub fn declare(self, m: &mut waffle::Module<'static>) -> Func {
+        let fun = m
+            .funcs
+            .push(waffle::FuncDecl::Import(self.sig, "$".to_owned()));
+        m.imports.push(waffle::Import {
+            module: self.module,
+            name: self.func,
+            kind: waffle::ImportKind::Func(fun),
+        });
+        fun
+    }
+}
@@ -0,0 +1,411 @@
+//! Function values, for [`CallIndirect`] and [`BindFun`].
+//!
+//! waffle has neither `ref.func` nor `call_ref`, so a function value is the `i32` slot of the
+//! function in a `funcref` table, called through `call_indirect`. A closure is the address of an
+//! environment in linear memory holding, in order, the slot of a trampoline, the callee it binds
+//! and the arguments it captured. Calling a closure calls its trampoline with the environment
+//! first; the trampoline loads the rest back and calls the callee.
+//!
+//! A value is only called as a function if it is known to be one: it was made by
+//! [`CallIndirect::fun`], [`CallIndirect::extern`] or [`BindFun::bind`], given a type through
+//! [`MFCache::set_callee`], or is a parameter every argument of which agrees on one.
+//!
+//! Environments are bump allocated from pages the first memory grows by, and never freed. The
+//! memory must be defined by the module, not imported.
+//!
+//! [`CallIndirect`]: crate::compat::call::CallIndirect
+//! [`CallIndirect::fun`]: crate::compat::call::CallIndirect::fun
+//! [`CallIndirect::extern`]: crate::compat::call::CallIndirect::extern
+//! [`BindFun`]: crate::compat::call::BindFun
+//! [`BindFun::bind`]: crate::compat::call::BindFun::bind
+use std::collections::{BTreeMap, BTreeSet};
+
+use waffle::{
+    Block, BlockTarget, ExportKind, Func, FuncDecl, FunctionBody, Global, GlobalData, ImportKind,
+    Memory, MemoryArg, MemoryData, Module, Operator, Signature, SignatureData, Table, TableData,
+    Terminator, Type, Value, ValueDef,
+};
+
+use crate::utils::waffle::pick_outputs;
+
+use super::base::{FuncAndBlock, GetModule, MFCache};
+
+/// How to call a function value.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub struct Callee {
+    /// Signature for `call_indirect`.
+    pub sig: Signature,
+    /// Whether the value is a closure environment, passed first and holding the slot to call.
+    pub env: bool,
+}
+
+/// Closures built through an [`MFCache`].
+#[derive(Default)]
+pub struct Closures {
+    /// The memory environments live in, and the globals holding the next free address in it and
+    /// the end of the space allocated so far.
+    heap: Option<(Memory, Global, Global)>,
+    /// Function values, by function and value.
+    values: BTreeMap<(Func, Value), Callee>,
+    /// Constructors by callee and number of arguments bound.
+    ctors: BTreeMap<(Callee, usize), Func>,
+    /// Signatures of the trampolines, by constructor.
+    trampolines: BTreeMap<Func, Signature>,
+}
+
+/// The signature of `m` taking `params` and returning `returns`, added if there is none.
+pub fn intern_signature(m: &mut Module, params: Vec<Type>, returns: Vec<Type>) -> Signature {
+    let d = SignatureData { params, returns };
+    let s = m.signatures.entries().find(|a| *a.1 == d).map(|a| a.0);
+    match s {
+        Some(s) => s,
+        None => m.signatures.push(d),
+    }
+}
+
+/// Offsets of captured arguments typed `tys`, after the two slots, and the environment's size.
+fn layout(tys: &[Type]) -> Option<(Vec<u32>, u32)> {
+    let mut off: u32 = 8;
+    let mut all = vec![];
+    for t in tys {
+        let s = size(*t)?;
+        off = off.next_multiple_of(s);
+        all.push(off);
+        off += s;
+    }
+    Some((all, off.next_multiple_of(8)))
+}
+fn size(t: Type) -> Option<u32> {
+    match t {
+        Type::I32 | Type::F32 => Some(4),
+        Type::I64 | Type::F64 => Some(8),
+        _ => None,
+    }
+}
+fn mem_arg(t: Type, memory: Memory, offset: u32) -> Option<MemoryArg> {
+    Some(MemoryArg {
+        align: size(t)?.trailing_zeros(),
+        offset,
+        memory,
+    })
+}
+fn load(t: Type, memory: Memory, offset: u32) -> Option<Operator> {
+    let memory = mem_arg(t, memory, offset)?;
+    Some(match t {
+        Type::I32 => Operator::I32Load { memory },
+        Type::I64 => Operator::I64Load { memory },
+        Type::F32 => Operator::F32Load { memory },
+        _ => Operator::F64Load { memory },
+    })
+}
+fn store(t: Type, memory: Memory, offset: u32) -> Option<Operator> {
+    let memory = mem_arg(t, memory, offset)?;
+    Some(match t {
+        Type::I32 => Operator::I32Store { memory },
+        Type::I64 => Operator::I64Store { memory },
+        Type::F32 => Operator::F32Store { memory },
+        _ => Operator::F64Store { memory },
+    })
+}
+fn append(f: &mut FunctionBody, b: Block, o: Operator, args: &[Value], tys: &[Type]) -> Value {
+    let args = f.arg_pool.from_iter(args.iter().cloned());
+    let tys = f.type_pool.from_iter(tys.iter().cloned());
+    let v = f.add_value(ValueDef::Operator(o, args, tys));
+    f.append_to_block(b, v);
+    v
+}
+
+impl<M: GetModule> MFCache<M> {
+    /// The first defined `funcref` table, or a new one.
+    pub fn funcref_table(&mut self) -> Table {
+        let m = self.module_mut();
+        let t = m
+            .tables
+            .entries()
+            .find(|a| a.1.ty == Type::FuncRef && a.1.func_elements.is_some());
+        match t {
+            Some((t, _)) => t,
+            None => m.tables.push(TableData {
+                ty: Type::FuncRef,
+                max: None,
+                func_elements: Some(vec![]),
+            }),
+        }
+    }
+    /// Slot of `f` in [`MFCache::funcref_table`], adding it there if needed.
+    pub fn table_slot(&mut self, f: Func) -> u32 {
+        let t = self.funcref_table();
+        let t = &mut self.module_mut().tables[t];
+        let e = t.func_elements.get_or_insert_with(Vec::new);
+        let i = match e.iter().position(|g| *g == f) {
+            Some(i) => i,
+            None => {
+                e.push(f);
+                e.len() - 1
+            }
+        };
+        if let Some(max) = &mut t.max {
+            *max = (*max).max(i as u32 + 1);
+        }
+        i as u32
+    }
+    /// Records that `v` in `k` is a function value called as `c`.
+    pub fn set_callee(&mut self, k: FuncAndBlock, v: Value, c: Callee) {
+        let f = self.module().funcs[k.func].body();
+        let v = f.map_or(v, |f| f.resolve_alias(v));
+        self.closures.values.insert((k.func, v), c);
+    }
+    /// How to call `v` in `k`, if it is known to be a function value; see [`indirect`].
+    ///
+    /// [`indirect`]: self
+    pub fn callee(&self, k: FuncAndBlock, v: Value) -> Option<Callee> {
+        self.trace_callee(k.func, v, &mut BTreeSet::new())?
+    }
+    /// `None` if `v` is not known to be a function value, and `Some(None)` if it only depends on
+    /// values already being traced.
+    fn trace_callee(
+        &self,
+        func: Func,
+        v: Value,
+        seen: &mut BTreeSet<(Func, Value)>,
+    ) -> Option<Option<Callee>> {
+        let m = self.module();
+        let f = m.funcs[func].body()?;
+        let v = f.resolve_alias(v);
+        if !seen.insert((func, v)) {
+            return Some(None);
+        }
+        if let Some(c) = self.closures.values.get(&(func, v)) {
+            return Some(Some(*c));
+        }
+        let &ValueDef::BlockParam(b, i, _) = &f.values[v] else {
+            return match &f.values[v] {
+                ValueDef::Operator(Operator::Call { function_index }, _, _) => Some(Some(Callee {
+                    sig: *self.closures.trampolines.get(function_index)?,
+                    env: true,
+                })),
+                _ => None,
+            };
+        };
+        let mut args = vec![];
+        for (_, p) in f.blocks.entries() {
+            p.terminator.visit_targets(|t| {
+                if t.block == b {
+                    args.push((func, t.args.get(i as usize).copied()));
+                }
+            });
+        }
+        if b == f.entry {
+            // Callers outside of the module may pass anything.
+            let called_indirectly = m.start_func == Some(func)
+                || m.exports
+                    .iter()
+                    .any(|e| matches!(e.kind, ExportKind::Func(g) if g == func))
+                || m.tables
+                    .values()
+                    .any(|t| t.func_elements.as_ref().is_some_and(|e| e.contains(&func)));
+            if called_indirectly {
+                return None;
+            }
+            for (g, d) in m.funcs.entries() {
+                let Some(d) = d.body() else {
+                    continue;
+                };
+                for (_, w) in d.values.entries() {
+                    if let ValueDef::Operator(Operator::Call { function_index }, a, _) = w {
+                        if *function_index == func {
+                            args.push((g, d.arg_pool[*a].get(i as usize).copied()));
+                        }
+                    }
+                }
+            }
+        }
+        let mut c = None;
+        for (g, a) in args {
+            match (c, self.trace_callee(g, a?, seen)?) {
+                (_, None) => {}
+                (None, d) => c = d,
+                (Some(c), Some(d)) => {
+                    if c != d {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(c)
+    }
+    /// The memory closure environments live in, once a closure has been built.
+    pub fn closure_memory(&self) -> Option<Memory> {
+        self.closures.heap.map(|a| a.0)
+    }
+    /// The memory and globals of [`Closures::heap`], set up with nothing allocated yet.
+    ///
+    /// `None` if the first memory is imported, so other modules may use it.
+    fn heap(&mut self) -> Option<(Memory, Global, Global)> {
+        if let Some(h) = self.closures.heap {
+            return Some(h);
+        }
+        let m = self.module_mut();
+        let mem = match m.memories.iter().next() {
+            Some(a) => a,
+            None => m.memories.push(MemoryData {
+                initial_pages: 0,
+                maximum_pages: None,
+                segments: vec![],
+            }),
+        };
+        if m.imports.iter().any(|i| i.kind == ImportKind::Memory(mem)) {
+            return None;
+        }
+        let mut global = || {
+            m.globals.push(GlobalData {
+                ty: Type::I32,
+                value: Some(0),
+                mutable: true,
+            })
+        };
+        let (next, end) = (global(), global());
+        self.closures.heap = Some((mem, next, end));
+        Some((mem, next, end))
+    }
+    /// A function making a closure out of a value called as `c` and its first `n` arguments,
+    /// which it takes in that order.
+    ///
+    /// The environment goes at the next free address, after growing the memory if there is not
+    /// enough room left; the constructor traps if it cannot grow.
+    ///
+    /// `None` if `c` takes fewer arguments, if one of them cannot be stored in memory, or if there
+    /// is no memory environments can live in; see [`MFCache::heap`].
+    pub fn closure_ctor(&mut self, c: Callee, n: usize) -> Option<Func> {
+        if let Some(f) = self.closures.ctors.get(&(c, n)) {
+            return Some(*f);
+        }
+        let d = self.module().signatures[c.sig].clone();
+        let params = &d.params[c.env as usize..];
+        if n > params.len() {
+            return None;
+        }
+        let (captured, rest) = params.split_at(n);
+        let (offs, total) = layout(captured)?;
+        let table = self.funcref_table();
+        let (mem, next, end) = self.heap()?;
+        // Pages to grow by, enough for one environment.
+        let pages = total.div_ceil(0x1_0000).max(1);
+        let grown = pages.checked_mul(0x1_0000)?;
+        let m = self.module_mut();
+
+        let ts = intern_signature(
+            m,
+            [Type::I32].iter().chain(rest).cloned().collect(),
+            d.returns.clone(),
+        );
+        let mut f = FunctionBody::new(m, ts);
+        let b = f.entry;
+        let ps: Vec<Value> = f.blocks[b].params.iter().map(|a| a.1).collect();
+        let callee = append(&mut f, b, load(Type::I32, mem, 4)?, &ps[..1], &[Type::I32]);
+        let mut args = vec![];
+        if c.env {
+            args.push(callee);
+        }
+        for (t, o) in captured.iter().zip(offs.iter()) {
+            args.push(append(&mut f, b, load(*t, mem, *o)?, &ps[..1], &[*t]));
+        }
+        args.extend(&ps[1..]);
+        args.push(match c.env {
+            true => append(&mut f, b, load(Type::I32, mem, 0)?, &[callee], &[Type::I32]),
+            false => callee,
+        });
+        let o = Operator::CallIndirect {
+            sig_index: c.sig,
+            table_index: table,
+        };
+        let r = append(&mut f, b, o, &args, &d.returns);
+        let values = pick_outputs(&mut f, b, r, &d.returns);
+        f.set_terminator(b, Terminator::Return { values });
+        let t = m
+            .funcs
+            .push(FuncDecl::Body(ts, "$trampoline".to_owned(), f));
+        let slot = self.table_slot(t);
+        let m = self.module_mut();
+
+        let cs = intern_signature(
+            m,
+            [Type::I32].iter().chain(captured).cloned().collect(),
+            vec![Type::I32],
+        );
+        let mut f = FunctionBody::new(m, cs);
+        let b = f.entry;
+        let ps: Vec<Value> = f.blocks[b].params.iter().map(|a| a.1).collect();
+        let int = |value: u32| Operator::I32Const { value };
+        let get = |global_index| Operator::GlobalGet { global_index };
+        let set = |global_index| Operator::GlobalSet { global_index };
+        let to = |block, args| BlockTarget { block, args };
+        let (grow, trap, fresh, fill) =
+            (f.add_block(), f.add_block(), f.add_block(), f.add_block());
+        // Both ends are in bounds, so their difference does not wrap.
+        let p = append(&mut f, b, get(next), &[], &[Type::I32]);
+        let e = append(&mut f, b, get(end), &[], &[Type::I32]);
+        let room = append(&mut f, b, Operator::I32Sub, &[e, p], &[Type::I32]);
+        let size = append(&mut f, b, int(total), &[], &[Type::I32]);
+        let full = append(&mut f, b, Operator::I32LtU, &[room, size], &[Type::I32]);
+        f.set_terminator(
+            b,
+            Terminator::CondBr {
+                cond: full,
+                if_true: to(grow, vec![]),
+                if_false: to(fill, vec![p]),
+            },
+        );
+        let k = append(&mut f, grow, int(pages), &[], &[Type::I32]);
+        let old = append(
+            &mut f,
+            grow,
+            Operator::MemoryGrow { mem },
+            &[k],
+            &[Type::I32],
+        );
+        let none = append(&mut f, grow, int(u32::MAX), &[], &[Type::I32]);
+        let failed = append(&mut f, grow, Operator::I32Eq, &[old, none], &[Type::I32]);
+        f.set_terminator(
+            grow,
+            Terminator::CondBr {
+                cond: failed,
+                if_true: to(trap, vec![]),
+                if_false: to(fresh, vec![]),
+            },
+        );
+        f.set_terminator(trap, Terminator::Unreachable);
+        let sixteen = append(&mut f, fresh, int(16), &[], &[Type::I32]);
+        let base = append(
+            &mut f,
+            fresh,
+            Operator::I32Shl,
+            &[old, sixteen],
+            &[Type::I32],
+        );
+        let g = append(&mut f, fresh, int(grown), &[], &[Type::I32]);
+        let e = append(&mut f, fresh, Operator::I32Add, &[base, g], &[Type::I32]);
+        append(&mut f, fresh, set(end), &[e], &[]);
+        f.set_terminator(
+            fresh,
+            Terminator::Br {
+                target: to(fill, vec![base]),
+            },
+        );
+        let p = f.add_blockparam(fill, Type::I32);
+        let q = append(&mut f, fill, Operator::I32Add, &[p, size], &[Type::I32]);
+        append(&mut f, fill, set(next), &[q], &[]);
+        let s = append(&mut f, fill, int(slot), &[], &[Type::I32]);
+        append(&mut f, fill, store(Type::I32, mem, 0)?, &[p, s], &[]);
+        append(&mut f, fill, store(Type::I32, mem, 4)?, &[p, ps[0]], &[]);
+        for ((t, o), v) in captured.iter().zip(offs.iter()).zip(&ps[1..]) {
+            append(&mut f, fill, store(*t, mem, *o)?, &[p, *v], &[]);
+        }
+        f.set_terminator(fill, Terminator::Return { values: vec![p] });
+        let ctor = m.funcs.push(FuncDecl::Body(cs, "$closure".to_owned(), f));
+
+        self.closures.ctors.insert((c, n), ctor);
+        self.closures.trampolines.insert(ctor, ts);
+        Some(ctor)
+    }
+}
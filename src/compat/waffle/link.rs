@@ -0,0 +1,353 @@
+//! Static linking of waffle modules.
+//!
+//! ```ignore
+//! let m = Linker::new().parse("lib", &lib)?.parse("main", &main)?.emit()?;
+//! ```
+use std::{collections::BTreeMap, pin::Pin};
+
+use anyhow::bail;
+use waffle::{
+    entity::{EntityRef, EntityVec},
+    Export, ExportKind, Func, FuncDecl, FunctionBody, Global, Import, ImportKind, Memory, Module,
+    SignatureData, Table, Terminator,
+};
+
+use crate::utils::waffle::{append_call, parse, tweak_body, EntityMap};
+
+use super::base::{ExportData, Importd, MFCache};
+
+/// Links several modules into one.
+///
+/// Each module is named by what the others import it as. Imports satisfied by another module's
+/// export, possibly through re-exported imports, are bound to the exporting entity, and the
+/// segments and elements of resolved memories and tables join those of their definitions. The
+/// remaining imports are kept, with identical ones shared, and come first in the output. Exports
+/// are kept unless an earlier module already exports the same name, and several start functions
+/// are called in order by a synthesized one.
+#[derive(Default)]
+pub struct Linker {
+    pub modules: Vec<(String, Module<'static>)>,
+}
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn module(mut self, name: &str, m: Module<'static>) -> Self {
+        self.modules.push((name.to_owned(), m));
+        self
+    }
+    /// Adds a module from its binary, see [`parse`].
+    pub fn parse(self, name: &str, bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(self.module(name, parse(bytes)?))
+    }
+    /// Module and function an [`Importd`] of module `i` would resolve to.
+    pub fn import(&self, i: usize, imp: &Importd) -> Option<(usize, Func)> {
+        let sig = &self.modules.get(i)?.1.signatures[imp.sig];
+        let (j, e) = resolve(
+            &self.modules,
+            i,
+            &Import {
+                module: imp.module.clone(),
+                name: imp.func.clone(),
+                kind: ImportKind::Func(Func::invalid()),
+            },
+        )?;
+        let Entity::Func(f) = e else {
+            return None;
+        };
+        let n = &self.modules[j].1;
+        (n.signatures[n.funcs[f].sig()] == *sig).then_some((j, f))
+    }
+    /// Table, global or memory exported by the module named `module` as `name`.
+    pub fn data(&self, module: &str, name: &str) -> Option<ExportData> {
+        let (_, m) = self.modules.iter().find(|a| a.0 == module)?;
+        m.exports.iter().find_map(|e| match e.kind {
+            _ if e.name != name => None,
+            ExportKind::Table(t) => Some(ExportData::Table(m.tables[t].clone())),
+            ExportKind::Global(g) => Some(ExportData::Global(m.globals[g].clone())),
+            ExportKind::Memory(a) => Some(ExportData::Memory(m.memories[a].clone())),
+            ExportKind::Func(_) => None,
+        })
+    }
+    pub fn link(self) -> anyhow::Result<Module<'static>> {
+        link(self.modules)
+    }
+    pub fn link_cache(self) -> anyhow::Result<Pin<Box<MFCache<Module<'static>>>>> {
+        Ok(MFCache::from_inner(self.link()?))
+    }
+    pub fn emit(self) -> anyhow::Result<Vec<u8>> {
+        self.link()?.to_wasm_bytes()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Entity {
+    Func(Func),
+    Table(Table),
+    Global(Global),
+    Memory(Memory),
+}
+impl From<&ImportKind> for Entity {
+    fn from(a: &ImportKind) -> Self {
+        match *a {
+            ImportKind::Func(f) => Entity::Func(f),
+            ImportKind::Table(t) => Entity::Table(t),
+            ImportKind::Global(g) => Entity::Global(g),
+            ImportKind::Memory(m) => Entity::Memory(m),
+        }
+    }
+}
+impl From<&ExportKind> for Entity {
+    fn from(a: &ExportKind) -> Self {
+        match *a {
+            ExportKind::Func(f) => Entity::Func(f),
+            ExportKind::Table(t) => Entity::Table(t),
+            ExportKind::Global(g) => Entity::Global(g),
+            ExportKind::Memory(m) => Entity::Memory(m),
+        }
+    }
+}
+impl Entity {
+    fn same_kind(&self, o: &Entity) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(o)
+    }
+    fn map(self, m: &EntityMap) -> Entity {
+        match self {
+            Entity::Func(f) => Entity::Func(m.func(f)),
+            Entity::Table(t) => Entity::Table(m.table(t)),
+            Entity::Global(g) => Entity::Global(m.global(g)),
+            Entity::Memory(a) => Entity::Memory(m.memory(a)),
+        }
+    }
+    fn insert(self, m: &mut EntityMap, to: Entity) {
+        match (self, to) {
+            (Entity::Func(a), Entity::Func(b)) => drop(m.funcs.insert(a, b)),
+            (Entity::Table(a), Entity::Table(b)) => drop(m.tables.insert(a, b)),
+            (Entity::Global(a), Entity::Global(b)) => drop(m.globals.insert(a, b)),
+            (Entity::Memory(a), Entity::Memory(b)) => drop(m.memories.insert(a, b)),
+            _ => unreachable!("entity kinds are checked during resolution"),
+        }
+    }
+}
+/// A module without any entities.
+pub fn empty_module() -> Module<'static> {
+    Module {
+        orig_bytes: &[],
+        funcs: EntityVec::default(),
+        signatures: EntityVec::default(),
+        globals: EntityVec::default(),
+        tables: EntityVec::default(),
+        imports: vec![],
+        exports: vec![],
+        memories: EntityVec::default(),
+        start_func: None,
+        debug: Default::default(),
+        debug_map: Default::default(),
+    }
+}
+/// Follows `module.name`, imported by `mods[i]`, to the module defining it.
+fn resolve(mods: &[(String, Module<'static>)], i: usize, imp: &Import) -> Option<(usize, Entity)> {
+    let want = Entity::from(&imp.kind);
+    let (mut i, mut imp) = (i, imp.clone());
+    for _ in 0..mods.len() {
+        let (j, e) = mods.iter().enumerate().find_map(|(j, (n, m))| {
+            if j == i || *n != imp.module {
+                return None;
+            }
+            m.exports
+                .iter()
+                .find(|e| e.name == imp.name && Entity::from(&e.kind).same_kind(&want))
+                .map(|e| (j, Entity::from(&e.kind)))
+        })?;
+        match mods[j]
+            .1
+            .imports
+            .iter()
+            .find(|a| Entity::from(&a.kind) == e)
+        {
+            None => return Some((j, e)),
+            Some(a) => {
+                (i, imp) = (j, a.clone());
+            }
+        }
+    }
+    None
+}
+fn link(mods: Vec<(String, Module<'static>)>) -> anyhow::Result<Module<'static>> {
+    let mut out = empty_module();
+    let mut maps = vec![EntityMap::default(); mods.len()];
+    for (i, (_, m)) in mods.iter().enumerate() {
+        for (s, d) in m.signatures.entries() {
+            let found = out.signatures.entries().find(|a| a.1 == d).map(|a| a.0);
+            let n = match found {
+                Some(n) => n,
+                None => out.signatures.push(d.clone()),
+            };
+            maps[i].signatures.insert(s, n);
+        }
+    }
+    // Imports come first, as the backend requires.
+    let mut resolved = vec![];
+    let mut shared: BTreeMap<(String, String), Entity> = BTreeMap::new();
+    for (i, (_, m)) in mods.iter().enumerate() {
+        for imp in m.imports.iter() {
+            let e = Entity::from(&imp.kind);
+            if let Some((j, t)) = resolve(&mods, i, imp) {
+                if let (Entity::Func(a), Entity::Func(b)) = (e, t) {
+                    let n = &mods[j].1;
+                    if m.signatures[m.funcs[a].sig()] != n.signatures[n.funcs[b].sig()] {
+                        bail!(
+                            "{}.{} is imported by {} with a different signature",
+                            imp.module,
+                            imp.name,
+                            mods[i].0
+                        );
+                    }
+                }
+                resolved.push((i, e, (j, t)));
+                continue;
+            }
+            let key = (imp.module.clone(), imp.name.clone());
+            if let Some(n) = shared.get(&key).filter(|n| n.same_kind(&e)) {
+                e.insert(&mut maps[i], *n);
+                continue;
+            }
+            let n = match e {
+                Entity::Func(f) => Entity::Func(out.funcs.push(FuncDecl::Import(
+                    maps[i].signature(m.funcs[f].sig()),
+                    m.funcs[f].name().to_owned(),
+                ))),
+                Entity::Table(t) => Entity::Table(out.tables.push(m.tables[t].clone())),
+                Entity::Global(g) => Entity::Global(out.globals.push(m.globals[g].clone())),
+                Entity::Memory(a) => Entity::Memory(out.memories.push(m.memories[a].clone())),
+            };
+            e.insert(&mut maps[i], n);
+            shared.insert(key, n);
+            out.imports.push(Import {
+                module: imp.module.clone(),
+                name: imp.name.clone(),
+                kind: match n {
+                    Entity::Func(f) => ImportKind::Func(f),
+                    Entity::Table(t) => ImportKind::Table(t),
+                    Entity::Global(g) => ImportKind::Global(g),
+                    Entity::Memory(a) => ImportKind::Memory(a),
+                },
+            });
+        }
+    }
+    let mut defined = vec![];
+    for (i, (_, m)) in mods.iter().enumerate() {
+        let imported: Vec<Entity> = m.imports.iter().map(|a| Entity::from(&a.kind)).collect();
+        let mut d = vec![];
+        d.extend(m.funcs.iter().map(Entity::Func));
+        d.extend(m.tables.iter().map(Entity::Table));
+        d.extend(m.globals.iter().map(Entity::Global));
+        d.extend(m.memories.iter().map(Entity::Memory));
+        for e in d.into_iter().filter(|e| !imported.contains(e)) {
+            let n = match e {
+                Entity::Func(_) => Entity::Func(out.funcs.push(FuncDecl::None)),
+                Entity::Table(t) => Entity::Table(out.tables.push(m.tables[t].clone())),
+                Entity::Global(g) => Entity::Global(out.globals.push(m.globals[g].clone())),
+                Entity::Memory(a) => Entity::Memory(out.memories.push(m.memories[a].clone())),
+            };
+            e.insert(&mut maps[i], n);
+            defined.push((i, e, n));
+        }
+    }
+    for (i, e, (j, t)) in resolved.iter().cloned() {
+        let t = t.map(&maps[j]);
+        e.insert(&mut maps[i], t);
+    }
+    for (i, e, n) in defined {
+        let m = &mods[i].1;
+        match (e, n) {
+            (Entity::Func(f), Entity::Func(n)) => {
+                out.funcs[n] = match &m.funcs[f] {
+                    FuncDecl::Body(sig, name, b) => {
+                        let mut b = b.clone();
+                        tweak_body(&mut b, &maps[i]);
+                        if i != 0 {
+                            b.source_locs = Default::default();
+                        }
+                        FuncDecl::Body(maps[i].signature(*sig), name.clone(), b)
+                    }
+                    _ => bail!("function {} of module {} is not expanded", f, mods[i].0),
+                }
+            }
+            (Entity::Table(_), Entity::Table(n)) => {
+                if let Some(e) = out.tables[n].func_elements.as_mut() {
+                    for f in e.iter_mut().filter(|f| f.is_valid()) {
+                        *f = maps[i].func(*f);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // Segments of resolved memories and elements of resolved tables join the target.
+    for (i, e, _) in resolved {
+        let m = &mods[i].1;
+        match (e, e.map(&maps[i])) {
+            (Entity::Memory(a), Entity::Memory(n)) => {
+                let segs = m.memories[a].segments.clone();
+                out.memories[n].segments.extend(segs);
+            }
+            (Entity::Table(t), Entity::Table(n)) => {
+                let Some(src) = &m.tables[t].func_elements else {
+                    continue;
+                };
+                let dst = out.tables[n].func_elements.get_or_insert_with(Vec::new);
+                for (k, f) in src.iter().enumerate().filter(|a| a.1.is_valid()) {
+                    if dst.len() <= k {
+                        dst.resize(k + 1, Func::invalid());
+                    }
+                    dst[k] = maps[i].func(*f);
+                }
+            }
+            _ => {}
+        }
+    }
+    for (i, (_, m)) in mods.iter().enumerate() {
+        for e in m.exports.iter() {
+            if out.exports.iter().any(|a| a.name == e.name) {
+                continue;
+            }
+            out.exports.push(Export {
+                name: e.name.clone(),
+                kind: match Entity::from(&e.kind).map(&maps[i]) {
+                    Entity::Func(f) => ExportKind::Func(f),
+                    Entity::Table(t) => ExportKind::Table(t),
+                    Entity::Global(g) => ExportKind::Global(g),
+                    Entity::Memory(a) => ExportKind::Memory(a),
+                },
+            });
+        }
+    }
+    let starts: Vec<Func> = mods
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (_, m))| Some(maps[i].func(m.start_func?)))
+        .collect();
+    out.start_func = match &starts[..] {
+        [] => None,
+        [s] => Some(*s),
+        _ => {
+            let sig = out.signatures.push(SignatureData {
+                params: vec![],
+                returns: vec![],
+            });
+            let mut b = FunctionBody::new(&out, sig);
+            let entry = b.entry;
+            for s in starts {
+                append_call(&out, &mut b, entry, s, vec![]);
+            }
+            b.set_terminator(entry, Terminator::Return { values: vec![] });
+            Some(out.funcs.push(FuncDecl::Body(sig, "$start".to_owned(), b)))
+        }
+    };
+    if let Some((_, m)) = mods.into_iter().next() {
+        out.debug = m.debug;
+        out.debug_map = m.debug_map;
+    }
+    Ok(out)
+}
@@ -0,0 +1,258 @@
+//! [`MemoryOp`] and [`RangeOp`] for waffle operators, and bounds checks of waffle modules.
+//!
+//! Memories are sized by their initial pages, since they only grow.
+use waffle::{MemoryArg, Operator};
+
+use crate::{
+    analysis::{
+        bounds::BoundsChecks,
+        memory::{Access, MemoryOp},
+        range::{Compare, Range, RangeOp},
+    },
+    error::Error,
+};
+
+use super::base::{FuncAndBlock, GetModule, MFCache};
+
+const WASM_PAGE: u64 = 65536;
+const I32: u64 = u32::MAX as u64;
+const I64: u64 = u64::MAX;
+
+/// Plans the bounds checks of `m`; see [`BoundsChecks`].
+pub fn bounds_checks<M: GetModule, E: From<Error<FuncAndBlock>>>(
+    m: &MFCache<M>,
+) -> Result<BoundsChecks<MFCache<M>, waffle::Memory>, E> {
+    let module = m.module();
+    BoundsChecks::new(m, |k| {
+        let d = module.memories.get(*k)?;
+        (d.initial_pages as u64).checked_mul(WASM_PAGE)
+    })
+}
+
+impl MemoryOp for Operator {
+    type Memory = waffle::Memory;
+
+    fn access(&self) -> Option<Access<waffle::Memory>> {
+        let (memory, store, size): (&MemoryArg, _, _) = match self {
+            Operator::I32Load8S { memory }
+            | Operator::I32Load8U { memory }
+            | Operator::I64Load8S { memory }
+            | Operator::I64Load8U { memory } => (memory, false, 1),
+            Operator::I32Load16S { memory }
+            | Operator::I32Load16U { memory }
+            | Operator::I64Load16S { memory }
+            | Operator::I64Load16U { memory } => (memory, false, 2),
+            Operator::I32Load { memory }
+            | Operator::F32Load { memory }
+            | Operator::I64Load32S { memory }
+            | Operator::I64Load32U { memory } => (memory, false, 4),
+            Operator::I64Load { memory } | Operator::F64Load { memory } => (memory, false, 8),
+            Operator::I32Store8 { memory } | Operator::I64Store8 { memory } => (memory, true, 1),
+            Operator::I32Store16 { memory } | Operator::I64Store16 { memory } => (memory, true, 2),
+            Operator::I32Store { memory }
+            | Operator::F32Store { memory }
+            | Operator::I64Store32 { memory } => (memory, true, 4),
+            Operator::I64Store { memory } | Operator::F64Store { memory } => (memory, true, 8),
+            _ => return None,
+        };
+        Some(Access {
+            memory: memory.memory,
+            store,
+            offset: memory.offset as u64,
+            size,
+        })
+    }
+
+    fn clobbers(&self) -> bool {
+        matches!(
+            self,
+            Operator::Call { .. } | Operator::CallIndirect { .. } | Operator::MemoryGrow { .. }
+        )
+    }
+
+    fn traps(&self) -> bool {
+        use Operator as O;
+        matches!(
+            self,
+            O::Unreachable
+                | O::Call { .. }
+                | O::CallIndirect { .. }
+                | O::I32DivS
+                | O::I32DivU
+                | O::I32RemS
+                | O::I32RemU
+                | O::I64DivS
+                | O::I64DivU
+                | O::I64RemS
+                | O::I64RemU
+                | O::I32TruncF32S
+                | O::I32TruncF32U
+                | O::I32TruncF64S
+                | O::I32TruncF64U
+                | O::I64TruncF32S
+                | O::I64TruncF32U
+                | O::I64TruncF64S
+                | O::I64TruncF64U
+                | O::TableGet { .. }
+                | O::TableSet { .. }
+        )
+    }
+
+    fn reloads(&self, store: &Self) -> bool {
+        use Operator as O;
+        matches!(
+            (self, store),
+            (O::I32Load { .. }, O::I32Store { .. })
+                | (O::I64Load { .. }, O::I64Store { .. })
+                | (O::F32Load { .. }, O::F32Store { .. })
+                | (O::F64Load { .. }, O::F64Store { .. })
+        )
+    }
+
+    fn writes(&self) -> bool {
+        self.clobbers()
+            || self.access().is_some_and(|a| a.store)
+            || matches!(
+                self,
+                Operator::GlobalSet { .. } | Operator::TableSet { .. } | Operator::TableGrow { .. }
+            )
+    }
+}
+
+impl RangeOp for Operator {
+    fn range(&self, args: &[Option<Range>]) -> Option<Range> {
+        use Operator as O;
+        let a = args.first().copied().flatten();
+        let b = args.get(1).copied().flatten();
+        let max = match self {
+            O::I64Const { .. }
+            | O::I64Add
+            | O::I64Sub
+            | O::I64Mul
+            | O::I64DivU
+            | O::I64RemU
+            | O::I64And
+            | O::I64Shl
+            | O::I64ShrU
+            | O::I64Load8U { .. }
+            | O::I64Load16U { .. }
+            | O::I64Load32U { .. }
+            | O::I64ExtendI32U => I64,
+            _ => I32,
+        };
+        let bits = max.count_ones();
+        match self {
+            O::I32Const { value } => Some(Range::exact(*value as u64, max)),
+            O::I64Const { value } => Some(Range::exact(*value, max)),
+            O::I32Add | O::I64Add => {
+                let (a, b) = (a?, b?);
+                let hi = a.hi.checked_add(b.hi).filter(|h| *h <= max)?;
+                Some(Range::new(a.lo + b.lo, hi, max))
+            }
+            O::I32Sub | O::I64Sub => {
+                let (a, b) = (a?, b?);
+                (a.lo >= b.hi).then(|| Range::new(a.lo - b.hi, a.hi - b.lo, max))
+            }
+            O::I32Mul | O::I64Mul => {
+                let (a, b) = (a?, b?);
+                let hi = a.hi.checked_mul(b.hi).filter(|h| *h <= max)?;
+                Some(Range::new(a.lo * b.lo, hi, max))
+            }
+            O::I32Shl | O::I64Shl => {
+                let (a, s) = (a?, b?.value().filter(|s| *s < bits as u64)?);
+                let hi = a.hi.checked_mul(1 << s).filter(|h| *h <= max)?;
+                Some(Range::new(a.lo << s, hi, max))
+            }
+            O::I32ShrU | O::I64ShrU => {
+                let a = a?;
+                match b.and_then(|b| b.value()).filter(|s| *s < bits as u64) {
+                    Some(s) => Some(Range::new(a.lo >> s, a.hi >> s, max)),
+                    None => Some(Range::new(0, a.hi, max)),
+                }
+            }
+            O::I32And | O::I64And => {
+                let hi = [a, b].into_iter().flatten().map(|r| r.hi).min()?;
+                Some(Range::new(0, hi, max))
+            }
+            O::I32RemU | O::I64RemU => match b.filter(|b| b.lo > 0) {
+                Some(b) => Some(Range::new(0, (b.hi - 1).min(a.map_or(max, |a| a.hi)), max)),
+                None => Some(Range::new(0, a?.hi, max)),
+            },
+            O::I32DivU | O::I64DivU => {
+                let (a, b) = (a?, b?);
+                (b.lo > 0).then(|| Range::new(a.lo / b.hi, a.hi / b.lo, max))
+            }
+            O::I32Eqz
+            | O::I32Eq
+            | O::I32Ne
+            | O::I32LtS
+            | O::I32LtU
+            | O::I32GtS
+            | O::I32GtU
+            | O::I32LeS
+            | O::I32LeU
+            | O::I32GeS
+            | O::I32GeU
+            | O::I64Eqz
+            | O::I64Eq
+            | O::I64Ne
+            | O::I64LtS
+            | O::I64LtU
+            | O::I64GtU
+            | O::I64GtS
+            | O::I64LeS
+            | O::I64LeU
+            | O::I64GeS
+            | O::I64GeU => Some(Range::new(0, 1, max)),
+            O::I32Load8U { .. } | O::I64Load8U { .. } => Some(Range::new(0, u8::MAX as u64, max)),
+            O::I32Load16U { .. } | O::I64Load16U { .. } => {
+                Some(Range::new(0, u16::MAX as u64, max))
+            }
+            O::I64Load32U { .. } => Some(Range::new(0, I32, max)),
+            O::I64ExtendI32U => Some(match a {
+                Some(a) => Range::new(a.lo, a.hi, max),
+                None => Range::new(0, I32, max),
+            }),
+            O::I32WrapI64 => {
+                let a = a?;
+                (a.hi <= max).then(|| Range::new(a.lo, a.hi, max))
+            }
+            // The condition comes last.
+            O::Select | O::TypedSelect { .. } => Some(a?.hull(&b?)),
+            _ => None,
+        }
+    }
+
+    fn compare(&self, args: &[Option<Range>]) -> Option<Compare> {
+        use Operator as O;
+        let signed = match self {
+            O::I32LtS | O::I32GtS | O::I32LeS | O::I32GeS => Some(I32),
+            O::I64LtS | O::I64GtS | O::I64LeS | O::I64GeS => Some(I64),
+            _ => None,
+        };
+        // Signed comparisons agree with unsigned ones between non-negative operands.
+        if let Some(max) = signed {
+            let non_negative = |i: usize| {
+                args.get(i)
+                    .copied()
+                    .flatten()
+                    .is_some_and(|r| r.hi <= max >> 1)
+            };
+            if !(non_negative(0) && non_negative(1)) {
+                return None;
+            }
+        }
+        let (lhs, rhs, strict) = match self {
+            O::I32LtU | O::I64LtU | O::I32LtS | O::I64LtS => (0, 1, true),
+            O::I32LeU | O::I64LeU | O::I32LeS | O::I64LeS => (0, 1, false),
+            O::I32GtU | O::I64GtU | O::I32GtS | O::I64GtS => (1, 0, true),
+            O::I32GeU | O::I64GeU | O::I32GeS | O::I64GeS => (1, 0, false),
+            _ => return None,
+        };
+        Some(Compare { lhs, rhs, strict })
+    }
+
+    fn is_add(&self) -> bool {
+        matches!(self, Operator::I32Add | Operator::I64Add)
+    }
+}
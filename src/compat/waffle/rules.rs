@@ -0,0 +1,128 @@
+//! `macro_rules!` DSL for [`Rule`]s over waffle operators.
+//!
+//! ```ignore
+//! rule!((I32Add x (I32Const 0)) => x)
+//! rule!("mul-two"; (I32Mul x (I32Const 2)) => (I32Shl x (I32Const 1)))
+//! ```
+//!
+//! Identifiers bind values, reusing an identifier requires the same value, and
+//! `(Op args...)` matches or builds a field-less operator. `I32Const`/`I64Const` take a literal.
+//! Each rule numbers its identifiers' capture slots from zero, in order of first appearance.
+pub use waffle::Operator;
+
+use crate::compat::rewrite::Rule;
+
+use super::base::{GetModule, MFCache};
+
+/// Capture slots of the variables of one rule, in order of first appearance.
+#[derive(Clone, Default, Debug)]
+pub struct Slots(Vec<&'static str>);
+impl Slots {
+    /// Capture slot of the variable `name`, allocating the next one if it is new.
+    pub fn slot(&mut self, name: &'static str) -> usize {
+        match self.0.iter().position(|n| *n == name) {
+            Some(i) => i,
+            None => {
+                self.0.push(name);
+                self.0.len() - 1
+            }
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! rule {
+    (@pat $s:ident; (I32Const $v:literal)) => {
+        $crate::compat::rewrite::Pattern::op(
+            |o| *o == $crate::compat::waffle::rules::Operator::I32Const { value: $v },
+            vec![],
+        )
+    };
+    (@pat $s:ident; (I64Const $v:literal)) => {
+        $crate::compat::rewrite::Pattern::op(
+            |o| *o == $crate::compat::waffle::rules::Operator::I64Const { value: $v },
+            vec![],
+        )
+    };
+    (@pat $s:ident; ($op:ident $($a:tt)*)) => {
+        $crate::compat::rewrite::Pattern::op(
+            |o| matches!(o, $crate::compat::waffle::rules::Operator::$op),
+            vec![$($crate::rule!(@pat $s; $a)),*],
+        )
+    };
+    (@pat $s:ident; $x:ident) => {
+        $crate::compat::rewrite::Pattern::any($s.slot(stringify!($x)))
+    };
+    (@tpl $s:ident; (I32Const $v:literal)) => {
+        $crate::compat::rewrite::Template::op(
+            |_| Some($crate::compat::waffle::rules::Operator::I32Const { value: $v }),
+            vec![],
+        )
+    };
+    (@tpl $s:ident; (I64Const $v:literal)) => {
+        $crate::compat::rewrite::Template::op(
+            |_| Some($crate::compat::waffle::rules::Operator::I64Const { value: $v }),
+            vec![],
+        )
+    };
+    (@tpl $s:ident; ($op:ident $($a:tt)*)) => {
+        $crate::compat::rewrite::Template::op(
+            |_| Some($crate::compat::waffle::rules::Operator::$op),
+            vec![$($crate::rule!(@tpl $s; $a)),*],
+        )
+    };
+    (@tpl $s:ident; $x:ident) => {
+        $crate::compat::rewrite::Template::capture($s.slot(stringify!($x)))
+    };
+    (@rule $name:expr; $p:tt => $t:tt) => {{
+        let mut slots = $crate::compat::waffle::rules::Slots::default();
+        $crate::compat::rewrite::Rule {
+            name: ($name).to_owned(),
+            pattern: $crate::rule!(@pat slots; $p),
+            replacement: $crate::rule!(@tpl slots; $t),
+        }
+    }};
+    ($name:literal; $p:tt => $t:tt) => {
+        $crate::rule!(@rule $name; $p => $t)
+    };
+    ($p:tt => $t:tt) => {
+        $crate::rule!(@rule concat!(stringify!($p), " => ", stringify!($t)); $p => $t)
+    };
+}
+
+/// Collects [`rule!`]s into a `Vec`.
+#[macro_export]
+macro_rules! rules {
+    ($($p:tt => $t:tt),* $(,)?) => {
+        vec![$($crate::rule!($p => $t)),*]
+    };
+}
+
+/// Integer identities that hold regardless of operand values.
+pub fn algebraic<M: GetModule>() -> Vec<Rule<MFCache<M>>> {
+    rules![
+        (I32Add x (I32Const 0)) => x,
+        (I32Sub x (I32Const 0)) => x,
+        (I32Mul x (I32Const 1)) => x,
+        (I32Mul x (I32Const 0)) => (I32Const 0),
+        (I32Or x (I32Const 0)) => x,
+        (I32Xor x (I32Const 0)) => x,
+        (I32Shl x (I32Const 0)) => x,
+        (I32ShrS x (I32Const 0)) => x,
+        (I32ShrU x (I32Const 0)) => x,
+        (I32And x x) => x,
+        (I32Or x x) => x,
+        (I32Sub x x) => (I32Const 0),
+        (I32Xor x x) => (I32Const 0),
+        (I64Add x (I64Const 0)) => x,
+        (I64Sub x (I64Const 0)) => x,
+        (I64Mul x (I64Const 1)) => x,
+        (I64Mul x (I64Const 0)) => (I64Const 0),
+        (I64Or x (I64Const 0)) => x,
+        (I64Xor x (I64Const 0)) => x,
+        (I64And x x) => x,
+        (I64Or x x) => x,
+        (I64Sub x x) => (I64Const 0),
+        (I64Xor x x) => (I64Const 0),
+    ]
+}
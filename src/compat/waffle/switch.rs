@@ -0,0 +1,117 @@
+//! Switches on sparse `i32` keys.
+//!
+//! [`Tree::Switch`](crate::compat::tree::Tree::Switch) picks a target by its index, which
+//! `Select` does directly. Keys that are not indices are lowered by [`sparse_switch`]: runs of
+//! keys at least half as many as the values they span become jump tables, offset to start at
+//! zero and range checked by the table's default, and the rest is split by a binary search of
+//! `CondBr`s.
+use waffle::{BlockTarget, Operator, Terminator, Type, Value, ValueDef};
+
+use crate::{
+    compat::{
+        tree::{Entry, TreeTerminator},
+        FunLike,
+    },
+    error::Error,
+    utils::R,
+};
+
+use super::base::{BlockRef, FuncAndBlock, GetModule, MFCache};
+
+/// Branches from `n` to the case keyed by `v`, or to `default` if there is none.
+///
+/// Keys are compared as unsigned; if a key is repeated, its first case wins. Blocks added for
+/// the search are placed in the function of `n`.
+pub fn sparse_switch<M: GetModule, E: From<Error<FuncAndBlock>>>(
+    n: &mut BlockRef<MFCache<M>>,
+    v: Value,
+    mut cases: Vec<(u32, Entry<MFCache<M>>)>,
+    default: Entry<MFCache<M>>,
+) -> Result<Terminator, E> {
+    cases.sort_by_key(|a| a.0);
+    cases.dedup_by_key(|a| a.0);
+    lower(n, v, cases, default)
+}
+fn lower<M: GetModule, E: From<Error<FuncAndBlock>>>(
+    n: &mut BlockRef<MFCache<M>>,
+    v: Value,
+    mut cases: Vec<(u32, Entry<MFCache<M>>)>,
+    default: Entry<MFCache<M>>,
+) -> Result<Terminator, E> {
+    let (lo, hi) = match (cases.first(), cases.last()) {
+        (Some(a), Some(b)) => (a.0, b.0),
+        _ => return TreeTerminator::just(n, default),
+    };
+    // The span is `hi - lo + 1` keys, of which at least half are cases.
+    if ((hi - lo) as u64) < 2 * cases.len() as u64 {
+        let i = match lo {
+            0 => v,
+            _ => {
+                let lo = constant(n, lo)?;
+                binary(n, Operator::I32Sub, v, lo)?
+            }
+        };
+        let mut go = vec![];
+        let mut cases = cases.into_iter().peekable();
+        for key in lo..=hi {
+            go.push(match cases.next_if(|a| a.0 == key) {
+                Some((_, e)) => e,
+                None => copy(&default),
+            });
+        }
+        return TreeTerminator::switch(n, i, go, default);
+    }
+    let k = n.k;
+    let high = cases.split_off(cases.len() / 2);
+    let pivot = constant(n, high[0].0)?;
+    let cond = binary(n, Operator::I32LtU, v, pivot)?;
+    let mut targets = vec![];
+    for c in [cases, high] {
+        let block = n.try_func_mut()?.add_block();
+        let b = &mut n.try_cur_mut()?[FuncAndBlock {
+            func: k.func,
+            block,
+        }];
+        let t = lower(b, v, c, copy(&default))?;
+        *b.terminator_mut() = t;
+        targets.push(BlockTarget {
+            block,
+            args: vec![],
+        });
+    }
+    let if_false = targets.pop().r_or(Error::MissingBlock(k))?;
+    let if_true = targets.pop().r_or(Error::MissingBlock(k))?;
+    Ok(Terminator::CondBr {
+        cond,
+        if_true,
+        if_false,
+    })
+}
+fn copy<M: GetModule>(e: &Entry<MFCache<M>>) -> Entry<MFCache<M>> {
+    Entry {
+        fun: e.fun,
+        args: e.args.clone(),
+    }
+}
+fn constant<M: GetModule>(
+    n: &mut BlockRef<MFCache<M>>,
+    value: u32,
+) -> Result<Value, Error<FuncAndBlock>> {
+    let k = n.k;
+    let t = n.try_func_mut()?.single_type_list(Type::I32);
+    let o = ValueDef::Operator(Operator::I32Const { value }, Default::default(), t);
+    n.add(o).r_or(Error::MissingBody(k))
+}
+fn binary<M: GetModule>(
+    n: &mut BlockRef<MFCache<M>>,
+    o: Operator,
+    a: Value,
+    b: Value,
+) -> Result<Value, Error<FuncAndBlock>> {
+    let k = n.k;
+    let f = n.try_func_mut()?;
+    let args = f.arg_pool.from_iter([a, b].into_iter());
+    let t = f.single_type_list(Type::I32);
+    n.add(ValueDef::Operator(o, args, t))
+        .r_or(Error::MissingBody(k))
+}
@@ -0,0 +1,69 @@
+use std::fmt::{self, Debug, Display};
+
+/// What went wrong while working on a module, located by `L` (a [`FuncAndBlock`] for waffle).
+///
+/// Generic code takes any `E: From<Error<L>>`; `()` is such a type, for callers that do not care.
+///
+/// [`FuncAndBlock`]: crate::compat::waffle::base::FuncAndBlock
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Error<L> {
+    /// A handle that is not attached to a module, such as a defaulted one.
+    NullHandle(L),
+    /// A function without a body, such as an import.
+    MissingBody(L),
+    /// A block that could not be mapped into another function.
+    MissingBlock(L),
+    /// A function whose signature does not fit its use.
+    BadSignature(L),
+    /// A value called or bound that is not known to be a function.
+    NotAFunction(L),
+    /// A closure called without any memory its environment could live in.
+    NoClosureMemory(L),
+    /// Anything else the location lacks.
+    Missing(L, String),
+    /// `.2` happened at `.0` while doing `.1`.
+    Context(L, String, Box<Error<L>>),
+}
+impl<L> Error<L> {
+    /// Wraps `self` with what was being done, and where.
+    pub fn at(self, at: L, what: impl Into<String>) -> Self {
+        Error::Context(at, what.into(), Box::new(self))
+    }
+    pub fn location(&self) -> &L {
+        match self {
+            Error::NullHandle(l)
+            | Error::MissingBody(l)
+            | Error::MissingBlock(l)
+            | Error::BadSignature(l)
+            | Error::NotAFunction(l)
+            | Error::NoClosureMemory(l)
+            | Error::Missing(l, _)
+            | Error::Context(l, _, _) => l,
+        }
+    }
+    /// The innermost error, without context.
+    pub fn root(&self) -> &Self {
+        match self {
+            Error::Context(_, _, e) => e.root(),
+            e => e,
+        }
+    }
+}
+impl<L: Debug> Display for Error<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NullHandle(l) => write!(f, "{l:?}: handle is not attached to a module"),
+            Error::MissingBody(l) => write!(f, "{l:?}: function has no body"),
+            Error::MissingBlock(l) => write!(f, "{l:?}: block has no counterpart in the target"),
+            Error::BadSignature(l) => write!(f, "{l:?}: signature does not match"),
+            Error::NotAFunction(l) => write!(f, "{l:?}: value is not a known function"),
+            Error::NoClosureMemory(l) => write!(f, "{l:?}: no memory holds closure environments"),
+            Error::Missing(l, what) => write!(f, "{l:?}: missing {what}"),
+            Error::Context(l, what, e) => write!(f, "{l:?}: while {what}: {e}"),
+        }
+    }
+}
+impl<L: Debug> std::error::Error for Error<L> {}
+impl<L> From<Error<L>> for () {
+    fn from(_: Error<L>) -> Self {}
+}
@@ -7,7 +7,9 @@ use std::{
 use id_arena::{Arena, Id};
 
 pub mod adapt;
+pub mod analysis;
 pub mod compat;
+pub mod error;
 pub mod pass;
 pub mod utils;
 #[cfg(test)]
@@ -2,15 +2,23 @@ use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
 
 use id_arena::Arena;
 
+use either::Either::{Left, Right};
+
 use crate::compat::*;
 
+pub mod closure;
+pub mod entity;
+pub mod manager;
+pub mod map;
+pub mod memory;
+
 pub struct FuncTransformCtx<A: ModLike, B: ModLike> {
     pub input: <A::Code as ArenaLike<A::Fun>>::Id,
     pub output: <B::Code as ArenaLike<B::Fun>>::Id,
 }
 pub trait PassStateT<'a, 'b, A: ModLike, B: ModLike> {
-    fn get_input<'c: 'a>(&'c self) -> &'c A;
-    fn get_output<'c: 'b>(&'c mut self) -> &'c mut B;
+    fn get_input(&self) -> &A;
+    fn get_output(&mut self) -> &mut B;
 }
 pub struct PassState<'a, 'b, A: ModLike, B: ModLike> {
     pub input: &'a A,
@@ -19,13 +27,20 @@ pub struct PassState<'a, 'b, A: ModLike, B: ModLike> {
         BTreeMap<<A::Code as ArenaLike<A::Fun>>::Id, <B::Code as ArenaLike<B::Fun>>::Id>,
     pub datum_cache:
         BTreeMap<<A::Data as ArenaLike<A::Datum>>::Id, <B::Data as ArenaLike<B::Datum>>::Id>,
+    pub value_cache: BTreeMap<FunId<A>, ValueTransMap<A, B>>,
+}
+/// Where [`PassState::run_module`] put every function, datum and value of the input.
+pub struct Mapping<A: ModLike, B: ModLike> {
+    pub code: BTreeMap<FunId<A>, FunId<B>>,
+    pub data: BTreeMap<DatId<A>, DatId<B>>,
+    pub values: BTreeMap<FunId<A>, BTreeMap<ValID<A>, ValID<B>>>,
 }
 impl<'a, 'b, A: ModLike, B: ModLike> PassStateT<'a, 'b, A, B> for PassState<'a, 'b, A, B> {
-    fn get_input<'c: 'a>(&'c self) -> &'c A {
+    fn get_input(&self) -> &A {
         return self.input;
     }
 
-    fn get_output<'c: 'b>(&'c mut self) -> &'c mut B {
+    fn get_output(&mut self) -> &mut B {
         return self.out;
     }
 }
@@ -85,9 +100,25 @@ pub trait PassBehavior<A: ModLike, B: ModLike, Err>: Sized {
         fun: impl FunEmit<'a, 'b, A, B, S, Err, Self>,
         dat: impl DatEmit<'a, 'b, A, B, S, Err, Self>,
     ) -> Result<B::Datum, Err>;
+    /// Order in which [`PassState::run_module`] transforms the functions of `input`.
+    fn order(&mut self, input: &A, keys: Vec<FunId<A>>) -> Vec<FunId<A>> {
+        let _ = input;
+        keys
+    }
 }
 
 pub type ValueTransMap<A: ModLike, B: ModLike> = Rc<RefCell<BTreeMap<ValID<A>, ValID<B>>>>;
+impl<'a, 'b, A: ModLike, B: ModLike> PassState<'a, 'b, A, B> {
+    pub fn new(input: &'a A, out: &'b mut B) -> Self {
+        PassState {
+            input,
+            out,
+            code_cache: BTreeMap::new(),
+            datum_cache: BTreeMap::new(),
+            value_cache: BTreeMap::new(),
+        }
+    }
+}
 impl<'a, 'b, A: ModLike, B: ModLike> PassState<'a, 'b, A, B>
 where
     ValID<A>: Eq + Ord + Clone,
@@ -149,6 +180,7 @@ where
         let m = Rc::new(RefCell::new(BTreeMap::new()));
         // return self.out.code.alloc_with_id(|me| {
         self.code_cache.insert(f.clone(), me.clone());
+        self.value_cache.insert(f.clone(), m.clone());
         // let a = &self.input.code()[f.clone()];
         let f2 = f.clone();
         let t = w.terminator(
@@ -185,3 +217,89 @@ where
         return Ok(e);
     }
 }
+impl<'a, 'b, A: ModLikeIter + ModLikeExports, B: ModLikeExports> PassState<'a, 'b, A, B>
+where
+    ValID<A>: Eq + Ord + Clone,
+    ValID<B>: Eq + Ord + Clone,
+    FunId<A>: Eq + Ord + Clone,
+    FunId<B>: Eq + Ord + Clone,
+    DatId<A>: Eq + Ord + Clone,
+    DatId<B>: Eq + Ord + Clone,
+    <A::Fun as FunLike>::Value: Clone,
+    <B::Fun as FunLike>::Value: Default,
+    B::Fun: Default + ParamFunLike,
+    A::Fun: ParamFunLike,
+    <A::Fun as ParamFunLike>::Param: Into<<B::Fun as ParamFunLike>::Param>,
+    <A::Fun as FunLike>::Arena: ArenaLikeIter<Val<A>>,
+    <A::Fun as FunLike>::Terminator: Clone,
+    A::Datum: Clone,
+{
+    /// Transforms every function and datum of the input, in the order given by
+    /// [`PassBehavior::order`], then carries over its exports and entry point.
+    ///
+    /// Unlike [`PassState::func`], which only pulls in values reached from the terminator, every
+    /// parameter and value is transformed, in arena order.
+    pub fn run_module<E>(
+        &mut self,
+        w: &mut impl PassBehavior<A, B, E>,
+    ) -> Result<Mapping<A, B>, E> {
+        let keys = w.order(self.input, self.input.keys());
+        // Allocate every function first, so references between them resolve to the cache instead
+        // of transforming the target from its terminator only.
+        for f in keys.iter() {
+            if self.code_cache.contains_key(f) {
+                continue;
+            }
+            let me = self.out.code_mut().push(Default::default());
+            let m: ValueTransMap<A, B> = Rc::new(RefCell::new(BTreeMap::new()));
+            for (p, v) in self.input.code()[f.clone()].params() {
+                let q = self.out.code_mut()[me.clone()].add_param(p.into());
+                m.borrow_mut().insert(v, q);
+            }
+            let r = self.input.code()[f.clone()].returns();
+            let r = r.into_iter().map(Into::into).collect();
+            self.out.code_mut()[me.clone()].set_returns(r);
+            self.code_cache.insert(f.clone(), me);
+            self.value_cache.insert(f.clone(), m);
+        }
+        for f in keys {
+            let me = self.code_cache[&f].clone();
+            let m = self.value_cache[&f].clone();
+            for v in self.input.code()[f.clone()].all().keys() {
+                self.func_value(w, f.clone(), m.clone(), v)?;
+            }
+            let f2 = f.clone();
+            let t = w.terminator(
+                self,
+                FuncTransformCtx {
+                    input: f.clone(),
+                    output: me.clone(),
+                },
+                self.input.code()[f].terminator().clone(),
+                |w, t, v| t.func_value(w, f2.clone(), m.clone(), v),
+                |w, t, f| t.func(w, f),
+                |w, t, d| t.dat(w, d),
+            )?;
+            *self.out.code_mut()[me].terminator_mut() = t;
+        }
+        for d in self.input.data_keys() {
+            self.dat(w, d)?;
+        }
+        for (name, x) in self.input.exports() {
+            let x = match x {
+                Left(f) => Left(self.func(w, f)?),
+                Right(d) => Right(self.dat(w, d)?),
+            };
+            self.out.set_export(name.as_deref(), x);
+        }
+        Ok(Mapping {
+            code: self.code_cache.clone(),
+            data: self.datum_cache.clone(),
+            values: self
+                .value_cache
+                .iter()
+                .map(|(f, m)| (f.clone(), m.borrow().clone()))
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,118 @@
+//! Closure conversion of [`FunCommon`] callees, for backends without first-class closures.
+//!
+//! [`convert`] flattens a callee into a code pointer and an environment record, which can then
+//! be called directly, materialized through [`BindFun`], or, for a closed set of callees,
+//! [`defunctionalize`]d into a branch on a tag.
+use std::fmt;
+
+use either::Either::{self, Left, Right};
+
+use crate::{
+    compat::{
+        call::{BindFun, Call},
+        tree::{Entry, TreeTerminator},
+        ArenaLike, FunId, FunLike, ModLike, Term, ValID,
+    },
+    utils::FunCommon,
+};
+
+/// A code pointer and the arguments bound to it, which come before any other.
+pub struct Closure<X, M: ModLike> {
+    pub code: Either<FunId<M>, X>,
+    pub env: Vec<ValID<M>>,
+}
+
+/// Flattens the binds of `c`, innermost arguments first.
+pub fn convert<M: ModLike, X>(c: FunCommon<X, M::Fun, FunId<M>>) -> Closure<X, M> {
+    match c {
+        FunCommon::Extern(x) => Closure {
+            code: Right(x),
+            env: vec![],
+        },
+        FunCommon::Fun(k) => Closure {
+            code: Left(k),
+            env: vec![],
+        },
+        FunCommon::Bind(f, args) => {
+            let mut c = convert(*f);
+            c.env.extend(args);
+            c
+        }
+    }
+}
+
+impl<X, M: ModLike> Closure<X, M> {
+    /// Calls the code pointer directly, with the environment before `args`.
+    pub fn call<V, E>(self, n: &mut M::Fun, args: Vec<ValID<M>>) -> Result<V, E>
+    where
+        M::Fun: FunLike<Value = V>,
+        V: Call<M, M::Fun, X, E>,
+    {
+        let mut all = self.env;
+        all.extend(args);
+        V::call(n, self.code, all)
+    }
+    /// The closure as a value, bound through [`BindFun`] if its environment is not empty.
+    pub fn value<V, E>(self, n: &mut M::Fun) -> Result<V, E>
+    where
+        M::Fun: FunLike<Value = V>,
+        V: BindFun<M, M::Fun, X, E>,
+    {
+        let f = match self.code {
+            Left(k) => V::fun(n, k)?,
+            Right(x) => V::r#extern(n, x)?,
+        };
+        if self.env.is_empty() {
+            return Ok(f);
+        }
+        let f = n.all_mut().push(f);
+        V::bind(n, f, self.env)
+    }
+}
+
+/// Branches to one of `cases` picked by `tag`, passing `args` after its environment.
+///
+/// `tag` `i` picks `cases[i]`, and anything from the last index on picks the last case, as
+/// [`TreeTerminator::switch`] does with its default. External code cannot be branched to, so
+/// `shim` is asked for a function forwarding to it. Fails with [`NoCases`] if `cases` is empty.
+pub fn defunctionalize<M: ModLike, X, E>(
+    n: &mut M::Fun,
+    tag: ValID<M>,
+    cases: Vec<Closure<X, M>>,
+    args: Vec<ValID<M>>,
+    mut shim: impl FnMut(&mut M::Fun, X) -> Result<FunId<M>, E>,
+) -> Result<Term<M>, E>
+where
+    Term<M>: TreeTerminator<M, M::Fun, E>,
+    ValID<M>: Clone,
+    E: From<NoCases>,
+{
+    let mut go = vec![];
+    for c in cases {
+        let fun = match c.code {
+            Left(k) => k,
+            Right(x) => shim(n, x)?,
+        };
+        let mut all = c.env;
+        all.extend(args.iter().cloned());
+        go.push(Entry { fun, args: all });
+    }
+    let default = go.pop().ok_or(NoCases)?;
+    match go.is_empty() {
+        true => TreeTerminator::just(n, default),
+        false => TreeTerminator::switch(n, tag, go, default),
+    }
+}
+
+/// [`defunctionalize`] was given no case to branch to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoCases;
+impl fmt::Display for NoCases {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "there is no closure to defunctionalize")
+    }
+}
+impl std::error::Error for NoCases {}
+impl From<NoCases> for () {
+    fn from(_: NoCases) -> Self {}
+}
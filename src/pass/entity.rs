@@ -0,0 +1,329 @@
+//! Rewriting where the logic lives on the entities themselves.
+//!
+//! Where [`PassBehavior`](super::PassBehavior) puts a whole pass in one type, here each value,
+//! terminator, function and datum type of the input says how it rewrites into `Other`. `K` is
+//! the pass's own state, reachable from every cache through [`Deref`].
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+};
+
+use either::Either::{Left, Right};
+
+use crate::compat::{
+    ArenaLike, DatId, FunId, FunLike, ModLike, ModLikeExports, ModLikeIter, ParamFunLike, Term,
+    Val, ValID,
+};
+
+/// The function `src` of `input`, being rewritten into `fun` of the output module.
+pub struct FunRef<'a, In: ModLike, Other: ModLike> {
+    input: &'a In,
+    pub src: FunId<In>,
+    r#ref: &'a mut Other,
+    pub fun: FunId<Other>,
+}
+impl<'a, In: ModLike, Other: ModLike> FunRef<'a, In, Other>
+where
+    FunId<In>: Clone,
+    FunId<Other>: Clone,
+{
+    pub fn new(input: &'a In, src: FunId<In>, r#ref: &'a mut Other, fun: FunId<Other>) -> Self {
+        FunRef {
+            input,
+            src,
+            r#ref,
+            fun,
+        }
+    }
+    pub fn input(&self) -> &'a In {
+        self.input
+    }
+    pub fn src_fun(&self) -> &'a In::Fun {
+        &self.input.code()[self.src.clone()]
+    }
+    pub fn other(&self) -> &Other {
+        self.r#ref
+    }
+    pub fn other_mut(&mut self) -> &mut Other {
+        self.r#ref
+    }
+    pub fn fun(&self) -> &Other::Fun {
+        &(self.other().code()[self.fun.clone()])
+    }
+    pub fn fun_mut(&mut self) -> &mut Other::Fun {
+        let f = self.fun.clone();
+        &mut (self.other_mut().code_mut()[f])
+    }
+    /// Rewrites the function `f` of the input, or finds it already rewritten.
+    pub fn fun_id<K, E>(
+        &mut self,
+        k: &mut In::ModCodeCache,
+        f: FunId<In>,
+    ) -> Result<FunId<Other>, E>
+    where
+        In: PassModule<K, E, Other>,
+        In::Fun: PassFun<K, E, Other, In>,
+        Other::Fun: Default,
+    {
+        rewrite_fun_id(self.input, k, self.r#ref, f)
+    }
+}
+/// Values of one function, rewritten at most once.
+pub trait CodeCache<In: ModLike, T> {
+    fn cache<E>(
+        &mut self,
+        val: ValID<In>,
+        go: impl FnOnce(&mut Self) -> Result<T, E>,
+    ) -> Result<T, E>;
+}
+/// Functions and data of a module, rewritten at most once.
+pub trait ModCodeCache<In: ModLike, T, U> {
+    fn fun(&self, fun: &FunId<In>) -> Option<T>;
+    /// Records `fun` before its body is rewritten, so recursive references find it.
+    fn set_fun(&mut self, fun: FunId<In>, t: T);
+    fn cache_datum<E>(
+        &mut self,
+        dat: DatId<In>,
+        go: impl FnOnce(&mut Self) -> Result<U, E>,
+    ) -> Result<U, E>;
+}
+pub trait PassVal<K, E, Other: ModLike, In: PassModule<K, E, Other>>: Sized {
+    fn rewrite<C>(&self, k: &mut C, b: &mut FunRef<'_, In, Other>) -> Result<Val<Other>, E>
+    where
+        C: CodeCache<In, ValID<Other>> + DerefMut<Target = In::ModCodeCache>;
+    /// Rewrites the value `i` of the function `b` comes from, pushing it into `b` the first time.
+    fn rewrite_id<C>(
+        k: &mut C,
+        b: &mut FunRef<'_, In, Other>,
+        i: ValID<In>,
+    ) -> Result<ValID<Other>, E>
+    where
+        C: CodeCache<In, ValID<Other>> + DerefMut<Target = In::ModCodeCache>,
+        In::Fun: FunLike<Value = Self>,
+        FunId<In>: Clone,
+        FunId<Other>: Clone,
+        ValID<In>: Clone,
+    {
+        k.cache(i.clone(), move |k| {
+            let r = b.src_fun().all()[i].rewrite(k, b)?;
+            Ok(b.fun_mut().all_mut().push(r))
+        })
+    }
+}
+pub trait PassTerm<K, E, Other: ModLike, In: PassModule<K, E, Other>> {
+    fn rewrite<C>(&self, k: &mut C, om: &mut FunRef<'_, In, Other>) -> Result<Term<Other>, E>
+    where
+        C: CodeCache<In, ValID<Other>> + DerefMut<Target = In::ModCodeCache>;
+}
+pub trait PassFun<K, E, Other: ModLike, In: PassModule<K, E, Other, Fun = Self>>:
+    FunLike + Sized
+{
+    /// Fills in `om.fun`, which is already allocated and cached.
+    fn rewrite(&self, k: &mut In::ModCodeCache, om: &mut FunRef<'_, In, Other>) -> Result<(), E>;
+}
+pub trait PassDatum<K, E, Other: ModLike, In: PassModule<K, E, Other, Datum = Self>> {
+    fn rewrite(&self, k: &mut K) -> Result<Other::Datum, E>;
+}
+pub trait PassModule<K, E, Other: ModLike>: ModLike + Sized {
+    type ModCodeCache: Deref<Target = K> + DerefMut + ModCodeCache<Self, FunId<Other>, DatId<Other>>;
+}
+pub struct BasicFunCodeCache<'k, K, I: ModLike, V> {
+    re: &'k mut K,
+    all: BTreeMap<ValID<I>, V>,
+}
+impl<'k, K, I: ModLike, V> BasicFunCodeCache<'k, K, I, V>
+where
+    ValID<I>: Ord,
+{
+    pub fn new(re: &'k mut K) -> Self {
+        BasicFunCodeCache {
+            re,
+            all: BTreeMap::new(),
+        }
+    }
+    /// Maps `val` to `v` without rewriting it, as for parameters.
+    pub fn insert(&mut self, val: ValID<I>, v: V) {
+        self.all.insert(val, v);
+    }
+}
+impl<K, I: ModLike, V> Deref for BasicFunCodeCache<'_, K, I, V> {
+    type Target = K;
+
+    fn deref(&self) -> &Self::Target {
+        self.re
+    }
+}
+impl<K, I: ModLike, V> DerefMut for BasicFunCodeCache<'_, K, I, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.re
+    }
+}
+impl<K, I: ModLike, V: Clone> CodeCache<I, V> for BasicFunCodeCache<'_, K, I, V>
+where
+    ValID<I>: Eq + Ord,
+{
+    fn cache<E>(
+        &mut self,
+        val: ValID<I>,
+        go: impl FnOnce(&mut Self) -> Result<V, E>,
+    ) -> Result<V, E> {
+        if let Some(a) = self.all.get(&val) {
+            return Ok(a.clone());
+        }
+        let w = go(self)?;
+        self.all.insert(val, w.clone());
+        Ok(w)
+    }
+}
+/// A [`ModCodeCache`] over maps, holding the pass's state.
+pub struct BasicModCodeCache<K, I: ModLike, O: ModLike> {
+    pub state: K,
+    funs: BTreeMap<FunId<I>, FunId<O>>,
+    data: BTreeMap<DatId<I>, DatId<O>>,
+}
+impl<K, I: ModLike, O: ModLike> BasicModCodeCache<K, I, O> {
+    pub fn new(state: K) -> Self {
+        BasicModCodeCache {
+            state,
+            funs: BTreeMap::new(),
+            data: BTreeMap::new(),
+        }
+    }
+}
+impl<K, I: ModLike, O: ModLike> Deref for BasicModCodeCache<K, I, O> {
+    type Target = K;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+impl<K, I: ModLike, O: ModLike> DerefMut for BasicModCodeCache<K, I, O> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
+impl<K, I: ModLike, O: ModLike> ModCodeCache<I, FunId<O>, DatId<O>> for BasicModCodeCache<K, I, O>
+where
+    FunId<I>: Ord,
+    DatId<I>: Ord,
+    FunId<O>: Clone,
+    DatId<O>: Clone,
+{
+    fn fun(&self, fun: &FunId<I>) -> Option<FunId<O>> {
+        self.funs.get(fun).cloned()
+    }
+
+    fn set_fun(&mut self, fun: FunId<I>, t: FunId<O>) {
+        self.funs.insert(fun, t);
+    }
+
+    fn cache_datum<E>(
+        &mut self,
+        dat: DatId<I>,
+        go: impl FnOnce(&mut Self) -> Result<DatId<O>, E>,
+    ) -> Result<DatId<O>, E> {
+        if let Some(a) = self.data.get(&dat) {
+            return Ok(a.clone());
+        }
+        let w = go(self)?;
+        self.data.insert(dat, w.clone());
+        Ok(w)
+    }
+}
+/// Rewrites `s` into `om` with a fresh [`BasicFunCodeCache`]: its signature and parameters are
+/// copied over, then values are rewritten as its terminator reaches them.
+pub fn rewrite_basic_fun<K, E, Other: ModLike, In: PassModule<K, E, Other>>(
+    s: &In::Fun,
+    k: &mut In::ModCodeCache,
+    om: &mut FunRef<'_, In, Other>,
+) -> Result<(), E>
+where
+    Term<In>: PassTerm<K, E, Other, In>,
+    In::Fun: ParamFunLike,
+    Other::Fun: ParamFunLike,
+    <In::Fun as ParamFunLike>::Param: Into<<Other::Fun as ParamFunLike>::Param>,
+    FunId<In>: Clone,
+    FunId<Other>: Clone,
+    ValID<Other>: Clone,
+    ValID<In>: Eq + Ord,
+{
+    let mut cache = BasicFunCodeCache::<In::ModCodeCache, In, ValID<Other>>::new(k);
+    for (p, v) in s.params() {
+        let q = om.fun_mut().add_param(p.into());
+        cache.insert(v, q);
+    }
+    let r = s.returns().into_iter().map(Into::into).collect();
+    om.fun_mut().set_returns(r);
+    let t = PassTerm::rewrite(s.terminator(), &mut cache, om)?;
+    *om.fun_mut().terminator_mut() = t;
+    Ok(())
+}
+/// Rewrites the function `f` of `input` into `om`, or finds it already rewritten.
+pub fn rewrite_fun_id<K, E, Other: ModLike, In: PassModule<K, E, Other>>(
+    input: &In,
+    k: &mut In::ModCodeCache,
+    om: &mut Other,
+    f: FunId<In>,
+) -> Result<FunId<Other>, E>
+where
+    In::Fun: PassFun<K, E, Other, In>,
+    Other::Fun: Default,
+    FunId<In>: Clone,
+    FunId<Other>: Clone,
+{
+    if let Some(g) = k.fun(&f) {
+        return Ok(g);
+    }
+    let g = om.code_mut().push(Default::default());
+    k.set_fun(f.clone(), g.clone());
+    let mut r = FunRef::new(input, f.clone(), om, g.clone());
+    input.code()[f].rewrite(k, &mut r)?;
+    Ok(g)
+}
+/// Rewrites the datum `d` of `input` into `om`, or finds it already rewritten.
+pub fn rewrite_dat_id<K, E, Other: ModLike, In: PassModule<K, E, Other>>(
+    input: &In,
+    k: &mut In::ModCodeCache,
+    om: &mut Other,
+    d: DatId<In>,
+) -> Result<DatId<Other>, E>
+where
+    In::Datum: PassDatum<K, E, Other, In>,
+    DatId<In>: Clone,
+{
+    k.cache_datum(d.clone(), |k| {
+        let x = input.data()[d].rewrite(k)?;
+        Ok(om.data_mut().push(x))
+    })
+}
+/// Rewrites every function and datum of `input`, then carries over its exports and entry point.
+pub fn rewrite_module<K, E, Other, In>(
+    input: &In,
+    k: &mut In::ModCodeCache,
+    om: &mut Other,
+) -> Result<(), E>
+where
+    Other: ModLikeExports,
+    In: PassModule<K, E, Other> + ModLikeIter + ModLikeExports,
+    In::Fun: PassFun<K, E, Other, In>,
+    In::Datum: PassDatum<K, E, Other, In>,
+    Other::Fun: Default,
+    FunId<In>: Clone,
+    FunId<Other>: Clone,
+    DatId<In>: Clone,
+{
+    for f in input.keys() {
+        rewrite_fun_id(input, k, om, f)?;
+    }
+    for d in input.data_keys() {
+        rewrite_dat_id(input, k, om, d)?;
+    }
+    for (name, x) in input.exports() {
+        let x = match x {
+            Left(f) => Left(rewrite_fun_id(input, k, om, f)?),
+            Right(d) => Right(rewrite_dat_id(input, k, om, d)?),
+        };
+        om.set_export(name.as_deref(), x);
+    }
+    Ok(())
+}
@@ -0,0 +1,318 @@
+//! Orchestration of in-place passes over a module.
+//!
+//! ```ignore
+//! let mut pm = PassManager::new().function(Dce).function(Gvn).module(Inline);
+//! pm.run_pipeline(&mut m, "dce,gvn,inline")?;
+//! ```
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    analysis::{cfg::Cfg, dom::Dominators, liveness::Liveness},
+    compat::{
+        stmt::Statement, tree::UnTreeTerminator, ArenaLikeIter, FunId, FunLike, ModLikeIter, Term,
+        Val, ValID,
+    },
+};
+
+/// An analysis the [`AnalysisCache`] can compute and keep.
+pub trait CachedAnalysis<M: ModLikeIter, Err>: Sized + 'static {
+    const NAME: &'static str;
+    /// Whether the result for a function only depends on that function's body. Other results
+    /// are dropped whenever any function changes.
+    const LOCAL: bool = false;
+    /// Whether results cover the whole module whatever they are requested for, so that they
+    /// are computed and cached once, under `None`.
+    const MODULE: bool = false;
+    /// Computes the analysis for `f`, or for the whole module if `f` is `None`.
+    fn compute(m: &M, f: Option<&FunId<M>>, am: &mut AnalysisCache<M, Err>) -> Result<Self, Err>;
+}
+
+/// Analyses a pass leaves valid when it changes something.
+#[derive(Clone, Debug, Default)]
+pub enum Preserved {
+    #[default]
+    Nothing,
+    All,
+    Only(BTreeSet<&'static str>),
+}
+impl Preserved {
+    pub fn only(names: &[&'static str]) -> Self {
+        Preserved::Only(names.iter().cloned().collect())
+    }
+    pub fn keeps(&self, name: &str) -> bool {
+        match self {
+            Preserved::Nothing => false,
+            Preserved::All => true,
+            Preserved::Only(n) => n.contains(name),
+        }
+    }
+}
+
+type Key<M> = (&'static str, Option<FunId<M>>);
+
+/// Analysis results by name and by function, `None` standing for the whole module.
+pub struct AnalysisCache<M: ModLikeIter, Err> {
+    /// Results, with whether they are [`CachedAnalysis::LOCAL`].
+    cache: BTreeMap<Key<M>, (bool, Rc<dyn Any>)>,
+    pub hits: usize,
+    pub misses: usize,
+    _err: std::marker::PhantomData<fn() -> Err>,
+}
+impl<M: ModLikeIter, Err> Default for AnalysisCache<M, Err> {
+    fn default() -> Self {
+        AnalysisCache {
+            cache: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+            _err: std::marker::PhantomData,
+        }
+    }
+}
+impl<M: ModLikeIter, Err> AnalysisCache<M, Err>
+where
+    FunId<M>: Ord + Clone,
+{
+    /// `A` for `f`, computed if it is not cached.
+    pub fn get<A: CachedAnalysis<M, Err>>(&mut self, m: &M, f: &FunId<M>) -> Result<Rc<A>, Err> {
+        self.lookup(m, Some(f))
+    }
+    /// `A` for the whole module, computed if it is not cached.
+    pub fn get_module<A: CachedAnalysis<M, Err>>(&mut self, m: &M) -> Result<Rc<A>, Err> {
+        self.lookup(m, None)
+    }
+    fn lookup<A: CachedAnalysis<M, Err>>(
+        &mut self,
+        m: &M,
+        f: Option<&FunId<M>>,
+    ) -> Result<Rc<A>, Err> {
+        let f = f.filter(|_| !A::MODULE);
+        let key = (A::NAME, f.cloned());
+        if let Some((_, a)) = self.cache.get(&key) {
+            if let Ok(a) = a.clone().downcast::<A>() {
+                self.hits += 1;
+                return Ok(a);
+            }
+        }
+        self.misses += 1;
+        let a = Rc::new(A::compute(m, f, self)?);
+        self.cache.insert(key, (A::LOCAL, a.clone()));
+        Ok(a)
+    }
+    /// Whether `name` is cached for `f`.
+    pub fn contains(&self, name: &'static str, f: Option<&FunId<M>>) -> bool {
+        self.cache.contains_key(&(name, f.cloned()))
+    }
+    /// Drops what a change to `f` may have outdated, except for `keep`.
+    pub fn invalidate(&mut self, f: &FunId<M>, keep: &Preserved) {
+        self.cache.retain(|(name, g), (local, _)| {
+            keep.keeps(name) || (*local && g.as_ref().is_some_and(|g| g != f))
+        });
+    }
+    /// Drops everything but `keep`.
+    pub fn invalidate_all(&mut self, keep: &Preserved) {
+        self.cache.retain(|(name, _), _| keep.keeps(name));
+    }
+}
+
+impl<M: ModLikeIter + 'static, Err: 'static> CachedAnalysis<M, Err> for Cfg<M>
+where
+    FunId<M>: Ord + Clone,
+    Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+{
+    const NAME: &'static str = "cfg";
+    const MODULE: bool = true;
+
+    fn compute(m: &M, _: Option<&FunId<M>>, _: &mut AnalysisCache<M, Err>) -> Result<Self, Err> {
+        Cfg::new(m)
+    }
+}
+/// Rooted at the function it is requested for; for the whole module, at the first function,
+/// failing with [`NoFunctions`] if there is none.
+impl<M: ModLikeIter + 'static, Err: From<NoFunctions> + 'static> CachedAnalysis<M, Err>
+    for Dominators<M>
+where
+    FunId<M>: Ord + Clone,
+    Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+{
+    const NAME: &'static str = "dominators";
+
+    fn compute(m: &M, f: Option<&FunId<M>>, am: &mut AnalysisCache<M, Err>) -> Result<Self, Err> {
+        let cfg = am.get_module::<Cfg<M>>(m)?;
+        let root = match f {
+            Some(f) => f.clone(),
+            None => m.keys().into_iter().next().ok_or(NoFunctions)?,
+        };
+        Ok(Dominators::new(&cfg, root))
+    }
+}
+/// Computed and cached for the whole module, whatever it is requested for.
+impl<M: ModLikeIter + 'static, Err: 'static> CachedAnalysis<M, Err> for Liveness<M>
+where
+    FunId<M>: Ord + Clone,
+    ValID<M>: Ord + Clone,
+    Val<M>: Statement<M>,
+    Term<M>: UnTreeTerminator<M, M::Fun, Err>,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>>,
+{
+    const NAME: &'static str = "liveness";
+    const MODULE: bool = true;
+
+    fn compute(m: &M, _: Option<&FunId<M>>, _: &mut AnalysisCache<M, Err>) -> Result<Self, Err> {
+        Liveness::new(m)
+    }
+}
+
+/// A pass over one function at a time.
+pub trait FunctionPass<M: ModLikeIter, Err> {
+    fn name(&self) -> &str;
+    fn preserves(&self) -> Preserved {
+        Preserved::Nothing
+    }
+    /// Transforms `f`, returning whether anything changed.
+    fn run(&mut self, m: &mut M, f: &FunId<M>, am: &mut AnalysisCache<M, Err>)
+        -> Result<bool, Err>;
+}
+/// A pass over the whole module.
+pub trait ModulePass<M: ModLikeIter, Err> {
+    fn name(&self) -> &str;
+    fn preserves(&self) -> Preserved {
+        Preserved::Nothing
+    }
+    /// Transforms `m`, returning whether anything changed.
+    fn run(&mut self, m: &mut M, am: &mut AnalysisCache<M, Err>) -> Result<bool, Err>;
+}
+
+enum Registered<M: ModLikeIter, Err> {
+    Function(Box<dyn FunctionPass<M, Err>>),
+    Module(Box<dyn ModulePass<M, Err>>),
+}
+
+/// How often a pass ran, how often it changed something, and how long it took in total.
+#[derive(Clone, Default, Debug)]
+pub struct PassStats {
+    pub runs: usize,
+    pub changed: usize,
+    pub time: Duration,
+}
+
+/// A pipeline named a pass that is not registered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownPass(pub String);
+impl fmt::Display for UnknownPass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown pass `{}`", self.0)
+    }
+}
+impl std::error::Error for UnknownPass {}
+impl From<UnknownPass> for () {
+    fn from(_: UnknownPass) -> Self {}
+}
+/// A whole-module analysis needed a function to start from, but the module has none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoFunctions;
+impl fmt::Display for NoFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the module has no functions")
+    }
+}
+impl std::error::Error for NoFunctions {}
+impl From<NoFunctions> for () {
+    fn from(_: NoFunctions) -> Self {}
+}
+
+/// Runs registered passes by name, caching analyses between them.
+pub struct PassManager<M: ModLikeIter, Err> {
+    passes: BTreeMap<String, Registered<M, Err>>,
+    pub analyses: AnalysisCache<M, Err>,
+    pub stats: BTreeMap<String, PassStats>,
+}
+impl<M: ModLikeIter, Err> Default for PassManager<M, Err> {
+    fn default() -> Self {
+        PassManager {
+            passes: BTreeMap::new(),
+            analyses: AnalysisCache::default(),
+            stats: BTreeMap::new(),
+        }
+    }
+}
+impl<M: ModLikeIter, Err> PassManager<M, Err>
+where
+    FunId<M>: Ord + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn function(mut self, p: impl FunctionPass<M, Err> + 'static) -> Self {
+        self.passes
+            .insert(p.name().to_owned(), Registered::Function(Box::new(p)));
+        self
+    }
+    pub fn module(mut self, p: impl ModulePass<M, Err> + 'static) -> Self {
+        self.passes
+            .insert(p.name().to_owned(), Registered::Module(Box::new(p)));
+        self
+    }
+    /// Runs the pass `name`, returning whether it changed anything.
+    pub fn run_pass(&mut self, m: &mut M, name: &str) -> Result<bool, Err>
+    where
+        Err: From<UnknownPass>,
+    {
+        let p = self
+            .passes
+            .get_mut(name)
+            .ok_or_else(|| UnknownPass(name.to_owned()))?;
+        let start = Instant::now();
+        let changed = match p {
+            Registered::Function(p) => {
+                let keep = p.preserves();
+                let mut changed = false;
+                for f in m.keys() {
+                    if p.run(m, &f, &mut self.analyses)? {
+                        self.analyses.invalidate(&f, &keep);
+                        changed = true;
+                    }
+                }
+                changed
+            }
+            Registered::Module(p) => {
+                let changed = p.run(m, &mut self.analyses)?;
+                if changed {
+                    self.analyses.invalidate_all(&p.preserves());
+                }
+                changed
+            }
+        };
+        let s = self.stats.entry(name.to_owned()).or_default();
+        s.runs += 1;
+        s.changed += changed as usize;
+        s.time += start.elapsed();
+        Ok(changed)
+    }
+    /// Runs a comma-separated list of passes, such as `"dce,gvn,inline"`, in order.
+    ///
+    /// Every name is checked before anything runs. Returns whether any pass changed anything.
+    pub fn run_pipeline(&mut self, m: &mut M, pipeline: &str) -> Result<bool, Err>
+    where
+        Err: From<UnknownPass>,
+    {
+        let names: Vec<&str> = pipeline
+            .split(',')
+            .map(|a| a.trim())
+            .filter(|a| !a.is_empty())
+            .collect();
+        if let Some(n) = names.iter().find(|n| !self.passes.contains_key(**n)) {
+            return Err(UnknownPass(n.to_string()).into());
+        }
+        let mut changed = false;
+        for n in names {
+            changed |= self.run_pass(m, n)?;
+        }
+        Ok(changed)
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{
+    compat::{
+        rewrite::Op,
+        stmt::{Statement, Stmt},
+        tree::{Entry, MapTerminator, Tree, TreeTerminator, UnTreeTerminator},
+        FunId, ModLike, Term, Val, ValID,
+    },
+    error::Error,
+};
+
+use super::{DatEmit, FunEmit, FuncTransformCtx, PassBehavior, PassStateT, ValEmit};
+
+/// Maps the operands of terminators without a [`Tree`].
+pub type ValMap<'x, A, B, Err> = &'x mut dyn FnMut(ValID<A>) -> Result<ValID<B>, Err>;
+
+/// Copies a module as it is, renumbering functions, data and values.
+///
+/// Parameters are expected to be in place already, as [`PassState::run_module`] does.
+///
+/// [`PassState::run_module`]: super::PassState::run_module
+#[derive(Clone, Copy, Default, Debug)]
+pub struct IdentityPass;
+
+/// Converts a module statement by statement, mapping operators with `op`.
+///
+/// Branches are rebuilt through [`TreeTerminator`]; terminators without a [`Tree`] are given to
+/// `term` along with a function mapping their operands. Data convert through [`Into`].
+pub struct MapPass<O, T> {
+    pub op: O,
+    term: Option<T>,
+}
+impl<O, T> MapPass<O, T> {
+    pub fn new(op: O, term: T) -> Self {
+        MapPass {
+            op,
+            term: Some(term),
+        }
+    }
+}
+
+/// Rebuilds `it` in the output function, with `op` already applied to its operator.
+fn stmt<
+    'a,
+    'b,
+    A: ModLike,
+    B: ModLike,
+    S: PassStateT<'a, 'b, A, B>,
+    Err,
+    P: PassBehavior<A, B, Err>,
+>(
+    p: &mut P,
+    ctx: &mut S,
+    fun_ctx: FuncTransformCtx<A, B>,
+    it: Val<A>,
+    op: impl FnOnce(&mut P, &Op<A>) -> Result<Op<B>, Err>,
+    mut value: impl ValEmit<'a, 'b, A, B, S, Err, P>,
+) -> Result<Val<B>, Err>
+where
+    Val<A>: Statement<A>,
+    Val<B>: Statement<B>,
+    FunId<A>: Clone,
+    Err: From<Error<FunId<B>>>,
+{
+    let s = it.into_statement(&ctx.get_input().code()[fun_ctx.input.clone()]);
+    let s = match s {
+        Stmt::Basic(o, args) => {
+            let o = op(p, &o)?;
+            let mut a = vec![];
+            for v in args {
+                a.push(value(p, ctx, v)?);
+            }
+            Stmt::Basic(o, a)
+        }
+        Stmt::Param(i) => Stmt::Param(i),
+        Stmt::Pick(v, i) => Stmt::Pick(value(p, ctx, v)?, i),
+    };
+    Ok(Val::<B>::from_statement(
+        &s,
+        &mut ctx.get_output().code_mut()[fun_ctx.output],
+    )?)
+}
+fn entry<
+    'a,
+    'b,
+    A: ModLike,
+    B: ModLike,
+    S: PassStateT<'a, 'b, A, B>,
+    Err,
+    P: PassBehavior<A, B, Err>,
+>(
+    p: &mut P,
+    ctx: &mut S,
+    e: Entry<A>,
+    value: &mut impl ValEmit<'a, 'b, A, B, S, Err, P>,
+    fun: &mut impl FunEmit<'a, 'b, A, B, S, Err, P>,
+) -> Result<Entry<B>, Err> {
+    let f = fun(p, ctx, e.fun)?;
+    let mut args = vec![];
+    for v in e.args {
+        args.push(value(p, ctx, v)?);
+    }
+    Ok(Entry { fun: f, args })
+}
+/// Rebuilds the branch `t` in the output function.
+fn tree<
+    'a,
+    'b,
+    A: ModLike,
+    B: ModLike,
+    S: PassStateT<'a, 'b, A, B>,
+    Err,
+    P: PassBehavior<A, B, Err>,
+>(
+    p: &mut P,
+    ctx: &mut S,
+    fun_ctx: FuncTransformCtx<A, B>,
+    t: Tree<A, A::Fun>,
+    mut value: impl ValEmit<'a, 'b, A, B, S, Err, P>,
+    mut fun: impl FunEmit<'a, 'b, A, B, S, Err, P>,
+) -> Result<Term<B>, Err>
+where
+    Term<B>: TreeTerminator<B, B::Fun, Err>,
+{
+    match t {
+        Tree::Just(e) => {
+            let e = entry(p, ctx, e, &mut value, &mut fun)?;
+            TreeTerminator::just(&mut ctx.get_output().code_mut()[fun_ctx.output], e)
+        }
+        Tree::Switch(v, go, default) => {
+            let v = value(p, ctx, v)?;
+            let mut g = vec![];
+            for e in go {
+                g.push(entry(p, ctx, e, &mut value, &mut fun)?);
+            }
+            let d = entry(p, ctx, default, &mut value, &mut fun)?;
+            TreeTerminator::switch(&mut ctx.get_output().code_mut()[fun_ctx.output], v, g, d)
+        }
+        Tree::TailCall(e) => {
+            let e = entry(p, ctx, e, &mut value, &mut fun)?;
+            TreeTerminator::tail_call(&mut ctx.get_output().code_mut()[fun_ctx.output], e)
+        }
+        Tree::Return(vs) => {
+            let mut r = vec![];
+            for v in vs {
+                r.push(value(p, ctx, v)?);
+            }
+            TreeTerminator::r#return(&mut ctx.get_output().code_mut()[fun_ctx.output], r)
+        }
+        Tree::Trap => TreeTerminator::trap(&mut ctx.get_output().code_mut()[fun_ctx.output]),
+    }
+}
+
+impl<A: ModLike, Err> PassBehavior<A, A, Err> for IdentityPass
+where
+    Val<A>: Statement<A>,
+    Term<A>: UnTreeTerminator<A, A::Fun, Err> + MapTerminator<A::Fun>,
+    FunId<A>: Clone,
+    Err: From<Error<FunId<A>>>,
+{
+    fn value<'a, 'b, S: PassStateT<'a, 'b, A, A>>(
+        &mut self,
+        ctx: &mut S,
+        fun_ctx: FuncTransformCtx<A, A>,
+        it: Val<A>,
+        value: impl ValEmit<'a, 'b, A, A, S, Err, Self>,
+        _: impl FunEmit<'a, 'b, A, A, S, Err, Self>,
+        _: impl DatEmit<'a, 'b, A, A, S, Err, Self>,
+    ) -> Result<Val<A>, Err> {
+        stmt(self, ctx, fun_ctx, it, |_, o| Ok(o.clone()), value)
+    }
+
+    fn terminator<'a, 'b, S: PassStateT<'a, 'b, A, A>>(
+        &mut self,
+        ctx: &mut S,
+        fun_ctx: FuncTransformCtx<A, A>,
+        it: Term<A>,
+        mut value: impl ValEmit<'a, 'b, A, A, S, Err, Self>,
+        fun: impl FunEmit<'a, 'b, A, A, S, Err, Self>,
+        _: impl DatEmit<'a, 'b, A, A, S, Err, Self>,
+    ) -> Result<Term<A>, Err> {
+        match it.get_tree(&ctx.get_input().code()[fun_ctx.input.clone()])? {
+            Some(t) => tree(self, ctx, fun_ctx, t, value, fun),
+            None => it.map_values(&mut |v| value(self, ctx, v)),
+        }
+    }
+
+    fn datum<'a, 'b, S: PassStateT<'a, 'b, A, A>>(
+        &mut self,
+        _: &mut S,
+        def: A::Datum,
+        _: impl FunEmit<'a, 'b, A, A, S, Err, Self>,
+        _: impl DatEmit<'a, 'b, A, A, S, Err, Self>,
+    ) -> Result<A::Datum, Err> {
+        Ok(def)
+    }
+}
+
+impl<A: ModLike, B: ModLike, Err, O, T> PassBehavior<A, B, Err> for MapPass<O, T>
+where
+    Val<A>: Statement<A>,
+    Val<B>: Statement<B>,
+    Term<A>: UnTreeTerminator<A, A::Fun, Err>,
+    Term<B>: TreeTerminator<B, B::Fun, Err>,
+    FunId<A>: Clone,
+    A::Datum: Into<B::Datum>,
+    Err: From<Error<FunId<B>>>,
+    O: FnMut(&Op<A>) -> Result<Op<B>, Err>,
+    T: FnMut(Term<A>, ValMap<'_, A, B, Err>) -> Result<Term<B>, Err>,
+{
+    fn value<'a, 'b, S: PassStateT<'a, 'b, A, B>>(
+        &mut self,
+        ctx: &mut S,
+        fun_ctx: FuncTransformCtx<A, B>,
+        it: Val<A>,
+        value: impl ValEmit<'a, 'b, A, B, S, Err, Self>,
+        _: impl FunEmit<'a, 'b, A, B, S, Err, Self>,
+        _: impl DatEmit<'a, 'b, A, B, S, Err, Self>,
+    ) -> Result<Val<B>, Err> {
+        stmt(self, ctx, fun_ctx, it, |p, o| (p.op)(o), value)
+    }
+
+    fn terminator<'a, 'b, S: PassStateT<'a, 'b, A, B>>(
+        &mut self,
+        ctx: &mut S,
+        fun_ctx: FuncTransformCtx<A, B>,
+        it: Term<A>,
+        mut value: impl ValEmit<'a, 'b, A, B, S, Err, Self>,
+        fun: impl FunEmit<'a, 'b, A, B, S, Err, Self>,
+        _: impl DatEmit<'a, 'b, A, B, S, Err, Self>,
+    ) -> Result<Term<B>, Err> {
+        if let Some(t) = it.get_tree(&ctx.get_input().code()[fun_ctx.input.clone()])? {
+            return tree(self, ctx, fun_ctx, t, value, fun);
+        }
+        // `term` is taken out while it runs, as mapping operands needs `self`.
+        let mut term = self
+            .term
+            .take()
+            .expect("terminator conversion is not reentrant");
+        let t = term(it, &mut |v| value(self, ctx, v));
+        self.term = Some(term);
+        t
+    }
+
+    fn datum<'a, 'b, S: PassStateT<'a, 'b, A, B>>(
+        &mut self,
+        _: &mut S,
+        def: A::Datum,
+        _: impl FunEmit<'a, 'b, A, B, S, Err, Self>,
+        _: impl DatEmit<'a, 'b, A, B, S, Err, Self>,
+    ) -> Result<B::Datum, Err> {
+        Ok(def.into())
+    }
+}
@@ -0,0 +1,188 @@
+//! Redundant load and store elimination, over anything whose operators are [`MemoryOp`]s.
+//!
+//! Accesses fall into alias classes by memory and address value, and within a class by their
+//! offset and size: two accesses of a class overlap exactly when their bytes do, and accesses
+//! of different classes of the same memory may always overlap. Nothing is known across the
+//! edges between functions, so where functions are basic blocks, as with waffle, accesses are
+//! only eliminated within a block; there is no memory SSA over a whole control flow graph.
+use crate::{
+    analysis::memory::{Access, MemoryOp},
+    compat::{
+        rewrite::Op,
+        stmt::{Statement, Stmt},
+        ArenaLikeIter, FunId, FunLike, ModLike, ModLikeIter, MutableArenaLike, Val, ValID,
+    },
+};
+
+use super::manager::{AnalysisCache, FunctionPass, Preserved};
+
+/// The load or store `at` of `address`, and the value it loaded or stored.
+struct Known<M: ModLike>
+where
+    Val<M>: Statement<M>,
+    Op<M>: MemoryOp,
+{
+    at: ValID<M>,
+    op: Op<M>,
+    access: Access<<Op<M> as MemoryOp>::Memory>,
+    address: ValID<M>,
+    value: ValID<M>,
+}
+impl<M: ModLike> Clone for Known<M>
+where
+    Val<M>: Statement<M>,
+    Op<M>: MemoryOp,
+    ValID<M>: Clone,
+{
+    fn clone(&self) -> Self {
+        Known {
+            at: self.at.clone(),
+            op: self.op.clone(),
+            access: self.access.clone(),
+            address: self.address.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+impl<M: ModLike> Known<M>
+where
+    Val<M>: Statement<M>,
+    Op<M>: MemoryOp,
+    ValID<M>: PartialEq,
+{
+    fn same_class(&self, other: &Known<M>) -> bool {
+        self.access.memory == other.access.memory && self.address == other.address
+    }
+    fn same_place(&self, other: &Known<M>) -> bool {
+        let (a, b) = (&self.access, &other.access);
+        self.same_class(other) && (a.offset, a.size) == (b.offset, b.size)
+    }
+    fn end(&self) -> u64 {
+        self.access.offset.saturating_add(self.access.size)
+    }
+    fn may_alias(&self, other: &Known<M>) -> bool {
+        self.access.memory == other.access.memory
+            && (self.address != other.address
+                || (self.access.offset < other.end() && other.access.offset < self.end()))
+    }
+}
+
+/// Forwards stored and loaded values to later loads of the same place, and removes stores
+/// overwritten before anything could observe them.
+///
+/// Calls and anything else that [`MemoryOp::clobbers`] forget every known value. A store is
+/// only dead if the one overwriting it ends where it does, so that either both trap or neither
+/// does, and nothing in between accesses memory, [`MemoryOp::writes`] or [`MemoryOp::traps`].
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LoadStore {
+    pub forwarded: usize,
+    pub dead_stores: usize,
+}
+impl LoadStore {
+    /// Eliminates redundant accesses in `f`, returning how many were removed.
+    pub fn run_fun<M: ModLike>(&mut self, f: &mut M::Fun) -> usize
+    where
+        Val<M>: Statement<M>,
+        Op<M>: MemoryOp + PartialEq,
+        ValID<M>: Clone + PartialEq,
+        <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>> + MutableArenaLike<Val<M>>,
+    {
+        let mut known: Vec<Known<M>> = vec![];
+        // Stores nothing could have observed yet.
+        let mut pending: Vec<Known<M>> = vec![];
+        let mut forward: Vec<(ValID<M>, ValID<M>)> = vec![];
+        let mut dead = vec![];
+        for v in f.all().keys() {
+            let Stmt::Basic(o, args) = f.all()[v.clone()].into_statement(f) else {
+                continue;
+            };
+            // Operands may be loads forwarded already.
+            let resolve = |w: &ValID<M>| match forward.iter().find(|(old, _)| old == w) {
+                Some((_, new)) => new.clone(),
+                None => w.clone(),
+            };
+            let (access, address) = match (o.access(), args.first()) {
+                (Some(a), Some(address)) => (a, resolve(address)),
+                _ => {
+                    if o.clobbers() {
+                        known.clear();
+                    }
+                    // Removing the store would move a trap of the next one past this.
+                    if o.writes() || o.traps() {
+                        pending.clear();
+                    }
+                    continue;
+                }
+            };
+            let value = match access.store {
+                true => match args.get(1) {
+                    Some(w) => resolve(w),
+                    None => continue,
+                },
+                false => v.clone(),
+            };
+            let k = Known {
+                at: v.clone(),
+                op: o,
+                access,
+                address,
+                value,
+            };
+            if !k.access.store {
+                let hit = known.iter().rev().find(|j| {
+                    j.same_place(&k)
+                        && match j.access.store {
+                            true => k.op.reloads(&j.op),
+                            false => k.op == j.op,
+                        }
+                });
+                match hit {
+                    Some(j) => forward.push((v, j.value.clone())),
+                    None => {
+                        pending.clear();
+                        known.push(k);
+                    }
+                }
+                continue;
+            }
+            for p in pending.drain(..) {
+                if p.same_class(&k) && p.access.offset >= k.access.offset && p.end() == k.end() {
+                    dead.push(p.at);
+                }
+            }
+            known.retain(|j| !j.may_alias(&k));
+            known.push(k.clone());
+            pending.push(k);
+        }
+        let n = forward.len() + dead.len();
+        self.forwarded += forward.len();
+        self.dead_stores += dead.len();
+        for (old, new) in forward {
+            f.all_mut().replace_all_uses_with(old.clone(), new);
+            f.all_mut().remove(old);
+        }
+        for d in dead {
+            f.all_mut().remove(d);
+        }
+        n
+    }
+}
+/// Leaves the block graph as it was.
+impl<M: ModLikeIter, Err> FunctionPass<M, Err> for LoadStore
+where
+    FunId<M>: Clone,
+    Val<M>: Statement<M>,
+    Op<M>: MemoryOp + PartialEq,
+    ValID<M>: Clone + PartialEq,
+    <M::Fun as FunLike>::Arena: ArenaLikeIter<Val<M>> + MutableArenaLike<Val<M>>,
+{
+    fn name(&self) -> &str {
+        "load-store"
+    }
+    fn preserves(&self) -> Preserved {
+        Preserved::only(&["cfg", "dominators"])
+    }
+    fn run(&mut self, m: &mut M, f: &FunId<M>, _: &mut AnalysisCache<M, Err>) -> Result<bool, Err> {
+        Ok(self.run_fun::<M>(&mut m.code_mut()[f.clone()]) > 0)
+    }
+}
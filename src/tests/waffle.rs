@@ -1,13 +1,61 @@
-use waffle::Module;
+use std::{collections::BTreeSet, ops::DerefMut};
 
-use crate::compat::{FunLike, ModLikeIter};
+use either::Either::Right;
+
+use waffle::{
+    const_eval, entity::EntityRef, BlockTarget, ConstVal, Export, ExportKind, FuncDecl,
+    FunctionBody, GlobalData, Import, ImportKind, InterpContext, Memory, MemoryArg, MemoryData,
+    Module, Operator, SignatureData, TableData, Terminator, Type, Value, ValueDef,
+};
+
+use crate::adapt::waffle::{
+    exported_func, imported_func, merge, rename_export, reorder_imports, shim_import, wrap_export,
+};
+use crate::analysis::{
+    cfg::Cfg,
+    dataflow::{solve, Analysis, Direction},
+    defuse::{stmt_operands, term_operands, DefUse, Use},
+    dom::Dominators,
+    liveness::Liveness,
+};
+use crate::compat::{
+    typed::TypedValue, ArenaLike, ArenaLikeIter, FunLike, ModLikeIter, MutableArenaLike,
+    OrderedArenaLike,
+};
 use crate::{
     compat::{
-        tree::{Reloop, UnTreeTerminator},
-        waffle::base::{BlockRef, MFCache},
+        call::{BindFun, Call, CallIndirect},
+        rewrite::{Pattern, Rewriter, Rule, Template},
+        stmt::{Statement, Stmt},
+        tree::{Entry, MapTerminator, Reloop, Tree, TreeTerminator, UnTreeTerminator},
+        waffle::{
+            base::{BlockRef, ExportData, ExportKey, FuncAndBlock, GetModule, Importd, MFCache},
+            link::{empty_module, Linker},
+            memory::bounds_checks,
+            rules::algebraic,
+            switch::sparse_switch,
+        },
         ModLike,
     },
-    utils::waffle::parse,
+    error::Error,
+    pass::{
+        closure::{convert, defunctionalize},
+        entity::{
+            rewrite_basic_fun, rewrite_module, BasicModCodeCache, CodeCache, FunRef, PassDatum,
+            PassFun, PassModule, PassTerm, PassVal,
+        },
+        manager::{AnalysisCache, FunctionPass, ModulePass, PassManager, Preserved},
+        map::{IdentityPass, MapPass, ValMap},
+        memory::LoadStore,
+        DatEmit, FunEmit, FuncTransformCtx, PassBehavior, PassState, PassStateT, ValEmit,
+    },
+    utils::{
+        waffle::{
+            check_memory_access, emit, imports_first, memory_of, parse, tweak_operator,
+            vendor::op_outputs, EntityMap,
+        },
+        FunCommon,
+    },
 };
 fn mod1() -> Module<'static> {
     return parse(include_bytes!("./mod1.wasm")).unwrap();
@@ -15,7 +63,7 @@ fn mod1() -> Module<'static> {
 fn test_reloop(m: Module<'static>) {
     let m = MFCache::from_inner(m);
     for n in m.keys() {
-        let t: Result<_,()> = m[n].terminator().get_tree(&m[n]);
+        let t: Result<_, ()> = m[n].terminator().get_tree(&m[n]);
         assert!(t.is_ok(), "tree deconstruction should succeed");
         if let Some(_) = t.unwrap() {
             let r: Result<_, ()> = BlockRef::<MFCache<Module<'static>>>::reloop(&*m, &n);
@@ -27,3 +75,2110 @@ fn test_reloop(m: Module<'static>) {
 fn mod1_reloop() {
     test_reloop(mod1());
 }
+#[test]
+fn mod1_ordered_insert() {
+    let mut m = MFCache::from_inner(mod1());
+    let n = m
+        .keys()
+        .into_iter()
+        .find(|n| !m[*n].func().unwrap().blocks[n.block].insts.is_empty())
+        .expect("mod1 should have a non-empty block");
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let first = m[n].func().unwrap().blocks[n.block].insts[0];
+    let nop = || ValueDef::Operator(Operator::Nop, Default::default(), Default::default());
+    let a = m[n].push_after(nop(), first);
+    let b = m[n].push_after(nop(), first);
+    let c = m[n].push_just_before(nop(), first);
+    let d = m[n].push_just_before(nop(), first);
+    let e = m[n].push_after(nop(), a);
+    m.flush();
+    let insts = &m[n].func().unwrap().blocks[n.block].insts;
+    assert_eq!(&insts[..6], &[c, d, first, b, a, e]);
+    // Handles outside of the cache flush when dropped.
+    let (f, l) = std::mem::take(&mut m[n]).into_func_ref();
+    let g = f.r#in(l).push_after(nop(), first);
+    let insts = &m.module().funcs[n.func].body().unwrap().blocks[n.block].insts;
+    assert_eq!(&insts[..4], &[c, d, first, g]);
+}
+#[test]
+fn mod1_replace_uses() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let n = m.alloc_block(SignatureData {
+        params: vec![],
+        returns: vec![Type::I32],
+    });
+    let b = &mut m[n];
+    let t = b.func_mut().unwrap().single_type_list(Type::I32);
+    let x = b.push(ValueDef::Operator(
+        Operator::I32Const { value: 1 },
+        Default::default(),
+        t,
+    ));
+    let y = b.push(ValueDef::Operator(
+        Operator::I32Const { value: 2 },
+        Default::default(),
+        t,
+    ));
+    let args = b.func_mut().unwrap().arg_pool.from_iter([x, x].into_iter());
+    let add = b.push(ValueDef::Operator(Operator::I32Add, args, t));
+    *b.terminator_mut() = Terminator::Return { values: vec![x] };
+    assert_eq!(b.uses(x), vec![add]);
+    b.replace_all_uses_with(x, y);
+    assert!(b.uses(x).is_empty());
+    assert_eq!(b.uses(y), vec![add]);
+    assert_eq!(b.terminator(), &Terminator::Return { values: vec![y] });
+    b.remove(add);
+    assert_eq!(b.func().unwrap().blocks[n.block].insts, vec![x, y]);
+}
+#[test]
+fn mod1_defuse() {
+    let m = MFCache::from_inner(mod1());
+    let d: Result<_, ()> = DefUse::new(&*m);
+    let d = d.expect("def-use construction should succeed");
+    for n in m.keys() {
+        for v in m[n].keys() {
+            for a in stmt_operands::<MFCache<Module<'static>>>(&m[n], &m[n][v]) {
+                assert!(!d.is_dead(&n, &a), "operands should be recorded as used");
+            }
+        }
+    }
+}
+/// Builds `a: x = 1; br b` and `b: y = x + x; return y` as a fresh function.
+fn two_blocks(m: &mut MFCache<Module<'static>>) -> (FuncAndBlock, FuncAndBlock, Value) {
+    let a = m.alloc_block(SignatureData {
+        params: vec![],
+        returns: vec![Type::I32],
+    });
+    let b = FuncAndBlock {
+        func: a.func,
+        block: m[a].func_mut().unwrap().add_block(),
+    };
+    let t = m[a].func_mut().unwrap().single_type_list(Type::I32);
+    let x = m[a].push(ValueDef::Operator(
+        Operator::I32Const { value: 1 },
+        Default::default(),
+        t,
+    ));
+    *m[a].terminator_mut() = Terminator::Br {
+        target: BlockTarget {
+            block: b.block,
+            args: vec![],
+        },
+    };
+    let args = m[b]
+        .func_mut()
+        .unwrap()
+        .arg_pool
+        .from_iter([x, x].into_iter());
+    let y = m[b].push(ValueDef::Operator(Operator::I32Add, args, t));
+    *m[b].terminator_mut() = Terminator::Return { values: vec![y] };
+    (a, b, x)
+}
+#[test]
+#[should_panic(expected = "is not an instruction of")]
+fn remove_rejects_values_of_other_blocks() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (_, b, x) = two_blocks(m);
+    m[b].remove(x);
+}
+#[test]
+#[should_panic(expected = "is not an instruction of")]
+fn remove_rejects_block_params() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (_, b, _) = two_blocks(m);
+    let p = m[b].add_param(Type::I32).unwrap();
+    m[b].remove(p);
+}
+#[test]
+fn liveness_across_blocks() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (a, b, x) = two_blocks(m);
+    let l: Result<_, ()> = Liveness::new(&*m);
+    let l = l.expect("liveness should succeed");
+    assert!(l.is_live_out(&a, &x));
+    assert!(!l.is_live_in(&a, &x));
+    assert!(l.is_live_in(&b, &x));
+    assert_eq!(l.range(&x), [a, b].into_iter().collect());
+    assert_eq!(l.pressure(&a), 1);
+}
+/// Returning `z` of `a` from `b` instead of `y` is seen by both analyses without reporting
+/// it, though only the terminator of `b` uses `z`.
+#[test]
+fn analyses_see_terminator_edits() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (a, b, _) = two_blocks(m);
+    let y = m[b].keys()[0];
+    let t = m[a].func_mut().unwrap().single_type_list(Type::I32);
+    let z = m[a].push(ValueDef::Operator(
+        Operator::I32Const { value: 2 },
+        Default::default(),
+        t,
+    ));
+    let d: Result<_, ()> = DefUse::new(&*m);
+    let mut d = d.unwrap();
+    let l: Result<_, ()> = Liveness::new(&*m);
+    let mut l = l.unwrap();
+    assert!(d.is_dead(&b, &z) && !l.is_live_in(&b, &z));
+    *m[b].terminator_mut() = Terminator::Return { values: vec![z] };
+    let r: Result<_, ()> = d.refresh(&*m);
+    r.unwrap();
+    assert!(matches!(d.uses(&b, &z), [Use::Return { idx: 0 }]));
+    assert!(d.is_dead(&b, &y));
+    assert!(l.is_stale(&*m));
+    let r: Result<_, ()> = l.refresh(&*m);
+    assert!(r.unwrap());
+    assert!(l.is_live_out(&a, &z) && l.is_live_in(&b, &z));
+}
+#[test]
+fn exits_in_tree() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (a, b, _) = two_blocks(m);
+    let y = m[b].keys()[0];
+    let t: Result<_, ()> = m[b].terminator().get_tree(&m[b]);
+    assert!(matches!(t.unwrap(), Some(Tree::Return(v)) if v == [y]));
+    let o: Result<_, ()> = term_operands::<MFCache<Module<'static>>, ()>(&m[b]);
+    assert!(matches!(&o.unwrap()[..], [(v, Use::Return { idx: 0 })] if *v == y));
+    let t: Result<Terminator, ()> = TreeTerminator::trap(&mut m[a]);
+    *m[a].terminator_mut() = t.unwrap();
+    let t: Result<_, ()> = m[a].terminator().get_tree(&m[a]);
+    assert!(matches!(t.unwrap(), Some(Tree::Trap)));
+    let c: Result<_, ()> = Cfg::new(&*m);
+    assert!(c.unwrap().preds(&b).is_empty());
+}
+/// Values defined on some path so far, as [`BTreeSet`] facts join by union.
+struct Defined;
+impl Analysis<MFCache<Module<'static>>> for Defined {
+    type Fact = BTreeSet<Value>;
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn transfer(
+        &mut self,
+        _: &MFCache<Module<'static>>,
+        _: &FuncAndBlock,
+        v: &Value,
+        _: &Stmt<ValueDef, MFCache<Module<'static>>>,
+        fact: &mut Self::Fact,
+    ) {
+        fact.insert(*v);
+    }
+}
+#[test]
+fn dataflow_forward() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (a, b, x) = two_blocks(m);
+    let s: Result<_, ()> = solve(&*m, &mut Defined);
+    let s = s.expect("solving should succeed");
+    assert!(!s.entry[&a].contains(&x));
+    assert!(s.exit[&a].contains(&x));
+    assert!(s.entry[&b].contains(&x));
+}
+#[test]
+fn rewrite_algebraic() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let n = m.alloc_block(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let b = &mut m[n];
+    let x = b.params().unwrap()[0].1;
+    let t = b.func_mut().unwrap().single_type_list(Type::I32);
+    let c = |value| ValueDef::Operator(Operator::I32Const { value }, Default::default(), t);
+    let (zero, two) = (b.push(c(0)), b.push(c(2)));
+    let args = b
+        .func_mut()
+        .unwrap()
+        .arg_pool
+        .from_iter([x, zero].into_iter());
+    let y = b.push(ValueDef::Operator(Operator::I32Add, args, t));
+    let args = b
+        .func_mut()
+        .unwrap()
+        .arg_pool
+        .from_iter([y, two].into_iter());
+    let z = b.push(ValueDef::Operator(Operator::I32Mul, args, t));
+    *b.terminator_mut() = Terminator::Return { values: vec![z] };
+    type P = Pattern<MFCache<Module<'static>>>;
+    let konst = |value| P::op(move |o| *o == Operator::I32Const { value }, vec![]);
+    let mut r = Rewriter::new(vec![])
+        .rule(
+            "add-zero",
+            Pattern::op(|o| *o == Operator::I32Add, vec![Pattern::any(0), konst(0)]),
+            Template::capture(0),
+        )
+        .rule(
+            "mul-two",
+            Pattern::op(|o| *o == Operator::I32Mul, vec![Pattern::any(0), konst(2)])
+                .typed(|t| t == &[Type::I32]),
+            Template::op(
+                |_| Some(Operator::I32Shl),
+                vec![
+                    Template::capture(0),
+                    Template::op(|_| Some(Operator::I32Const { value: 1 }), vec![]),
+                ],
+            ),
+        );
+    assert_eq!(r.run_fun(b), 2);
+    assert_eq!(r.stats.applied["add-zero"], 1);
+    assert_eq!(r.stats.applied["mul-two"], 1);
+    let Stmt::Basic(Operator::I32Shl, args) = b[z].into_statement(b) else {
+        panic!("multiplication should become a shift");
+    };
+    assert_eq!(args[0], x);
+    assert!(!b.keys().contains(&y));
+    // The removed `y` still reads as a statement, but is no operand.
+    let Stmt::Basic(Operator::Unreachable, args) = b[y].into_statement(b) else {
+        panic!("a removed value should read as unreachable");
+    };
+    assert!(args.is_empty());
+    let s = Stmt::Basic(Operator::I32Add, vec![x, y]);
+    assert!(<ValueDef as Statement<Waffle>>::from_statement(&s, b).is_err());
+}
+#[test]
+fn rule_macro_matches() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let n = m.alloc_block(SignatureData {
+        params: vec![Type::I32, Type::I32],
+        returns: vec![Type::I32],
+    });
+    let b = &mut m[n];
+    let (x, y) = (b.params().unwrap()[0].1, b.params().unwrap()[1].1);
+    let t = b.func_mut().unwrap().single_type_list(Type::I32);
+    let args = b.func_mut().unwrap().arg_pool.from_iter([x, y].into_iter());
+    let xy = b.push(ValueDef::Operator(Operator::I32Sub, args, t));
+    let args = b.func_mut().unwrap().arg_pool.from_iter([x, x].into_iter());
+    let xx = b.push(ValueDef::Operator(Operator::I32Sub, args, t));
+    let r: Rule<MFCache<Module<'static>>> = crate::rule!((I32Sub x x) => (I32Const 0));
+    assert_eq!(r.name, "(I32Sub x x) => (I32Const 0)");
+    assert!(!r.pattern.matches(b, &xy, &mut Default::default()));
+    assert!(r.pattern.matches(b, &xx, &mut Default::default()));
+    let r: Rule<MFCache<Module<'static>>> = crate::rule!("sub"; (I32Sub a b) => a);
+    let mut caps = Default::default();
+    assert!(r.pattern.matches(b, &xy, &mut caps));
+    assert_eq!((caps.vals[&0], caps.vals[&1]), (x, y));
+    *b.terminator_mut() = Terminator::Return { values: vec![xx] };
+    let mut w = Rewriter::new(algebraic());
+    assert_eq!(w.run_fun(b), 1);
+    assert!(matches!(
+        b[xx].into_statement(b),
+        Stmt::Basic(Operator::I32Const { value: 0 }, _)
+    ));
+}
+fn append_i32(b: &mut FunctionBody, o: Operator, args: &[Value]) -> Value {
+    let l = b.entry;
+    append_i32_to(b, l, o, args)
+}
+/// Adds `o` on `args`, giving an `i32`, to the end of `l`.
+fn append_i32_to(b: &mut FunctionBody, l: waffle::Block, o: Operator, args: &[Value]) -> Value {
+    let t = b.single_type_list(Type::I32);
+    let args = b.arg_pool.from_iter(args.iter().cloned());
+    let v = b.add_value(ValueDef::Operator(o, args, t));
+    b.append_to_block(l, v);
+    v
+}
+/// `lib` exports `inc(x) = x + 1`.
+fn lib_module() -> Module<'static> {
+    let mut m = empty_module();
+    let sig = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, sig);
+    let x = b.blocks[b.entry].params[0].1;
+    let one = append_i32(&mut b, Operator::I32Const { value: 1 }, &[]);
+    let y = append_i32(&mut b, Operator::I32Add, &[x, one]);
+    b.set_terminator(b.entry, Terminator::Return { values: vec![y] });
+    let f = m.funcs.push(FuncDecl::Body(sig, "inc".to_owned(), b));
+    m.exports.push(Export {
+        name: "inc".to_owned(),
+        kind: ExportKind::Func(f),
+    });
+    m
+}
+/// `user` imports `lib.inc` and exports `run(x) = inc(x)`.
+fn user_module() -> Module<'static> {
+    let mut m = empty_module();
+    let sig = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let inc = m.funcs.push(FuncDecl::Import(sig, "inc".to_owned()));
+    m.imports.push(Import {
+        module: "lib".to_owned(),
+        name: "inc".to_owned(),
+        kind: ImportKind::Func(inc),
+    });
+    let mut b = FunctionBody::new(&m, sig);
+    let x = b.blocks[b.entry].params[0].1;
+    let y = append_i32(
+        &mut b,
+        Operator::Call {
+            function_index: inc,
+        },
+        &[x],
+    );
+    b.set_terminator(b.entry, Terminator::Return { values: vec![y] });
+    let f = m.funcs.push(FuncDecl::Body(sig, "run".to_owned(), b));
+    m.exports.push(Export {
+        name: "run".to_owned(),
+        kind: ExportKind::Func(f),
+    });
+    m
+}
+#[test]
+fn adapt_merge() {
+    let m = merge(lib_module(), "lib", user_module(), "user").unwrap();
+    assert!(m.imports.is_empty());
+    let names: Vec<_> = m.exports.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, vec!["inc", "run"]);
+    let inc = exported_func(&m, "inc").unwrap();
+    let run = exported_func(&m, "run").unwrap();
+    let FuncDecl::Body(_, _, b) = &m.funcs[run] else {
+        panic!("run should keep its body");
+    };
+    assert!(b.values.values().any(|v| matches!(
+        v,
+        ValueDef::Operator(Operator::Call { function_index }, _, _) if *function_index == inc
+    )));
+    let bytes = m.to_wasm_bytes().unwrap();
+    assert_eq!(parse(&bytes).unwrap().exports.len(), 2);
+}
+#[test]
+fn adapt_shim_and_wrap() {
+    let mut m = user_module();
+    assert!(rename_export(&mut m, "run", "main"));
+    assert!(!rename_export(&mut m, "run", "main"));
+    let inc = imported_func(&m, "lib", "inc").unwrap();
+    let add = binary(&mut m, Operator::I32Add);
+    let bad = wrap_export(&mut m, "main", Some(add), None);
+    assert_eq!(bad, Err(Error::BadSignature(add)));
+    assert_eq!(wrap_export(&mut m, "none", Some(inc), None), Ok(None));
+    let w = wrap_export(&mut m, "main", Some(inc), None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(exported_func(&m, "main"), Some(w));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let f: Result<_, ()> = shim_import(c, "lib", "inc", |_, p| Ok(p));
+    assert_eq!(f.unwrap(), Some(inc));
+    c.flush();
+    let m = reorder_imports(c.module().clone()).unwrap();
+    assert!(m.imports.is_empty());
+    assert!(m.to_wasm_bytes().is_ok());
+}
+/// `mid` re-exports `lib.inc` and exports a global `g`.
+fn mid_module() -> Module<'static> {
+    let mut m = empty_module();
+    let sig = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let inc = m.funcs.push(FuncDecl::Import(sig, "inc".to_owned()));
+    m.imports.push(Import {
+        module: "lib".to_owned(),
+        name: "inc".to_owned(),
+        kind: ImportKind::Func(inc),
+    });
+    let g = m.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(7),
+        mutable: false,
+    });
+    m.exports.push(Export {
+        name: "inc".to_owned(),
+        kind: ExportKind::Func(inc),
+    });
+    m.exports.push(Export {
+        name: "g".to_owned(),
+        kind: ExportKind::Global(g),
+    });
+    m
+}
+#[test]
+fn link_through_reexport() {
+    let mut user = user_module();
+    user.imports[0].module = "mid".to_owned();
+    let sig = user.funcs[imported_func(&user, "mid", "inc").unwrap()].sig();
+    let l = Linker::new()
+        .module("user", user)
+        .module("mid", mid_module())
+        .module("lib", lib_module());
+    let imp = Importd {
+        module: "mid".to_owned(),
+        func: "inc".to_owned(),
+        sig,
+    };
+    let (j, _) = l.import(0, &imp).expect("mid.inc should resolve");
+    assert_eq!(l.modules[j].0, "lib");
+    assert!(matches!(
+        l.data("mid", "g"),
+        Some(ExportData::Global(GlobalData { value: Some(7), .. }))
+    ));
+    let m = l.link().unwrap();
+    assert!(m.imports.is_empty());
+    assert_eq!(m.signatures.len(), 1);
+    let names: Vec<_> = m.exports.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, vec!["run", "inc", "g"]);
+    let bytes = m.to_wasm_bytes().unwrap();
+    let m = Linker::new().parse("all", &bytes).unwrap().link().unwrap();
+    assert_eq!(m.exports.len(), 3);
+}
+#[test]
+fn link_signature_mismatch() {
+    let mut lib = lib_module();
+    let sig = lib.signatures.push(SignatureData {
+        params: vec![Type::I64],
+        returns: vec![Type::I32],
+    });
+    let inc = exported_func(&lib, "inc").unwrap();
+    if let FuncDecl::Body(s, _, _) = &mut lib.funcs[inc] {
+        *s = sig;
+    }
+    let l = Linker::new()
+        .module("lib", lib)
+        .module("user", user_module());
+    assert!(l.link().is_err());
+}
+/// Every wasm fixture, by name.
+fn fixtures() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("mod1", include_bytes!("./mod1.wasm").to_vec()),
+        ("lib", lib_module().to_wasm_bytes().unwrap()),
+        ("user", user_module().to_wasm_bytes().unwrap()),
+        ("mid", mid_module().to_wasm_bytes().unwrap()),
+    ]
+}
+/// The [`ModLikeIter::keys`] view of `m`, with the parameter types and length of every block.
+fn shape(m: &MFCache<Module<'static>>) -> Vec<(FuncAndBlock, Vec<Type>, usize)> {
+    m.keys()
+        .into_iter()
+        .map(|k| {
+            let p = m[k].params().unwrap().into_iter().map(|a| a.0).collect();
+            (k, p, m[k].keys().len())
+        })
+        .collect()
+}
+/// The operators of every function with a body, sorted, which outlive restructured blocks.
+fn operators(m: &Module) -> Vec<Vec<String>> {
+    let ops = |b: &FunctionBody| {
+        let mut o: Vec<_> = b
+            .blocks
+            .values()
+            .flat_map(|k| k.insts.iter())
+            .filter_map(|v| match &b.values[*v] {
+                ValueDef::Operator(o, _, _) => Some(o.to_string()),
+                _ => None,
+            })
+            .collect();
+        o.sort();
+        o
+    };
+    m.funcs.values().filter_map(|f| f.body()).map(ops).collect()
+}
+/// Signature of every function with a body, and the export names.
+fn interface(m: &Module) -> (Vec<SignatureData>, Vec<String>) {
+    let sigs = m
+        .funcs
+        .values()
+        .filter(|f| f.body().is_some())
+        .map(|f| m.signatures[f.sig()].clone())
+        .collect();
+    (sigs, m.exports.iter().map(|e| e.name.clone()).collect())
+}
+#[test]
+fn emit_round_trip() {
+    for (name, bytes) in fixtures() {
+        let mut m = MFCache::from_inner(parse(&bytes).unwrap());
+        let m = unsafe { m.as_mut().get_unchecked_mut() };
+        let out = emit(m).unwrap();
+        let mut n = MFCache::from_inner(parse(&out).unwrap());
+        assert_eq!(interface(n.module()), interface(m.module()), "{name}");
+        // The backend may restructure blocks once, keeping every operator; the keys are
+        // stable from then on.
+        assert_eq!(operators(n.module()), operators(m.module()), "{name}");
+        let before = shape(&n);
+        let n = unsafe { n.as_mut().get_unchecked_mut() };
+        let again = MFCache::from_inner(parse(&emit(n).unwrap()).unwrap());
+        assert_eq!(shape(&again), before, "{name} should round-trip");
+    }
+}
+#[test]
+fn emit_reorders_imports() {
+    let mut m = MFCache::from_inner(lib_module());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let inc = exported_func(m.module(), "inc").unwrap();
+    let entry = m.module().funcs[inc].body().unwrap().entry;
+    let sig = m.module().funcs[inc].sig();
+    let k = &mut m[FuncAndBlock {
+        func: inc,
+        block: entry,
+    }];
+    let x = k.params().unwrap()[0].1;
+    let imp = Importd {
+        module: "env".to_owned(),
+        func: "log".to_owned(),
+        sig,
+    };
+    let v: Result<_, ()> = <ValueDef as Call<_, _, Importd, ()>>::call(k, Right(imp), vec![x]);
+    k.push(v.unwrap());
+    assert!(!imports_first(m.module()));
+    let out = parse(&emit(m).unwrap()).unwrap();
+    assert!(imports_first(&out));
+    assert_eq!(out.imports[0].name, "log");
+}
+#[test]
+fn errors_say_what_is_missing() {
+    let mut k = BlockRef::<MFCache<Module<'static>>>::default();
+    let r: Result<ValueDef, Error<FuncAndBlock>> = Call::<_, _, Importd, _>::call(
+        &mut k,
+        Right(Importd {
+            module: "env".to_owned(),
+            func: "f".to_owned(),
+            sig: Default::default(),
+        }),
+        vec![],
+    );
+    assert!(matches!(r, Err(Error::NullHandle(_))));
+    let mut m = MFCache::from_inner(user_module());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let run = exported_func(m.module(), "run").unwrap();
+    let inc = imported_func(m.module(), "lib", "inc").unwrap();
+    let entry = m.module().funcs[run].body().unwrap().entry;
+    let from = FuncAndBlock {
+        func: run,
+        block: entry,
+    };
+    let to = FuncAndBlock {
+        func: inc,
+        block: entry,
+    };
+    let r: Result<Terminator, Error<FuncAndBlock>> = TreeTerminator::just(
+        &mut m[from],
+        Entry {
+            fun: to,
+            args: vec![],
+        },
+    );
+    let e = r.unwrap_err();
+    assert_eq!(e.root(), &Error::MissingBlock(to));
+    assert_eq!(e.location(), &from);
+    assert!(e.to_string().contains("branching"));
+}
+/// Copies waffle operators as they are, for single-block functions.
+struct CopyOps;
+type Waffle = MFCache<Module<'static>>;
+impl PassBehavior<Waffle, Waffle, ()> for CopyOps {
+    fn value<'a, 'b, S: PassStateT<'a, 'b, Waffle, Waffle>>(
+        &mut self,
+        ctx: &mut S,
+        fun_ctx: FuncTransformCtx<Waffle, Waffle>,
+        it: ValueDef,
+        mut value: impl ValEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+        _: impl FunEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+        _: impl DatEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+    ) -> Result<ValueDef, ()> {
+        let ValueDef::Operator(op, args, tys) = it else {
+            return Err(());
+        };
+        let f = ctx.get_input().module().funcs[fun_ctx.input.func]
+            .body()
+            .unwrap();
+        let (args, tys) = (f.arg_pool[args].to_vec(), f.type_pool[tys].to_vec());
+        let mut a = vec![];
+        for v in args {
+            a.push(value(self, ctx, v)?);
+        }
+        let g = ctx.get_output()[fun_ctx.output].func_mut().unwrap();
+        let a = g.arg_pool.from_iter(a.into_iter());
+        let t = g.type_pool.from_iter(tys.into_iter());
+        Ok(ValueDef::Operator(op, a, t))
+    }
+
+    fn terminator<'a, 'b, S: PassStateT<'a, 'b, Waffle, Waffle>>(
+        &mut self,
+        ctx: &mut S,
+        _: FuncTransformCtx<Waffle, Waffle>,
+        it: Terminator,
+        mut value: impl ValEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+        _: impl FunEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+        _: impl DatEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+    ) -> Result<Terminator, ()> {
+        let Terminator::Return { values } = it else {
+            return Err(());
+        };
+        let mut v = vec![];
+        for x in values {
+            v.push(value(self, ctx, x)?);
+        }
+        Ok(Terminator::Return { values: v })
+    }
+
+    fn datum<'a, 'b, S: PassStateT<'a, 'b, Waffle, Waffle>>(
+        &mut self,
+        _: &mut S,
+        def: ExportData,
+        _: impl FunEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+        _: impl DatEmit<'a, 'b, Waffle, Waffle, S, (), Self>,
+    ) -> Result<ExportData, ()> {
+        Ok(def)
+    }
+}
+#[test]
+fn pass_run_module() {
+    let mut lib = lib_module();
+    let g = lib.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(3),
+        mutable: false,
+    });
+    lib.exports.push(Export {
+        name: "g".to_owned(),
+        kind: ExportKind::Global(g),
+    });
+    let input = MFCache::from_inner(lib);
+    let mut out = MFCache::from_inner(empty_module());
+    let out = unsafe { out.as_mut().get_unchecked_mut() };
+    let mut s = PassState::new(&*input, out);
+    let map = s.run_module(&mut CopyOps).unwrap();
+    assert_eq!(map.code.len(), 1);
+    assert_eq!(map.data.len(), 1);
+    let (&k, vals) = map.values.iter().next().unwrap();
+    assert_eq!(vals.len(), input[k].keys().len());
+    out.flush();
+    let m = out.module();
+    let inc = exported_func(m, "inc").unwrap();
+    let b = m.funcs[inc].body().unwrap();
+    assert_eq!(b.blocks[b.entry].params.len(), 1);
+    assert_eq!(b.blocks[b.entry].insts.len(), 2);
+    let s = &m.signatures[m.funcs[inc].sig()];
+    assert_eq!(
+        (&s.params[..], &s.returns[..]),
+        (&[Type::I32][..], &[Type::I32][..])
+    );
+    assert!(matches!(
+        m.exports[1].kind,
+        ExportKind::Global(g) if m.globals[g].value == Some(3)
+    ));
+    validated(m);
+}
+/// Emits `m`, which must be a valid module.
+fn validated(m: &Module) -> Vec<u8> {
+    let bytes = m.to_wasm_bytes().unwrap();
+    waffle::wasmparser::Validator::new()
+        .validate_all(&bytes)
+        .unwrap();
+    bytes
+}
+/// Runs `w` over `lib_module` into a fresh module.
+fn run_lib<W: PassBehavior<Waffle, Waffle, ()>>(mut w: W) -> Module<'static> {
+    let input = MFCache::from_inner(lib_module());
+    let mut out = MFCache::from_inner(empty_module());
+    let out = unsafe { out.as_mut().get_unchecked_mut() };
+    PassState::new(&*input, out).run_module(&mut w).unwrap();
+    out.flush();
+    out.module().clone()
+}
+fn ops(m: &Module, f: waffle::Func) -> Vec<Operator> {
+    let b = m.funcs[f].body().unwrap();
+    b.blocks[b.entry]
+        .insts
+        .iter()
+        .filter_map(|v| match &b.values[*v] {
+            ValueDef::Operator(o, _, _) => Some(*o),
+            _ => None,
+        })
+        .collect()
+}
+/// What the export `name` of `m` returns for each of `xs`.
+fn call_i32(m: &Module, name: &str, xs: &[u32]) -> Vec<Vec<ConstVal>> {
+    let f = exported_func(m, name).unwrap();
+    let mut i = InterpContext::new(m).unwrap();
+    xs.iter()
+        .map(|x| i.call(m, f, &[ConstVal::I32(*x)]).ok().unwrap().to_vec())
+        .collect()
+}
+#[test]
+fn identity_and_map_pass() {
+    let xs = [0, 41, u32::MAX];
+    let lib = lib_module();
+    let m = run_lib(IdentityPass);
+    let inc = exported_func(&m, "inc").unwrap();
+    assert_eq!(
+        ops(&m, inc),
+        vec![Operator::I32Const { value: 1 }, Operator::I32Add]
+    );
+    validated(&m);
+    assert_eq!(call_i32(&m, "inc", &xs), call_i32(&lib, "inc", &xs));
+    let m = run_lib(MapPass::new(
+        |o: &Operator| {
+            Ok(match o {
+                Operator::I32Add => Operator::I32Sub,
+                o => *o,
+            })
+        },
+        |t: Terminator, f: ValMap<'_, Waffle, Waffle, ()>| {
+            MapTerminator::<BlockRef<Waffle>>::map_values(t, f)
+        },
+    ));
+    let inc = exported_func(&m, "inc").unwrap();
+    assert_eq!(
+        ops(&m, inc),
+        vec![Operator::I32Const { value: 1 }, Operator::I32Sub]
+    );
+    validated(&m);
+    let dec: Vec<_> = call_i32(&lib, "inc", &xs)
+        .into_iter()
+        .map(|r| match r[..] {
+            [ConstVal::I32(y)] => vec![ConstVal::I32(y.wrapping_sub(2))],
+            _ => r,
+        })
+        .collect();
+    assert_eq!(call_i32(&m, "inc", &xs), dec);
+}
+/// A function applying `o` to its two `i32` parameters.
+fn binary(m: &mut Module<'static>, o: Operator) -> waffle::Func {
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32, Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(m, s);
+    let (x, y) = (b.blocks[b.entry].params[0].1, b.blocks[b.entry].params[1].1);
+    let d = append_i32(&mut b, o, &[x, y]);
+    b.set_terminator(b.entry, Terminator::Return { values: vec![d] });
+    m.funcs.push(FuncDecl::Body(s, format!("{o}"), b))
+}
+/// `run(x)` returns `sub(x, 10)` called through its table slot, and `sub(10, x)` through
+/// closures binding `10`, then `x`.
+#[test]
+fn call_indirect_and_bind() {
+    let mut m = empty_module();
+    let sub = binary(&mut m, Operator::I32Sub);
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32, Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let ten = append_i32(&mut b, Operator::I32Const { value: 10 }, &[]);
+    let (entry, sub_entry) = (b.entry, m.funcs[sub].body().unwrap().entry);
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let k = FuncAndBlock {
+        func: run,
+        block: entry,
+    };
+    let n = &mut c[k];
+    let x = n.params().unwrap()[0].1;
+    let sub = FuncAndBlock {
+        func: sub,
+        block: sub_entry,
+    };
+    let f = <ValueDef as CallIndirect<_, _, _, Error<FuncAndBlock>>>::fun(n, sub).unwrap();
+    let f = n.add(f).unwrap();
+    let v =
+        <ValueDef as CallIndirect<_, _, _, Error<FuncAndBlock>>>::call_indirect(n, f, vec![x, ten]);
+    let direct = n.add(v.unwrap()).unwrap();
+    let e = <ValueDef as CallIndirect<_, _, _, Error<FuncAndBlock>>>::call_indirect(n, x, vec![]);
+    assert_eq!(e.err(), Some(Error::NotAFunction(k)));
+    // A closure before any was bound has no memory to hold its environment.
+    let env = crate::compat::waffle::indirect::Callee { sig: s, env: true };
+    n.try_cur_mut().unwrap().set_callee(k, x, env);
+    let e = <ValueDef as CallIndirect<_, _, _, Error<FuncAndBlock>>>::call_indirect(n, x, vec![]);
+    assert_eq!(e.err(), Some(Error::NoClosureMemory(k)));
+    let c1 = <ValueDef as BindFun<_, _, _, Error<FuncAndBlock>>>::bind(n, f, vec![ten]).unwrap();
+    let c1 = n.add(c1).unwrap();
+    let c2 = <ValueDef as BindFun<_, _, _, Error<FuncAndBlock>>>::bind(n, c1, vec![x]).unwrap();
+    let c2 = n.add(c2).unwrap();
+    let r = <ValueDef as BindFun<_, _, _, Error<FuncAndBlock>>>::call_one(n, c2).unwrap();
+    let r = n.add(r).unwrap();
+    n.func_mut().unwrap().set_terminator(
+        entry,
+        Terminator::Return {
+            values: vec![direct, r],
+        },
+    );
+    c.flush();
+    let m = c.module();
+    let mut i = InterpContext::new(m).unwrap();
+    for x in [3u32, 25] {
+        let r = i.call(m, run, &[ConstVal::I32(x)]).ok().unwrap();
+        assert_eq!(
+            &r[..],
+            [
+                ConstVal::I32(x.wrapping_sub(10)),
+                ConstVal::I32(10u32.wrapping_sub(x))
+            ]
+        );
+    }
+    assert!(parse(&emit(c).unwrap()).is_ok());
+}
+/// A closure reaches `apply` through a block parameter and a call, after enough others were
+/// built that their environments take several pages.
+#[test]
+fn closures_through_params() {
+    type CI =
+        dyn Fn(&mut BlockRef<Waffle>, Value, Vec<Value>) -> Result<ValueDef, Error<FuncAndBlock>>;
+    let call: &CI = &|n, v, r| <ValueDef as CallIndirect<_, _, _, _>>::call_indirect(n, v, r);
+    let bind: &CI = &|n, v, r| <ValueDef as BindFun<_, _, _, _>>::bind(n, v, r);
+    let mut m = empty_module();
+    let sub = binary(&mut m, Operator::I32Sub);
+    let sub_entry = m.funcs[sub].body().unwrap().entry;
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32, Type::I32],
+        returns: vec![Type::I32],
+    });
+    let a = FunctionBody::new(&m, s);
+    let apply_entry = a.entry;
+    let apply = m.funcs.push(FuncDecl::Body(s, "apply".to_owned(), a));
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let entry = b.entry;
+    let x = b.blocks[entry].params[0].1;
+    let (header, body, exit, done) = (b.add_block(), b.add_block(), b.add_block(), b.add_block());
+    let i = b.add_blockparam(header, Type::I32);
+    let p = b.add_blockparam(done, Type::I32);
+    let zero = append_i32_to(&mut b, entry, Operator::I32Const { value: 0 }, &[]);
+    let ten = append_i32_to(&mut b, entry, Operator::I32Const { value: 10 }, &[]);
+    let n = append_i32_to(&mut b, header, Operator::I32Const { value: 10000 }, &[]);
+    let lt = append_i32_to(&mut b, header, Operator::I32LtU, &[i, n]);
+    let target = |block, args| BlockTarget { block, args };
+    b.set_terminator(
+        header,
+        Terminator::CondBr {
+            cond: lt,
+            if_true: target(body, vec![]),
+            if_false: target(exit, vec![]),
+        },
+    );
+    let one = append_i32_to(&mut b, body, Operator::I32Const { value: 1 }, &[]);
+    let i2 = append_i32_to(&mut b, body, Operator::I32Add, &[i, one]);
+    b.set_terminator(
+        body,
+        Terminator::Br {
+            target: target(header, vec![i2]),
+        },
+    );
+    let o = Operator::Call {
+        function_index: apply,
+    };
+    let r = append_i32_to(&mut b, done, o, &[p, x]);
+    b.set_terminator(done, Terminator::Return { values: vec![r] });
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let at = |block| FuncAndBlock { func: run, block };
+    let sub = FuncAndBlock {
+        func: sub,
+        block: sub_entry,
+    };
+    let n = &mut c[at(entry)];
+    let f = <ValueDef as CallIndirect<_, _, _, Error<FuncAndBlock>>>::fun(n, sub).unwrap();
+    let f = n.add(f).unwrap();
+    let c1 = bind(n, f, vec![ten]).unwrap();
+    let c1 = n.add(c1).unwrap();
+    assert_eq!(
+        call(n, ten, vec![x]).err(),
+        Some(Error::NotAFunction(at(entry)))
+    );
+    let t = Terminator::Br {
+        target: target(header, vec![zero]),
+    };
+    n.func_mut().unwrap().set_terminator(entry, t);
+    let n = &mut c[at(body)];
+    let e = bind(n, f, vec![i]).unwrap();
+    n.add(e).unwrap();
+    let t = Terminator::Br {
+        target: target(done, vec![c1]),
+    };
+    n.func_mut().unwrap().set_terminator(exit, t);
+    let k = FuncAndBlock {
+        func: apply,
+        block: apply_entry,
+    };
+    let n = &mut c[k];
+    let ps = n.params().unwrap();
+    let r = call(n, ps[0].1, vec![ps[1].1]).unwrap();
+    let r = n.add(r).unwrap();
+    let t = Terminator::Return { values: vec![r] };
+    n.func_mut().unwrap().set_terminator(apply_entry, t);
+    c.flush();
+    let m = c.module();
+    let mut i = InterpContext::new(m).unwrap();
+    for x in [3u32, 25] {
+        let r = i.call(m, run, &[ConstVal::I32(x)]).ok().unwrap();
+        assert_eq!(&r[..], [ConstVal::I32(10u32.wrapping_sub(x))]);
+    }
+    waffle::wasmparser::Validator::new()
+        .validate_all(&emit(c).unwrap())
+        .unwrap();
+}
+/// Environments do not go in a memory other modules may use.
+#[test]
+fn closures_refuse_imported_memory() {
+    let mut m = empty_module();
+    let mem = m.memories.push(MemoryData {
+        initial_pages: 1,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    m.imports.push(Import {
+        module: "env".to_owned(),
+        name: "memory".to_owned(),
+        kind: ImportKind::Memory(mem),
+    });
+    let sub = binary(&mut m, Operator::I32Sub);
+    let k = FuncAndBlock {
+        func: sub,
+        block: m.funcs[sub].body().unwrap().entry,
+    };
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let n = &mut c[k];
+    let x = n.params().unwrap()[0].1;
+    let f = <ValueDef as CallIndirect<_, _, _, Error<FuncAndBlock>>>::fun(n, k).unwrap();
+    let f = n.add(f).unwrap();
+    let b = <ValueDef as BindFun<_, _, _, Error<FuncAndBlock>>>::bind(n, f, vec![x]);
+    assert!(b.is_err());
+}
+/// `run(x)` tail calls `sub(x, 10)`; `pair(x)` returns two values, so it cannot.
+#[test]
+fn tail_call_returns_callee_results() {
+    let mut m = empty_module();
+    let sub = binary(&mut m, Operator::I32Sub);
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let ten = append_i32(&mut b, Operator::I32Const { value: 10 }, &[]);
+    let (entry, sub_entry) = (b.entry, m.funcs[sub].body().unwrap().entry);
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32, Type::I32],
+    });
+    let b = FunctionBody::new(&m, s);
+    let pair = m.funcs.push(FuncDecl::Body(s, "pair".to_owned(), b));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let sub = FuncAndBlock {
+        func: sub,
+        block: sub_entry,
+    };
+    let k = FuncAndBlock {
+        func: pair,
+        block: entry,
+    };
+    let n = &mut c[k];
+    let x = n.params().unwrap()[0].1;
+    let r: Result<Terminator, Error<FuncAndBlock>> = TreeTerminator::tail_call(
+        n,
+        Entry {
+            fun: sub,
+            args: vec![x, x],
+        },
+    );
+    assert_eq!(r.unwrap_err().root(), &Error::BadSignature(k));
+    let n = &mut c[FuncAndBlock {
+        func: run,
+        block: entry,
+    }];
+    let x = n.params().unwrap()[0].1;
+    let r: Result<Terminator, Error<FuncAndBlock>> = TreeTerminator::tail_call(
+        n,
+        Entry {
+            fun: sub,
+            args: vec![x, ten],
+        },
+    );
+    *n.terminator_mut() = r.unwrap();
+    let t: Result<_, ()> = n.terminator().get_tree(n);
+    let Some(Tree::TailCall(e)) = t.unwrap() else {
+        panic!("the call and return should read back as a tail call");
+    };
+    assert_eq!((e.fun, &e.args[..]), (sub, &[x, ten][..]));
+    // Lowering it again reuses the call instead of adding another.
+    let len = n.func().unwrap().blocks[entry].insts.len();
+    let r: Result<Terminator, Error<FuncAndBlock>> = TreeTerminator::tail_call(n, e);
+    *n.terminator_mut() = r.unwrap();
+    assert_eq!(n.func().unwrap().blocks[entry].insts.len(), len);
+    c.flush();
+    let m = c.module();
+    assert_eq!(m.funcs.len(), 3);
+    let mut i = InterpContext::new(m).unwrap();
+    for x in [3u32, 25] {
+        let r = i.call(m, run, &[ConstVal::I32(x)]).ok().unwrap();
+        assert_eq!(&r[..], [ConstVal::I32(x.wrapping_sub(10))]);
+    }
+    assert!(parse(&emit(c).unwrap()).is_ok());
+}
+/// A call of `sub(x, x)` followed by returning its result that `tail_call` did not emit is
+/// no tail call, so passes still see the call.
+#[test]
+fn hand_built_call_and_return_stays_a_call() {
+    let mut m = empty_module();
+    let sub = binary(&mut m, Operator::I32Sub);
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let k = c.alloc_block(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let n = &mut c[k];
+    let x = n.params().unwrap()[0].1;
+    let t = n.func_mut().unwrap().single_type_list(Type::I32);
+    let a = n.func_mut().unwrap().arg_pool.from_iter([x, x].into_iter());
+    let call = Operator::Call {
+        function_index: sub,
+    };
+    let v = n.push(ValueDef::Operator(call, a, t));
+    *n.terminator_mut() = Terminator::Return { values: vec![v] };
+    let t: Result<_, ()> = n.terminator().get_tree(n);
+    assert!(matches!(t.unwrap(), Some(Tree::Return(r)) if r == [v]));
+    c.flush();
+    validated(c.module());
+}
+/// `run(x)` adds both results of `pair(x) = (x, x)`, or returns `x` if it is zero, reaching
+/// `x` through an alias.
+#[test]
+fn from_statement_multi_value() {
+    let mut m = empty_module();
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32, Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let x = b.blocks[b.entry].params[0].1;
+    b.set_terminator(b.entry, Terminator::Return { values: vec![x, x] });
+    let pair = m.funcs.push(FuncDecl::Body(s, "pair".to_owned(), b));
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let b = FunctionBody::new(&m, s);
+    let entry = b.entry;
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let n = &mut c[FuncAndBlock {
+        func: run,
+        block: entry,
+    }];
+    let add = |n: &mut BlockRef<Waffle>, s| {
+        let v = <ValueDef as Statement<Waffle>>::from_statement(&s, n);
+        n.add(v.unwrap()).unwrap()
+    };
+    let x = add(n, Stmt::Param(0));
+    let call = Operator::Call {
+        function_index: pair,
+    };
+    let p = add(n, Stmt::Basic(call, vec![x]));
+    assert_eq!(n[p].type_of(n), [Type::I32, Type::I32]);
+    let (a, b) = (add(n, Stmt::Pick(p, 0)), add(n, Stmt::Pick(p, 1)));
+    let s = add(n, Stmt::Basic(Operator::I32Add, vec![a, b]));
+    let r = add(n, Stmt::Basic(Operator::Select, vec![s, x, x]));
+    assert_eq!(n[x].type_of(n), [Type::I32]);
+    assert_eq!(n[r].type_of(n), [Type::I32]);
+    // There is no memory to load from, and `pair` has no third result.
+    let memory = MemoryArg {
+        align: 2,
+        offset: 0,
+        memory: Memory::new(0),
+    };
+    let load = Stmt::Basic(Operator::I32Load { memory }, vec![x]);
+    // Both results of `pair` would fill the stack of `i32.add`, but need picking first.
+    let both = Stmt::Basic(Operator::I32Add, vec![p]);
+    for s in [load, Stmt::Pick(p, 2), both] {
+        let v = <ValueDef as Statement<Waffle>>::from_statement(&s, n);
+        assert!(matches!(v, Err(Error::Missing(..))));
+    }
+    *n.terminator_mut() = Terminator::Return { values: vec![r] };
+    c.flush();
+    let m = c.module();
+    validated(m);
+    let mut i = InterpContext::new(m).unwrap();
+    for x in [0u32, 7] {
+        let r = i.call(m, run, &[ConstVal::I32(x)]).ok().unwrap();
+        assert_eq!(&r[..], [ConstVal::I32(2 * x)]);
+    }
+}
+type Callee = FunCommon<Importd, BlockRef<Waffle>, FuncAndBlock>;
+/// `run(x, t)` computes `d = sub(10, x)` through a converted closure, then branches to
+/// `sub(10, d)` if `t` is `0` and `mul(d, d)` otherwise.
+#[test]
+fn closure_convert_and_defunctionalize() {
+    let mut m = empty_module();
+    let sub = binary(&mut m, Operator::I32Sub);
+    let mul = binary(&mut m, Operator::I32Mul);
+    let s = m.funcs[sub].sig();
+    let mut b = FunctionBody::new(&m, s);
+    let ten = append_i32(&mut b, Operator::I32Const { value: 10 }, &[]);
+    let entry = b.entry;
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let [sub, mul] = [sub, mul].map(|func| FuncAndBlock {
+        func,
+        block: c.module().funcs[func].body().unwrap().entry,
+    });
+    let k = FuncAndBlock {
+        func: run,
+        block: entry,
+    };
+    let n = &mut c[k];
+    let p = n.params().unwrap();
+    let (x, t) = (p[0].1, p[1].1);
+    let bound = || Callee::Bind(Box::new(Callee::Fun(sub)), vec![ten]);
+    let d = convert::<Waffle, _>(bound());
+    let d: ValueDef = d.call::<_, Error<FuncAndBlock>>(n, vec![x]).unwrap();
+    let d = n.add(d).unwrap();
+    let twice = Callee::Bind(Box::new(Callee::Fun(mul)), vec![d]);
+    let cases = vec![convert::<Waffle, _>(bound()), convert(twice)];
+    let no_shim = |_: &mut BlockRef<Waffle>, _| Err(());
+    let t = defunctionalize(n, t, cases, vec![d], no_shim).unwrap();
+    *n.terminator_mut() = t;
+    c.flush();
+    let m = c.module();
+    let mut i = InterpContext::new(m).unwrap();
+    for (x, t, r) in [(3, 0, 3), (3, 1, 49), (12, 0, 12)] {
+        let a = [ConstVal::I32(x), ConstVal::I32(t)];
+        assert_eq!(&i.call(m, run, &a).ok().unwrap()[..], [ConstVal::I32(r)]);
+    }
+    assert!(parse(&emit(c).unwrap()).is_ok());
+}
+#[test]
+fn defunctionalize_without_cases_fails() {
+    let mut m = empty_module();
+    let f = binary(&mut m, Operator::I32Add);
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let k = FuncAndBlock {
+        func: f,
+        block: c.module().funcs[f].body().unwrap().entry,
+    };
+    let n = &mut c[k];
+    let tag = n.params().unwrap()[0].1;
+    let no_shim = |_: &mut BlockRef<Waffle>, _: Importd| Err(());
+    let t = defunctionalize::<Waffle, _, _>(n, tag, vec![], vec![], no_shim);
+    assert_eq!(t.err(), Some(()));
+}
+/// Counts the additions [`PassVal`] turned into subtractions.
+struct Swaps(usize);
+type SwapCache = BasicModCodeCache<Swaps, Waffle, Waffle>;
+impl PassModule<Swaps, (), Waffle> for Waffle {
+    type ModCodeCache = SwapCache;
+}
+impl PassVal<Swaps, (), Waffle, Waffle> for ValueDef {
+    fn rewrite<C>(&self, k: &mut C, b: &mut FunRef<'_, Waffle, Waffle>) -> Result<ValueDef, ()>
+    where
+        C: CodeCache<Waffle, Value> + DerefMut<Target = SwapCache>,
+    {
+        let Stmt::Basic(o, args) = self.into_statement(b.src_fun()) else {
+            return Err(());
+        };
+        let o = match o {
+            Operator::I32Add => {
+                k.0 += 1;
+                Operator::I32Sub
+            }
+            o => o,
+        };
+        let mut a = vec![];
+        for v in args {
+            a.push(ValueDef::rewrite_id(k, b, v)?);
+        }
+        Ok(ValueDef::from_statement(&Stmt::Basic(o, a), b.fun_mut())?)
+    }
+}
+impl PassTerm<Swaps, (), Waffle, Waffle> for Terminator {
+    fn rewrite<C>(&self, k: &mut C, om: &mut FunRef<'_, Waffle, Waffle>) -> Result<Terminator, ()>
+    where
+        C: CodeCache<Waffle, Value> + DerefMut<Target = SwapCache>,
+    {
+        let Terminator::Return { values } = self else {
+            return Err(());
+        };
+        let mut v = vec![];
+        for x in values {
+            v.push(ValueDef::rewrite_id(k, om, *x)?);
+        }
+        Ok(Terminator::Return { values: v })
+    }
+}
+impl PassFun<Swaps, (), Waffle, Waffle> for BlockRef<Waffle> {
+    fn rewrite(&self, k: &mut SwapCache, om: &mut FunRef<'_, Waffle, Waffle>) -> Result<(), ()> {
+        rewrite_basic_fun(self, k, om)
+    }
+}
+impl PassDatum<Swaps, (), Waffle, Waffle> for ExportData {
+    fn rewrite(&self, _: &mut Swaps) -> Result<ExportData, ()> {
+        Ok(self.clone())
+    }
+}
+#[test]
+fn entity_rewrite_module() {
+    let mut lib = lib_module();
+    let g = lib.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(3),
+        mutable: false,
+    });
+    lib.exports.push(Export {
+        name: "g".to_owned(),
+        kind: ExportKind::Global(g),
+    });
+    let input = MFCache::from_inner(lib);
+    let mut out = MFCache::from_inner(empty_module());
+    let out = unsafe { out.as_mut().get_unchecked_mut() };
+    let mut k = SwapCache::new(Swaps(0));
+    rewrite_module(&*input, &mut k, out).unwrap();
+    assert_eq!(k.0, 1);
+    out.flush();
+    let m = out.module();
+    let inc = exported_func(m, "inc").unwrap();
+    assert_eq!(
+        ops(m, inc),
+        vec![Operator::I32Const { value: 1 }, Operator::I32Sub]
+    );
+    assert!(m.exports.iter().any(|e| e.name == "g"));
+    let s = &m.signatures[m.funcs[inc].sig()];
+    assert_eq!(
+        (&s.params[..], &s.returns[..]),
+        (&[Type::I32][..], &[Type::I32][..])
+    );
+    validated(m);
+}
+/// Reports a change to one function only.
+struct Touch(FuncAndBlock, Preserved);
+impl FunctionPass<Waffle, ()> for Touch {
+    fn name(&self) -> &str {
+        "touch"
+    }
+    fn preserves(&self) -> Preserved {
+        self.1.clone()
+    }
+    fn run(
+        &mut self,
+        _: &mut Waffle,
+        f: &FuncAndBlock,
+        _: &mut AnalysisCache<Waffle, ()>,
+    ) -> Result<bool, ()> {
+        Ok(*f == self.0)
+    }
+}
+/// Computes dominators from one function.
+struct Doms(FuncAndBlock);
+impl ModulePass<Waffle, ()> for Doms {
+    fn name(&self) -> &str {
+        "doms"
+    }
+    fn run(&mut self, m: &mut Waffle, am: &mut AnalysisCache<Waffle, ()>) -> Result<bool, ()> {
+        am.get::<Dominators<Waffle>>(m, &self.0)?;
+        Ok(false)
+    }
+}
+#[test]
+fn pass_manager_pipeline() {
+    let mut m = MFCache::from_inner(mod1());
+    let m = unsafe { m.as_mut().get_unchecked_mut() };
+    let (a, b, _) = two_blocks(m);
+    let mut pm = PassManager::new()
+        .function(Touch(b, Preserved::only(&["cfg"])))
+        .module(Doms(a));
+    assert_eq!(pm.run_pipeline(m, "doms"), Ok(false));
+    let misses = pm.analyses.misses;
+    let d = pm.analyses.get::<Dominators<Waffle>>(m, &a).unwrap();
+    assert_eq!(pm.analyses.misses, misses);
+    assert_eq!(d.idom(&b), Some(&a));
+    assert!(d.dominates(&a, &b) && !d.dominates(&b, &a));
+    assert_eq!(pm.run_pipeline(m, " touch ,doms"), Ok(true));
+    assert_eq!(pm.analyses.misses, misses + 1);
+    assert!(pm.analyses.contains("cfg", None));
+    assert_eq!(pm.stats["touch"].changed, 1);
+    assert_eq!(pm.stats["doms"].runs, 2);
+    assert_eq!(pm.run_pipeline(m, "touch,nope"), Err(()));
+    assert_eq!(pm.stats["touch"].runs, 1);
+    // Liveness covers the module, so every function shares one result.
+    let mut am = AnalysisCache::<Waffle, ()>::default();
+    for f in [a, b] {
+        am.get::<Liveness<Waffle>>(m, &f).unwrap();
+    }
+    assert_eq!((am.misses, am.hits), (1, 1));
+    assert!(am.contains("liveness", None));
+    let e = MFCache::from_inner(empty_module());
+    let mut am = AnalysisCache::<Waffle, ()>::default();
+    assert!(am.get_module::<Dominators<Waffle>>(&e).is_err());
+}
+/// `run(x)` with a block returning each of `keys`, then one returning `99`, and no terminator
+/// in its entry.
+fn switch_module(keys: &[u32]) -> (Module<'static>, waffle::Func, Vec<waffle::Block>) {
+    let mut m = empty_module();
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let mut blocks = vec![];
+    for k in keys.iter().chain([99].iter()) {
+        let l = b.add_block();
+        let t = b.single_type_list(Type::I32);
+        let v = b.add_value(ValueDef::Operator(
+            Operator::I32Const { value: *k },
+            Default::default(),
+            t,
+        ));
+        b.append_to_block(l, v);
+        b.set_terminator(l, Terminator::Return { values: vec![v] });
+        blocks.push(l);
+    }
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    (m, run, blocks)
+}
+#[test]
+fn switch_round_trip() {
+    for n in [0u32, 1, 2, 5] {
+        let keys: Vec<u32> = (0..n).collect();
+        let (m, run, blocks) = switch_module(&keys);
+        let mut c = MFCache::from_inner(m);
+        let c = unsafe { c.as_mut().get_unchecked_mut() };
+        let k = FuncAndBlock {
+            func: run,
+            block: c.module().funcs[run].body().unwrap().entry,
+        };
+        let n = &mut c[k];
+        let x = n.params().unwrap()[0].1;
+        let e = |block| Entry {
+            fun: FuncAndBlock { func: run, block },
+            args: vec![],
+        };
+        let (default, go) = blocks.split_last().unwrap();
+        let go: Vec<_> = go.iter().map(|b| e(*b)).collect();
+        let t: Result<Terminator, ()> = TreeTerminator::switch(n, x, go, e(*default));
+        *n.terminator_mut() = t.unwrap();
+        let t: Result<_, ()> = n.terminator().get_tree(n);
+        match t.unwrap().unwrap() {
+            Tree::Just(d) if keys.is_empty() => assert_eq!(d.fun.block, *default),
+            Tree::Switch(v, go, d) => {
+                assert_eq!(v, x);
+                let go: Vec<_> = go.iter().map(|e| e.fun.block).collect();
+                assert_eq!(&go[..], &blocks[..keys.len()]);
+                assert_eq!(d.fun.block, *default);
+            }
+            _ => panic!("the switch should come back as it was built"),
+        }
+        c.flush();
+        let m = c.module();
+        let mut i = InterpContext::new(m).unwrap();
+        for x in (0..keys.len() as u32 + 2).chain([u32::MAX]) {
+            let r = i.call(m, run, &[ConstVal::I32(x)]).ok().unwrap();
+            let want = if keys.contains(&x) { x } else { 99 };
+            assert_eq!(&r[..], [ConstVal::I32(want)]);
+        }
+        assert!(parse(&emit(c).unwrap()).is_ok());
+    }
+}
+/// Dense keys share a jump table, and the rest are found by a binary search.
+#[test]
+fn sparse_switch_lowering() {
+    let keys = [3, 4, 6, 7, 1000, 70000, u32::MAX];
+    let (m, run, blocks) = switch_module(&keys);
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let k = FuncAndBlock {
+        func: run,
+        block: c.module().funcs[run].body().unwrap().entry,
+    };
+    let n = &mut c[k];
+    let x = n.params().unwrap()[0].1;
+    let e = |block| Entry {
+        fun: FuncAndBlock { func: run, block },
+        args: vec![],
+    };
+    let mut cases: Vec<_> = keys.iter().zip(&blocks).map(|(k, b)| (*k, e(*b))).collect();
+    cases.push((4, e(blocks[0])));
+    let t = sparse_switch::<_, Error<FuncAndBlock>>(n, x, cases, e(blocks[keys.len()]));
+    *n.terminator_mut() = t.unwrap();
+    c.flush();
+    let m = c.module();
+    let selects = m.funcs[run]
+        .body()
+        .unwrap()
+        .blocks
+        .values()
+        .filter(|b| matches!(b.terminator, Terminator::Select { .. }))
+        .count();
+    assert_eq!(selects, 1);
+    let mut i = InterpContext::new(m).unwrap();
+    for x in [
+        0,
+        2,
+        3,
+        4,
+        5,
+        6,
+        7,
+        8,
+        999,
+        1000,
+        1001,
+        70000,
+        u32::MAX - 1,
+        u32::MAX,
+    ] {
+        let r = i.call(m, run, &[ConstVal::I32(x)]).ok().unwrap();
+        let want = if keys.contains(&x) { x } else { 99 };
+        assert_eq!(&r[..], [ConstVal::I32(want)], "key {x}");
+    }
+    assert!(parse(&emit(c).unwrap()).is_ok());
+}
+/// One of each waffle operator, using the entities of [`op_module`].
+fn every_operator(m: &Module<'static>) -> Vec<Operator> {
+    use Operator as O;
+    let (f, s) = (
+        m.funcs.iter().next().unwrap(),
+        m.signatures.iter().next().unwrap(),
+    );
+    let (g, t) = (
+        m.globals.iter().next().unwrap(),
+        m.tables.iter().next().unwrap(),
+    );
+    let mem = m.memories.iter().next().unwrap();
+    let memory = MemoryArg {
+        align: 0,
+        offset: 0,
+        memory: mem,
+    };
+    vec![
+        O::Unreachable,
+        O::Nop,
+        O::Call { function_index: f },
+        O::CallIndirect {
+            sig_index: s,
+            table_index: t,
+        },
+        O::Select,
+        O::TypedSelect { ty: Type::I32 },
+        O::GlobalGet { global_index: g },
+        O::GlobalSet { global_index: g },
+        O::I32Load { memory },
+        O::I64Load { memory },
+        O::F32Load { memory },
+        O::F64Load { memory },
+        O::I32Load8S { memory },
+        O::I32Load8U { memory },
+        O::I32Load16S { memory },
+        O::I32Load16U { memory },
+        O::I64Load8S { memory },
+        O::I64Load8U { memory },
+        O::I64Load16S { memory },
+        O::I64Load16U { memory },
+        O::I64Load32S { memory },
+        O::I64Load32U { memory },
+        O::I32Store { memory },
+        O::I64Store { memory },
+        O::F32Store { memory },
+        O::F64Store { memory },
+        O::I32Store8 { memory },
+        O::I32Store16 { memory },
+        O::I64Store8 { memory },
+        O::I64Store16 { memory },
+        O::I64Store32 { memory },
+        O::I32Const { value: 0 },
+        O::I64Const { value: 0 },
+        O::F32Const { value: 0 },
+        O::F64Const { value: 0 },
+        O::I32Eqz,
+        O::I32Eq,
+        O::I32Ne,
+        O::I32LtS,
+        O::I32LtU,
+        O::I32GtS,
+        O::I32GtU,
+        O::I32LeS,
+        O::I32LeU,
+        O::I32GeS,
+        O::I32GeU,
+        O::I64Eqz,
+        O::I64Eq,
+        O::I64Ne,
+        O::I64LtS,
+        O::I64LtU,
+        O::I64GtU,
+        O::I64GtS,
+        O::I64LeS,
+        O::I64LeU,
+        O::I64GeS,
+        O::I64GeU,
+        O::F32Eq,
+        O::F32Ne,
+        O::F32Lt,
+        O::F32Gt,
+        O::F32Le,
+        O::F32Ge,
+        O::F64Eq,
+        O::F64Ne,
+        O::F64Lt,
+        O::F64Gt,
+        O::F64Le,
+        O::F64Ge,
+        O::I32Clz,
+        O::I32Ctz,
+        O::I32Popcnt,
+        O::I32Add,
+        O::I32Sub,
+        O::I32Mul,
+        O::I32DivS,
+        O::I32DivU,
+        O::I32RemS,
+        O::I32RemU,
+        O::I32And,
+        O::I32Or,
+        O::I32Xor,
+        O::I32Shl,
+        O::I32ShrS,
+        O::I32ShrU,
+        O::I32Rotl,
+        O::I32Rotr,
+        O::I64Clz,
+        O::I64Ctz,
+        O::I64Popcnt,
+        O::I64Add,
+        O::I64Sub,
+        O::I64Mul,
+        O::I64DivS,
+        O::I64DivU,
+        O::I64RemS,
+        O::I64RemU,
+        O::I64And,
+        O::I64Or,
+        O::I64Xor,
+        O::I64Shl,
+        O::I64ShrS,
+        O::I64ShrU,
+        O::I64Rotl,
+        O::I64Rotr,
+        O::F32Abs,
+        O::F32Neg,
+        O::F32Ceil,
+        O::F32Floor,
+        O::F32Trunc,
+        O::F32Nearest,
+        O::F32Sqrt,
+        O::F32Add,
+        O::F32Sub,
+        O::F32Mul,
+        O::F32Div,
+        O::F32Min,
+        O::F32Max,
+        O::F32Copysign,
+        O::F64Abs,
+        O::F64Neg,
+        O::F64Ceil,
+        O::F64Floor,
+        O::F64Trunc,
+        O::F64Nearest,
+        O::F64Sqrt,
+        O::F64Add,
+        O::F64Sub,
+        O::F64Mul,
+        O::F64Div,
+        O::F64Min,
+        O::F64Max,
+        O::F64Copysign,
+        O::I32WrapI64,
+        O::I32TruncF32S,
+        O::I32TruncF32U,
+        O::I32TruncF64S,
+        O::I32TruncF64U,
+        O::I64ExtendI32S,
+        O::I64ExtendI32U,
+        O::I64TruncF32S,
+        O::I64TruncF32U,
+        O::I64TruncF64S,
+        O::I64TruncF64U,
+        O::F32ConvertI32S,
+        O::F32ConvertI32U,
+        O::F32ConvertI64S,
+        O::F32ConvertI64U,
+        O::F32DemoteF64,
+        O::F64ConvertI32S,
+        O::F64ConvertI32U,
+        O::F64ConvertI64S,
+        O::F64ConvertI64U,
+        O::F64PromoteF32,
+        O::I32Extend8S,
+        O::I32Extend16S,
+        O::I64Extend8S,
+        O::I64Extend16S,
+        O::I64Extend32S,
+        O::I32TruncSatF32S,
+        O::I32TruncSatF32U,
+        O::I32TruncSatF64S,
+        O::I32TruncSatF64U,
+        O::I64TruncSatF32S,
+        O::I64TruncSatF32U,
+        O::I64TruncSatF64S,
+        O::I64TruncSatF64U,
+        O::F32ReinterpretI32,
+        O::F64ReinterpretI64,
+        O::I32ReinterpretF32,
+        O::I64ReinterpretF64,
+        O::TableGet { table_index: t },
+        O::TableSet { table_index: t },
+        O::TableGrow { table_index: t },
+        O::TableSize { table_index: t },
+        O::MemorySize { mem },
+        O::MemoryGrow { mem },
+    ]
+}
+/// A function `(i32) -> (i64, f32)`, an `f64` global, a table and a memory.
+fn op_module() -> Module<'static> {
+    let mut m = empty_module();
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I64, Type::F32],
+    });
+    let b = FunctionBody::new(&m, s);
+    m.funcs.push(FuncDecl::Body(s, "f".to_owned(), b));
+    m.globals.push(GlobalData {
+        ty: Type::F64,
+        value: None,
+        mutable: true,
+    });
+    m.tables.push(TableData {
+        ty: Type::FuncRef,
+        max: None,
+        func_elements: Some(vec![]),
+    });
+    m.memories.push(MemoryData {
+        initial_pages: 1,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    m
+}
+/// Operand types of a numeric operator, from its name, or `None` for other operators.
+fn numeric_inputs(o: &Operator) -> Option<Vec<Type>> {
+    let name = format!("{o:?}");
+    let ty = |s: &str| match s {
+        "I32" => Some(Type::I32),
+        "I64" => Some(Type::I64),
+        "F32" => Some(Type::F32),
+        "F64" => Some(Type::F64),
+        _ => None,
+    };
+    let t = ty(name.get(..3)?)?;
+    let rest = &name[3..];
+    if rest.starts_with("Const") || rest.contains("Load") || rest.contains("Store") {
+        return None;
+    }
+    // Conversions name their operand's type last, as in `F32ConvertI64U`.
+    let tail = rest.trim_end_matches(['S', 'U']);
+    if let Some(u) = tail.get(tail.len().saturating_sub(3)..).and_then(ty) {
+        return Some(vec![u]);
+    }
+    let unary = [
+        "Eqz", "Clz", "Ctz", "Popcnt", "Abs", "Neg", "Ceil", "Floor", "Trunc", "Nearest", "Sqrt",
+        "Extend",
+    ];
+    match unary.iter().any(|u| rest.starts_with(u)) {
+        true => Some(vec![t]),
+        false => Some(vec![t, t]),
+    }
+}
+/// Checks `op_outputs` against the interpreter for every numeric operator, and by hand for
+/// the others.
+#[test]
+fn op_outputs_every_operator() {
+    let m = op_module();
+    let one = |t: Type| match t {
+        Type::I32 => ConstVal::I32(1),
+        Type::I64 => ConstVal::I64(1),
+        Type::F32 => ConstVal::F32(1f32.to_bits()),
+        _ => ConstVal::F64(1f64.to_bits()),
+    };
+    let type_of = |c: ConstVal| match c {
+        ConstVal::I32(_) => Type::I32,
+        ConstVal::I64(_) => Type::I64,
+        ConstVal::F32(_) => Type::F32,
+        _ => Type::F64,
+    };
+    let v = Value::new(0);
+    let mut evaluated = 0;
+    for o in every_operator(&m) {
+        // Memory accesses take their address first.
+        let stack = match numeric_inputs(&o) {
+            Some(tys) => tys,
+            None if memory_of(&o).is_some() => vec![Type::I32, Type::F64, Type::I32],
+            None => vec![Type::F64, Type::F64, Type::I32],
+        };
+        let stack: Vec<_> = stack.into_iter().map(|t| (t, v)).collect();
+        let out = op_outputs(&m, &stack, &o).unwrap();
+        if numeric_inputs(&o).is_some() {
+            let vals: Vec<_> = stack.iter().map(|a| one(a.0)).collect();
+            let r = const_eval(&o, &vals, None).unwrap();
+            assert_eq!(&out[..], [type_of(r)], "{o}");
+            evaluated += 1;
+        }
+    }
+    assert!(evaluated > 100, "only {evaluated} operators were checked");
+    let f = m.funcs.iter().next().unwrap();
+    let t = m.tables.iter().next().unwrap();
+    let g = m.globals.iter().next().unwrap();
+    let memory = MemoryArg {
+        align: 0,
+        offset: 0,
+        memory: m.memories.iter().next().unwrap(),
+    };
+    let stack = [(Type::F64, v), (Type::F64, v), (Type::I32, v)];
+    let access = [(Type::I32, v), (Type::F32, v)];
+    for (o, want) in [
+        (
+            Operator::Call { function_index: f },
+            &[Type::I64, Type::F32][..],
+        ),
+        (Operator::Select, &[Type::F64]),
+        (Operator::TypedSelect { ty: Type::F32 }, &[Type::F32]),
+        (Operator::I64Load8U { memory }, &[Type::I64]),
+        (Operator::F32Store { memory }, &[]),
+        (Operator::TableGrow { table_index: t }, &[Type::I32]),
+        (Operator::TableGet { table_index: t }, &[Type::FuncRef]),
+        (Operator::GlobalGet { global_index: g }, &[Type::F64]),
+        (Operator::GlobalSet { global_index: g }, &[]),
+    ] {
+        let stack = match memory_of(&o) {
+            Some(_) => &access[..],
+            None => &stack[..],
+        };
+        assert_eq!(&op_outputs(&m, stack, &o).unwrap()[..], want, "{o}");
+    }
+}
+/// Accesses are typed and checked by the memory they name, which need not be the first.
+#[test]
+fn multi_memory_access() {
+    let mut m = op_module();
+    let mem = m.memories.push(MemoryData {
+        initial_pages: 2,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    let v = Value::new(0);
+    let memory = MemoryArg {
+        align: 2,
+        offset: 0,
+        memory: mem,
+    };
+    let load = Operator::I32Load { memory };
+    assert!(check_memory_access(&m, &[(Type::I32, v)], &load));
+    assert!(!check_memory_access(&m, &[(Type::I64, v)], &load));
+    assert!(op_outputs(&m, &[(Type::I64, v)], &load).is_err());
+    assert!(!check_memory_access(&m, &[], &load));
+    let size = Operator::MemorySize { mem };
+    assert!(check_memory_access(&m, &[], &size));
+    assert_eq!(&op_outputs(&m, &[], &size).unwrap()[..], [Type::I32]);
+    let missing = Operator::MemoryGrow {
+        mem: Memory::new(2),
+    };
+    assert!(!check_memory_access(&m, &[(Type::I32, v)], &missing));
+    let mut e = EntityMap::default();
+    let first = m.memories.iter().next().unwrap();
+    e.memories.insert(mem, first);
+    let mut o = load;
+    tweak_operator(&mut o, &e);
+    assert_eq!(memory_of(&o), Some(first));
+    let c = MFCache::from_inner(m);
+    assert!(c.data_keys().contains(&ExportKey::Memory(mem)));
+    let ExportData::Memory(d) = c.create_export(ExportKey::Memory(mem)) else {
+        panic!("a memory should export as one");
+    };
+    assert_eq!(d.initial_pages, 2);
+}
+/// A loop over `0..100` indexes a one-page memory, and a parameter addresses it unchecked.
+#[test]
+fn bounds_check_loop() {
+    let mut m = empty_module();
+    let mem = m.memories.push(MemoryData {
+        initial_pages: 1,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let memory = |offset| MemoryArg {
+        align: 2,
+        offset,
+        memory: mem,
+    };
+    let load = |offset| Operator::I32Load {
+        memory: memory(offset),
+    };
+    let entry = b.entry;
+    let p = b.blocks[entry].params[0].1;
+    let (header, body, exit) = (b.add_block(), b.add_block(), b.add_block());
+    let i = b.add_blockparam(header, Type::I32);
+    let zero = append_i32_to(&mut b, entry, Operator::I32Const { value: 0 }, &[]);
+    let target = |block, args| BlockTarget { block, args };
+    b.set_terminator(
+        entry,
+        Terminator::Br {
+            target: target(header, vec![zero]),
+        },
+    );
+    let n = append_i32_to(&mut b, header, Operator::I32Const { value: 100 }, &[]);
+    let lt = append_i32_to(&mut b, header, Operator::I32LtU, &[i, n]);
+    b.set_terminator(
+        header,
+        Terminator::CondBr {
+            cond: lt,
+            if_true: target(body, vec![]),
+            if_false: target(exit, vec![]),
+        },
+    );
+    let four = append_i32_to(&mut b, body, Operator::I32Const { value: 4 }, &[]);
+    let a = append_i32_to(&mut b, body, Operator::I32Mul, &[i, four]);
+    let at_a = append_i32_to(&mut b, body, load(0), &[a]);
+    let next = append_i32_to(&mut b, body, Operator::I32Add, &[a, four]);
+    let at_next = append_i32_to(&mut b, body, load(0), &[next]);
+    let past = append_i32_to(&mut b, body, load(65536), &[a]);
+    let one = append_i32_to(&mut b, body, Operator::I32Const { value: 1 }, &[]);
+    let i2 = append_i32_to(&mut b, body, Operator::I32Add, &[i, one]);
+    b.set_terminator(
+        body,
+        Terminator::Br {
+            target: target(header, vec![i2]),
+        },
+    );
+    let at_p = append_i32_to(&mut b, exit, load(0), &[p]);
+    let at_p8 = append_i32_to(&mut b, exit, load(8), &[p]);
+    let store = Operator::I32Store { memory: memory(0) };
+    let args = b.arg_pool.from_iter([p, at_p8].into_iter());
+    let stored = b.add_value(ValueDef::Operator(store, args, Default::default()));
+    b.append_to_block(exit, stored);
+    let again = append_i32_to(&mut b, exit, load(0), &[p]);
+    b.set_terminator(
+        exit,
+        Terminator::Return {
+            values: vec![again],
+        },
+    );
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let c = MFCache::from_inner(m);
+    let r = bounds_checks::<_, Error<FuncAndBlock>>(&c).unwrap();
+    let proven: BTreeSet<Value> = r.proven().map(|a| a.value).collect();
+    assert_eq!(proven, BTreeSet::from([at_a, at_next]));
+    let split = r.accesses.iter().find(|a| a.value == at_next).unwrap();
+    assert_eq!((split.base, split.offset), (a, 4));
+    let checks: Vec<_> = r
+        .checks
+        .iter()
+        .map(|c| (c.fun.func, c.before, c.base, c.end, c.accesses.clone()))
+        .collect();
+    assert_eq!(
+        checks,
+        [
+            (run, past, a, 65540, vec![past]),
+            (run, at_p, p, 12, vec![at_p, at_p8, stored]),
+            (run, again, p, 4, vec![again]),
+        ]
+    );
+}
+/// A bound learnt on only one way into a block does not hold in it.
+#[test]
+fn bounds_check_diamond() {
+    let mut m = empty_module();
+    let mem = m.memories.push(MemoryData {
+        initial_pages: 1,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let entry = b.entry;
+    let x = b.blocks[entry].params[0].1;
+    let (left, right, join) = (b.add_block(), b.add_block(), b.add_block());
+    let ten = append_i32_to(&mut b, entry, Operator::I32Const { value: 10 }, &[]);
+    let lt = append_i32_to(&mut b, entry, Operator::I32LtU, &[x, ten]);
+    let target = |block| BlockTarget {
+        block,
+        args: vec![],
+    };
+    b.set_terminator(
+        entry,
+        Terminator::CondBr {
+            cond: lt,
+            if_true: target(left),
+            if_false: target(right),
+        },
+    );
+    b.set_terminator(
+        left,
+        Terminator::Br {
+            target: target(join),
+        },
+    );
+    b.set_terminator(
+        right,
+        Terminator::Br {
+            target: target(join),
+        },
+    );
+    let memory = MemoryArg {
+        align: 2,
+        offset: 0,
+        memory: mem,
+    };
+    let at_x = append_i32_to(&mut b, join, Operator::I32Load { memory }, &[x]);
+    b.set_terminator(join, Terminator::Return { values: vec![at_x] });
+    m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let c = MFCache::from_inner(m);
+    let r = bounds_checks::<_, Error<FuncAndBlock>>(&c).unwrap();
+    let a = r.accesses.iter().find(|a| a.value == at_x).unwrap();
+    assert!(!a.in_bounds);
+    assert_eq!(r.proven().count(), 0);
+}
+/// Stores and loads through two addresses which may be the same, around a `memory.grow`.
+#[test]
+fn load_store_elimination() {
+    let mut m = empty_module();
+    let mem = m.memories.push(MemoryData {
+        initial_pages: 1,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32, Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let memory = MemoryArg {
+        align: 2,
+        offset: 0,
+        memory: mem,
+    };
+    let l = b.entry;
+    let (p, q) = (b.blocks[l].params[0].1, b.blocks[l].params[1].1);
+    let load = Operator::I32Load { memory };
+    let store = |b: &mut FunctionBody, at, w| {
+        let args = b.arg_pool.from_iter([at, w].into_iter());
+        let o = Operator::I32Store { memory };
+        let v = b.add_value(ValueDef::Operator(o, args, Default::default()));
+        b.append_to_block(l, v);
+        v
+    };
+    let one = append_i32_to(&mut b, l, Operator::I32Const { value: 1 }, &[]);
+    let two = append_i32_to(&mut b, l, Operator::I32Const { value: 2 }, &[]);
+    let overwritten = store(&mut b, p, one);
+    store(&mut b, p, two);
+    let reload = append_i32_to(&mut b, l, load, &[p]);
+    let byte = append_i32_to(&mut b, l, Operator::I32Load8U { memory }, &[p]);
+    store(&mut b, q, byte);
+    let after_q = append_i32_to(&mut b, l, load, &[p]);
+    let again = append_i32_to(&mut b, l, load, &[p]);
+    append_i32_to(&mut b, l, Operator::MemoryGrow { mem }, &[one]);
+    let grown = append_i32_to(&mut b, l, load, &[p]);
+    let mut sum = reload;
+    for v in [byte, after_q, again, grown] {
+        let w = append_i32_to(&mut b, l, Operator::I32Mul, &[sum, two]);
+        sum = append_i32_to(&mut b, l, Operator::I32Add, &[w, v]);
+    }
+    b.set_terminator(l, Terminator::Return { values: vec![sum] });
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let args = [[0, 0], [0, 8], [8, 0]].map(|a| a.map(ConstVal::I32));
+    let results = |m: &Module<'static>| -> Vec<_> {
+        args.iter()
+            .map(|a| InterpContext::new(m).unwrap().call(m, run, a).ok().unwrap())
+            .collect()
+    };
+    let before = results(&m);
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let k = FuncAndBlock {
+        func: run,
+        block: l,
+    };
+    let mut pass = LoadStore::default();
+    assert_eq!(pass.run_fun::<Waffle>(&mut c[k]), 3);
+    assert_eq!((pass.forwarded, pass.dead_stores), (2, 1));
+    let insts = &c.module().funcs[run].body().unwrap().blocks[l].insts;
+    assert!(!insts.contains(&overwritten) && !insts.contains(&reload) && !insts.contains(&again));
+    assert!(insts.contains(&after_q) && insts.contains(&grown));
+    c.flush();
+    assert_eq!(results(c.module()), before);
+    assert_eq!(pass.run_fun::<Waffle>(&mut c[k]), 0);
+}
+/// A store overwritten after a `global.set` stays, as removing it would let an out of bounds
+/// address trap only after the global changed.
+#[test]
+fn load_store_keeps_store_before_write() {
+    let mut m = empty_module();
+    let mem = m.memories.push(MemoryData {
+        initial_pages: 1,
+        maximum_pages: None,
+        segments: vec![],
+    });
+    let g = m.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(0),
+        mutable: true,
+    });
+    let s = m.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![],
+    });
+    let mut b = FunctionBody::new(&m, s);
+    let l = b.entry;
+    let p = b.blocks[l].params[0].1;
+    let store = |b: &mut FunctionBody, w| {
+        let memory = MemoryArg {
+            align: 2,
+            offset: 0,
+            memory: mem,
+        };
+        let args = b.arg_pool.from_iter([p, w].into_iter());
+        let o = Operator::I32Store { memory };
+        let v = b.add_value(ValueDef::Operator(o, args, Default::default()));
+        b.append_to_block(l, v);
+        v
+    };
+    let one = append_i32_to(&mut b, l, Operator::I32Const { value: 1 }, &[]);
+    let first = store(&mut b, one);
+    let args = b.arg_pool.from_iter([one].into_iter());
+    let set = b.add_value(ValueDef::Operator(
+        Operator::GlobalSet { global_index: g },
+        args,
+        Default::default(),
+    ));
+    b.append_to_block(l, set);
+    store(&mut b, one);
+    b.set_terminator(l, Terminator::Return { values: vec![] });
+    let run = m.funcs.push(FuncDecl::Body(s, "run".to_owned(), b));
+    let mut c = MFCache::from_inner(m);
+    let c = unsafe { c.as_mut().get_unchecked_mut() };
+    let k = FuncAndBlock {
+        func: run,
+        block: l,
+    };
+    let mut pass = LoadStore::default();
+    assert_eq!(pass.run_fun::<Waffle>(&mut c[k]), 0);
+    c.flush();
+    assert!(c.module().funcs[run].body().unwrap().blocks[l]
+        .insts
+        .contains(&first));
+}
@@ -18,17 +18,38 @@ pub enum FunCommon<X, F: FunLike, K> {
 
 pub trait R<Err> {
     type Ty;
-    fn r(self) -> Result<Self::Ty, Err>;
+    fn r(self) -> Result<Self::Ty, Err>
+    where
+        Err: Default;
+    /// Like [`R::r`], failing with `e`, typically an [`Error`](crate::error::Error) saying what
+    /// is missing and where.
+    fn r_or(self, e: impl Into<Err>) -> Result<Self::Ty, Err>;
+    /// Like [`R::r_or`], only building the error on failure, for errors that allocate.
+    fn r_or_else<E: Into<Err>>(self, e: impl FnOnce() -> E) -> Result<Self::Ty, Err>;
 }
-impl<Err: Default, T> R<Err> for Option<T> {
+impl<Err, T> R<Err> for Option<T> {
     type Ty = T;
 
-    fn r(self) -> Result<Self::Ty, Err> {
+    fn r(self) -> Result<Self::Ty, Err>
+    where
+        Err: Default,
+    {
+        self.r_or(Err::default())
+    }
+
+    fn r_or(self, e: impl Into<Err>) -> Result<Self::Ty, Err> {
         return match self {
             Some(a) => Ok(a),
-            None => Err(Default::default()),
+            None => Err(e.into()),
         };
     }
+
+    fn r_or_else<E: Into<Err>>(self, e: impl FnOnce() -> E) -> Result<Self::Ty, Err> {
+        match self {
+            Some(a) => Ok(a),
+            None => Err(e().into()),
+        }
+    }
 }
 pub fn my_hash<T>(obj: T) -> u64
 where
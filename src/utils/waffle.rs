@@ -1,8 +1,14 @@
 use std::collections::BTreeMap;
 
 use waffle::{
-    cfg::CFGInfo, pool::ListRef, Block, BlockTarget, FrontendOptions, Func, FunctionBody, Memory,
-    MemoryArg, Module, Operator, Signature, SignatureData, Terminator, Type, Value, ValueDef,
+    cfg::CFGInfo, pool::ListRef, Block, BlockTarget, FrontendOptions, Func, FunctionBody, Global,
+    Memory, MemoryArg, Module, Operator, Signature, SignatureData, Table, Terminator, Type, Value,
+    ValueDef, ImportKind, entity::EntityRef,
+};
+
+use crate::compat::waffle::{
+    base::{GetModule, MFCache},
+    link::Linker,
 };
 
 pub fn tweak_value(
@@ -28,7 +34,7 @@ pub fn tweak_value(
                 m(v)
             }
         }
-        ValueDef::None => todo!(),
+        ValueDef::None => {}
     }
 }
 pub fn tweak_target(
@@ -79,6 +85,119 @@ pub fn tweak_terminator(
         Terminator::None => {}
     }
 }
+/// Entity renumbering from one module into another. Unmapped entities keep their index.
+#[derive(Default, Clone, Debug)]
+pub struct EntityMap {
+    pub funcs: BTreeMap<Func, Func>,
+    pub signatures: BTreeMap<Signature, Signature>,
+    pub globals: BTreeMap<Global, Global>,
+    pub tables: BTreeMap<Table, Table>,
+    pub memories: BTreeMap<Memory, Memory>,
+}
+impl EntityMap {
+    pub fn func(&self, a: Func) -> Func {
+        *self.funcs.get(&a).unwrap_or(&a)
+    }
+    pub fn signature(&self, a: Signature) -> Signature {
+        *self.signatures.get(&a).unwrap_or(&a)
+    }
+    pub fn global(&self, a: Global) -> Global {
+        *self.globals.get(&a).unwrap_or(&a)
+    }
+    pub fn table(&self, a: Table) -> Table {
+        *self.tables.get(&a).unwrap_or(&a)
+    }
+    pub fn memory(&self, a: Memory) -> Memory {
+        *self.memories.get(&a).unwrap_or(&a)
+    }
+}
+pub fn tweak_operator(x: &mut Operator, m: &EntityMap) {
+    match x {
+        Operator::Call { function_index } => *function_index = m.func(*function_index),
+        Operator::CallIndirect {
+            sig_index,
+            table_index,
+        } => {
+            *sig_index = m.signature(*sig_index);
+            *table_index = m.table(*table_index);
+        }
+        Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+            *global_index = m.global(*global_index)
+        }
+        Operator::TableGet { table_index }
+        | Operator::TableSet { table_index }
+        | Operator::TableGrow { table_index }
+        | Operator::TableSize { table_index } => *table_index = m.table(*table_index),
+        o => {
+            if let Some(a) = memory_mut(o) {
+                *a = m.memory(*a);
+            }
+        }
+    }
+}
+/// The memory `o` accesses, if any.
+pub fn memory_mut(o: &mut Operator) -> Option<&mut Memory> {
+    match o {
+        Operator::MemorySize { mem } | Operator::MemoryGrow { mem } => Some(mem),
+        Operator::I32Load { memory }
+        | Operator::I64Load { memory }
+        | Operator::F32Load { memory }
+        | Operator::F64Load { memory }
+        | Operator::I32Load8S { memory }
+        | Operator::I32Load8U { memory }
+        | Operator::I32Load16S { memory }
+        | Operator::I32Load16U { memory }
+        | Operator::I64Load8S { memory }
+        | Operator::I64Load8U { memory }
+        | Operator::I64Load16S { memory }
+        | Operator::I64Load16U { memory }
+        | Operator::I64Load32S { memory }
+        | Operator::I64Load32U { memory }
+        | Operator::I32Store { memory }
+        | Operator::I64Store { memory }
+        | Operator::F32Store { memory }
+        | Operator::F64Store { memory }
+        | Operator::I32Store8 { memory }
+        | Operator::I32Store16 { memory }
+        | Operator::I64Store8 { memory }
+        | Operator::I64Store16 { memory }
+        | Operator::I64Store32 { memory } => Some(&mut memory.memory),
+        _ => None,
+    }
+}
+/// Like [`memory_mut`].
+pub fn memory_of(o: &Operator) -> Option<Memory> {
+    memory_mut(&mut o.clone()).copied()
+}
+/// Type of addresses, sizes and page counts in `mem`.
+///
+/// waffle has no 64-bit memories, so this is always `i32` for now; typing goes through here so
+/// that memory64 only needs to change it.
+pub fn address_type(_: &Module, _: Memory) -> Type {
+    Type::I32
+}
+/// Whether the memory `o` accesses, if any, exists in `m`, and its address or page count
+/// operand, first on `op_stack`, has that memory's [`address_type`].
+pub fn check_memory_access(m: &Module, op_stack: &[(Type, Value)], o: &Operator) -> bool {
+    let Some(mem) = memory_of(o) else {
+        return true;
+    };
+    if mem.index() >= m.memories.len() {
+        return false;
+    }
+    match o {
+        Operator::MemorySize { .. } => true,
+        _ => op_stack.first().is_some_and(|a| a.0 == address_type(m, mem)),
+    }
+}
+/// Renumbers every entity referenced by the operators of `f`.
+pub fn tweak_body(f: &mut FunctionBody, m: &EntityMap) {
+    for v in f.values.values_mut() {
+        if let ValueDef::Operator(o, _, _) = v {
+            tweak_operator(o, m);
+        }
+    }
+}
 pub fn clone_value(
     basis: &FunctionBody,
     f: &mut FunctionBody,
@@ -87,6 +206,15 @@ pub fn clone_value(
     b: Block,
 ) -> Value {
     let mut w = basis.values.get(v).unwrap().clone();
+    // Lists point into the pools of `basis`; `tweak_value` copies them within `f`.
+    match &mut w {
+        ValueDef::Operator(_, l, t) => {
+            *l = f.arg_pool.from_iter(basis.arg_pool[*l].iter().cloned());
+            *t = f.type_pool.from_iter(basis.type_pool[*t].iter().cloned());
+        }
+        ValueDef::Trace(_, l) => *l = f.arg_pool.from_iter(basis.arg_pool[*l].iter().cloned()),
+        _ => {}
+    }
     tweak_value(f, &mut w, m, b);
     return f.add_value(w);
 }
@@ -156,3 +284,62 @@ pub fn parse(a: &[u8]) -> anyhow::Result<waffle::Module<'static>> {
     m.expand_all_funcs()?;
     return Ok(m.without_orig_bytes());
 }
+/// Whether imported entities precede defined ones, in import order, as the backend requires.
+pub fn imports_first(m: &Module) -> bool {
+    let mut n = [0usize; 4];
+    m.imports.iter().all(|i| {
+        let (k, x) = match i.kind {
+            ImportKind::Func(f) => (0, f.index()),
+            ImportKind::Table(t) => (1, t.index()),
+            ImportKind::Global(g) => (2, g.index()),
+            ImportKind::Memory(a) => (3, a.index()),
+        };
+        n[k] += 1;
+        x + 1 == n[k]
+    }) && m.funcs.entries().filter(|a| a.1.body().is_none()).count() == n[0]
+}
+/// Inverse of [`parse`]: flushes `m` and emits it, relinking first if imports come after
+/// definitions, as they do once [`Call`](crate::compat::call::Call) added one.
+///
+/// Edges are recomputed, as terminators set through the compat traits do not record them.
+pub fn emit<M: GetModule>(m: &mut MFCache<M>) -> anyhow::Result<Vec<u8>> {
+    m.flush();
+    for f in m.module_mut().funcs.values_mut() {
+        if let Some(b) = f.body_mut() {
+            b.recompute_edges();
+        }
+    }
+    if imports_first(m.module()) {
+        return m.module().to_wasm_bytes();
+    }
+    let l = Linker::new().module("", m.module().clone().without_orig_bytes());
+    l.emit()
+}
+/// Values produced by `v`, typed `tys`, picking outputs apart when there are several.
+pub fn pick_outputs(f: &mut FunctionBody, b: Block, v: Value, tys: &[Type]) -> Vec<Value> {
+    if tys.len() == 1 {
+        return vec![v];
+    }
+    let mut all = vec![];
+    for (i, t) in tys.iter().enumerate() {
+        let p = f.add_value(ValueDef::PickOutput(v, i as u32, *t));
+        f.append_to_block(b, p);
+        all.push(p);
+    }
+    all
+}
+/// Appends a direct call to `callee` to `b`, returning its results.
+pub fn append_call(m: &Module, f: &mut FunctionBody, b: Block, callee: Func, args: Vec<Value>) -> Vec<Value> {
+    let rets = m.signatures[m.funcs[callee].sig()].returns.clone();
+    let args = f.arg_pool.from_iter(args.into_iter());
+    let tys = f.type_pool.from_iter(rets.iter().cloned());
+    let v = f.add_value(ValueDef::Operator(
+        Operator::Call {
+            function_index: callee,
+        },
+        args,
+        tys,
+    ));
+    f.append_to_block(b, v);
+    pick_outputs(f, b, v, &rets)
+}
@@ -1,11 +1,21 @@
-use std::{convert::Infallible, borrow::Cow};
+use std::borrow::Cow;
 
 use waffle::*;
+
+use super::check_memory_access;
+/// Result types of `op`, after waffle's own, which is private.
+///
+/// This covers every operator of the pinned waffle, which has no SIMD, bulk memory, atomics or
+/// reference type operators. The match has no wildcard, so new operators fail exhaustiveness.
+/// Memory accesses failing [`check_memory_access`] are errors.
 pub fn op_outputs(
     module: &Module,
     op_stack: &[(Type, Value)],
     op: &Operator,
-) -> Result<Cow<'static, [Type]>,Infallible> {
+) -> anyhow::Result<Cow<'static, [Type]>> {
+    if !check_memory_access(module, op_stack, op) {
+        anyhow::bail!("bad memory access {op}");
+    }
     match op {
         &Operator::Unreachable | &Operator::Nop => Ok(Cow::Borrowed(&[])),
 
@@ -198,9 +208,11 @@ pub fn op_outputs(
         Operator::I64ReinterpretF64 => Ok(Cow::Borrowed(&[Type::I64])),
         Operator::TableGet { table_index } => Ok(vec![module.tables[*table_index].ty].into()),
         Operator::TableSet { .. } => Ok(Cow::Borrowed(&[])),
-        Operator::TableGrow { .. } => Ok(Cow::Borrowed(&[])),
+        // The previous size, which waffle itself leaves out.
+        Operator::TableGrow { .. } => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::TableSize { .. } => Ok(Cow::Borrowed(&[Type::I32])),
-        Operator::MemorySize { .. } => Ok(Cow::Borrowed(&[Type::I32])),
-        Operator::MemoryGrow { .. } => Ok(Cow::Borrowed(&[Type::I32])),
+        Operator::MemorySize { mem } | Operator::MemoryGrow { mem } => {
+            Ok(vec![super::address_type(module, *mem)].into())
+        }
     }
 }